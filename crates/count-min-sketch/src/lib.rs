@@ -145,6 +145,31 @@ impl CountMinSketch {
         self.depth
     }
 
+    /// Returns the ε for which the sketch's estimates are within εN of the true
+    /// count (with probability 1-δ), N being the total count.
+    pub fn epsilon(&self) -> f64 {
+        1f64.exp() / self.width as f64
+    }
+
+    /// Returns the δ for which the sketch's estimates are within εN of the
+    /// true count with probability 1-δ.
+    pub fn delta(&self) -> f64 {
+        (-(self.depth as f64)).exp()
+    }
+
+    /// Returns the total count of the items in the sketch.
+    ///
+    /// Every row of a sketch built with `add_value` sums to the total, other
+    /// operations can cause them to differ, in which case we return the
+    /// smallest sum.
+    pub fn total(&self) -> i64 {
+        self.counters
+            .iter()
+            .map(|row| row.iter().sum::<i64>())
+            .min()
+            .unwrap()
+    }
+
     /// Returns a vector containing the keys of the hash functions used with the
     /// sketch.
     pub fn hash_keys(&self) -> Vec<u64> {
@@ -194,6 +219,28 @@ impl CountMinSketch {
         }
     }
 
+    /// Adds the given `item` to the sketch using conservative update: only the
+    /// counters which hold the current minimum for `item` are incremented, the
+    /// rest are raised to that new minimum if they are below it.
+    ///
+    /// This reduces the overestimation caused by hash collisions, at the cost
+    /// of the sketch no longer being linear; sketches built this way should
+    /// not have values subtracted from them.
+    pub fn add_value_conservative<T: Hash>(&mut self, item: T) {
+        let buckets = self.get_bucket_indices(item);
+        let new_count = self
+            .counters
+            .iter()
+            .zip(&buckets)
+            .map(|(counter, &bucket)| counter[bucket])
+            .min()
+            .unwrap()
+            + 1;
+        for (counter, bucket) in self.counters.iter_mut().zip(buckets) {
+            counter[bucket] = counter[bucket].max(new_count);
+        }
+    }
+
     /// Subtract the given `item` from the sketch.
     pub fn subtract_value<T: Hash>(&mut self, item: T) {
         for i in 0..self.depth {
@@ -202,6 +249,25 @@ impl CountMinSketch {
         }
     }
 
+    /// Returns an estimate of the inner product of the vectors summarized by
+    /// `self` and `other`, i.e. the sum over all items of the product of their
+    /// counts in each sketch. This is the minimum over the rows of the dot
+    /// products of the corresponding counter rows, and like point estimates it
+    /// can only overestimate.
+    ///
+    /// The underlying `CountMinHashFn`s in each sketch must have the same keys.
+    pub fn inner_product(&self, other: &CountMinSketch) -> i64 {
+        assert_eq!(self.width, other.width);
+        assert_eq!(self.depth, other.depth);
+        assert_eq!(self.hashfuncs, other.hashfuncs);
+        self.counters
+            .iter()
+            .zip(&other.counters)
+            .map(|(row1, row2)| row1.iter().zip(row2).map(|(a, b)| a * b).sum::<i64>())
+            .min()
+            .unwrap()
+    }
+
     /// Includes the counts from `other` into `self` via elementwise addition of
     /// the counter vectors.
     ///
@@ -216,6 +282,34 @@ impl CountMinSketch {
             }
         }
     }
+
+    /// Removes the counts in `other` from `self` via elementwise subtraction of
+    /// the counter vectors. The estimates are only meaningful if the items
+    /// summarized by `other` are a subset of those summarized by `self`.
+    ///
+    /// The underlying `CountMinHashFn`s in each sketch must have the same keys.
+    pub fn subtract(&mut self, other: CountMinSketch) {
+        assert_eq!(self.width, other.width);
+        assert_eq!(self.depth, other.depth);
+        assert_eq!(self.hashfuncs, other.hashfuncs);
+        for (counter1, counter2) in self.counters.iter_mut().zip(other.counters) {
+            for (val1, val2) in counter1.iter_mut().zip(counter2) {
+                *val1 -= val2;
+            }
+        }
+    }
+
+    /// Multiplies every counter by `factor`, rounding up so that decayed
+    /// counts don't drop out of the sketch before `factor` reaches 0, e.g. to
+    /// exponentially decay older counts.
+    pub fn scale(&mut self, factor: f64) {
+        assert!(factor >= 0.0);
+        for counter in self.counters.iter_mut() {
+            for val in counter.iter_mut() {
+                *val = (*val as f64 * factor).ceil() as i64;
+            }
+        }
+    }
 }
 
 impl fmt::Display for CountMinSketch {
@@ -58,3 +58,111 @@ fn add_repeated_with_collisions() {
     assert!(1_000 <= bar_est && bar_est < (1_000 + err_margin));
     assert!(1_000_000 <= baz_est && baz_est < (1_000_000 + err_margin));
 }
+
+#[test]
+fn conservative_update_bounds() {
+    let mut standard = CountMinSketch::with_dim(2, 5);
+    let mut conservative = CountMinSketch::with_dim(2, 5);
+
+    let items = [("foo", 100_000), ("bar", 1_000), ("baz", 1_000_000)];
+    for (item, count) in items {
+        for _ in 0..count {
+            standard.add_value(item);
+            conservative.add_value_conservative(item);
+        }
+    }
+
+    for (item, count) in items {
+        let estimate = conservative.estimate(item);
+        assert!(count <= estimate);
+        assert!(estimate <= standard.estimate(item));
+    }
+}
+
+#[test]
+fn conservative_update_exact_without_collisions() {
+    let mut cms = CountMinSketch::with_prob(0.001, 0.01);
+    for _ in 0..1_000 {
+        cms.add_value_conservative("foo");
+    }
+    cms.add_value_conservative("bar");
+    assert_eq!(cms.estimate("foo"), 1_000);
+    assert_eq!(cms.estimate("bar"), 1);
+}
+
+#[test]
+fn inner_product() {
+    let mut a = CountMinSketch::with_prob(0.001, 0.01);
+    let mut b = CountMinSketch::with_prob(0.001, 0.01);
+    for i in 0..100 {
+        for _ in 0..i {
+            a.add_value(i);
+        }
+        b.add_value(i);
+        b.add_value(i);
+    }
+    // sum over i of i * 2
+    let expected: i64 = (0..100).map(|i| i * 2).sum();
+    let estimate = a.inner_product(&b);
+    assert!(expected <= estimate);
+    let total_a: i64 = (0..100).sum();
+    let total_b = 200;
+    // error is bounded by epsilon * |a| * |b|
+    assert!(estimate as f64 <= expected as f64 + 0.001 * (total_a * total_b) as f64);
+    assert_eq!(a.inner_product(&b), b.inner_product(&a));
+}
+
+#[test]
+#[should_panic]
+fn inner_product_mismatched_dims() {
+    CountMinSketch::with_dim(2, 2).inner_product(&CountMinSketch::with_dim(3, 2));
+}
+
+#[test]
+fn subtract_sketch() {
+    let mut recent = CountMinSketch::with_prob(0.01, 0.01);
+    let mut expired = CountMinSketch::with_prob(0.01, 0.01);
+    for i in 0..100 {
+        expired.add_value(i);
+        recent.add_value(i + 50);
+    }
+    let mut all = expired.clone();
+    all.combine(recent.clone());
+    assert_eq!(all.total(), 200);
+
+    all.subtract(expired);
+    assert_eq!(all.total(), 100);
+    assert_eq!(all.counters(), recent.counters());
+}
+
+#[test]
+#[should_panic]
+fn subtract_mismatched_keys() {
+    let mut a = CountMinSketch::with_dims_and_hashfn_keys(2, 2, vec![1, 2]);
+    a.subtract(CountMinSketch::with_dims_and_hashfn_keys(2, 2, vec![3, 4]));
+}
+
+#[test]
+fn scale() {
+    let mut cms = CountMinSketch::with_dim(4, 3);
+    for _ in 0..10 {
+        cms.add_value("foo");
+    }
+    cms.add_value("bar");
+    cms.scale(0.5);
+    assert_eq!(cms.estimate("foo"), 5);
+    assert_eq!(cms.total(), 5 + 1);
+    cms.scale(0.1);
+    assert_eq!(cms.estimate("foo"), 1);
+    cms.scale(0.0);
+    assert_eq!(cms.total(), 0);
+}
+
+#[test]
+fn error_bounds() {
+    let cms = CountMinSketch::with_prob(0.01, 0.05);
+    assert!(cms.epsilon() <= 0.01);
+    assert!(cms.delta() <= 0.05);
+    assert_eq!(cms.width(), 272);
+    assert_eq!(cms.depth(), 3);
+}
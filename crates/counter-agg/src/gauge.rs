@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use tspoint::TSPoint;
+
+use crate::CounterError;
+
+/// GaugeLevels tracks the level of a gauge over time, which a MetricSummary
+/// doesn't: its extremes and the integral of its linearly-interpolated value
+/// between the first and last points, in value-microseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GaugeLevels {
+    pub min: TSPoint,
+    pub max: TSPoint,
+    pub integral: f64,
+}
+
+impl GaugeLevels {
+    pub fn new(pt: &TSPoint) -> Self {
+        Self {
+            min: *pt,
+            max: *pt,
+            integral: 0.0,
+        }
+    }
+
+    /// `last` is the last point already added, `incoming` must not come before it
+    pub fn add_point(&mut self, last: &TSPoint, incoming: &TSPoint) -> Result<(), CounterError> {
+        if incoming.ts < last.ts {
+            return Err(CounterError::OrderError);
+        }
+        // as with MetricSummary, if two points are equal we only use the first we see
+        if incoming.ts == last.ts {
+            return Ok(());
+        }
+        self.integral += trapezoid(last, incoming);
+        // ties keep the earliest point
+        if incoming.val < self.min.val {
+            self.min = *incoming;
+        }
+        if incoming.val > self.max.val {
+            self.max = *incoming;
+        }
+        Ok(())
+    }
+
+    /// `last` is the last point of self and `incoming_first` the first point
+    /// of `incoming`, which must come strictly after it
+    pub fn combine(
+        &mut self,
+        last: &TSPoint,
+        incoming_first: &TSPoint,
+        incoming: &GaugeLevels,
+    ) -> Result<(), CounterError> {
+        if last.ts >= incoming_first.ts {
+            return Err(CounterError::OrderError);
+        }
+        self.integral += trapezoid(last, incoming_first) + incoming.integral;
+        if incoming.min.val < self.min.val {
+            self.min = incoming.min;
+        }
+        if incoming.max.val > self.max.val {
+            self.max = incoming.max;
+        }
+        Ok(())
+    }
+
+    /// The time-weighted average between `first_ts` and `last_ts`, the times of
+    /// the first and last points, undefined if they are the same.
+    pub fn average(&self, first_ts: i64, last_ts: i64) -> Option<f64> {
+        if first_ts == last_ts {
+            return None;
+        }
+        Some(self.integral / (last_ts - first_ts) as f64)
+    }
+
+    /// expects time-ordered, non-empty input
+    pub fn from_points<'a>(
+        mut points: impl Iterator<Item = &'a TSPoint>,
+    ) -> Result<Self, CounterError> {
+        let mut last = *points.next().expect("no points to take the levels of");
+        let mut levels = Self::new(&last);
+        for p in points {
+            levels.add_point(&last, p)?;
+            if p.ts != last.ts {
+                last = *p;
+            }
+        }
+        Ok(levels)
+    }
+}
+
+pub(crate) fn trapezoid(p1: &TSPoint, p2: &TSPoint) -> f64 {
+    (p2.ts - p1.ts) as f64 * (p1.val + p2.val) / 2.0
+}
@@ -0,0 +1,70 @@
+/// A histogram bucket as Prometheus sees it: the bucket's inclusive upper bound
+/// (its `le` label) and the cumulative count of observations at or below it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    pub upper_bound: f64,
+    pub count: f64,
+}
+
+// based on: https://github.com/prometheus/prometheus/blob/v2.45.0/promql/quantile.go#L73
+/// Estimates the `q` quantile from cumulative histogram buckets the same way
+/// PromQL's `histogram_quantile` does, interpolating linearly within the bucket
+/// the quantile falls into. Expects the buckets sorted by upper bound, with no
+/// duplicates; the last bucket must be `+Inf` or the result is `NaN`.
+pub fn bucket_quantile(q: f64, buckets: &mut [Bucket]) -> f64 {
+    if q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+    match buckets.last() {
+        Some(last) if last.upper_bound == f64::INFINITY => {}
+        _ => return f64::NAN,
+    }
+    if buckets.len() < 2 {
+        return f64::NAN;
+    }
+    ensure_monotonic(buckets);
+
+    let observations = buckets[buckets.len() - 1].count;
+    if observations == 0.0 {
+        return f64::NAN;
+    }
+    let mut rank = q * observations;
+    let b = buckets.partition_point(|bucket| bucket.count < rank);
+
+    if b == buckets.len() - 1 {
+        return buckets[buckets.len() - 2].upper_bound;
+    }
+    if b == 0 && buckets[0].upper_bound <= 0.0 {
+        return buckets[0].upper_bound;
+    }
+
+    let mut bucket_start = 0.0;
+    let bucket_end = buckets[b].upper_bound;
+    let mut count = buckets[b].count;
+    if b > 0 {
+        bucket_start = buckets[b - 1].upper_bound;
+        count -= buckets[b - 1].count;
+        rank -= buckets[b - 1].count;
+    }
+    bucket_start + (bucket_end - bucket_start) * (rank / count)
+}
+
+// Counts can be non-monotonic when buckets are scraped or extrapolated
+// independently of each other, Prometheus smooths that over by carrying the
+// running maximum forward.
+fn ensure_monotonic(buckets: &mut [Bucket]) {
+    let mut max = f64::NEG_INFINITY;
+    for bucket in buckets {
+        if bucket.count > max {
+            max = bucket.count;
+        } else if bucket.count < max {
+            bucket.count = max;
+        }
+    }
+}
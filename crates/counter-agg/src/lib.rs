@@ -3,7 +3,11 @@ use stats_agg::{stats2d::StatsSummary2D, XYPair};
 use std::fmt;
 use tspoint::TSPoint;
 
+pub mod gauge;
+pub mod histogram;
+pub mod points;
 pub mod range;
+pub mod window;
 
 #[cfg(test)]
 mod tests;
@@ -393,3 +397,94 @@ impl From<MetricSummary> for CounterSummaryBuilder {
         Self(summary)
     }
 }
+
+/// Builds the summary of a counter that wraps around at `modulus` instead of
+/// resetting to zero, such as 32- or 64-bit SNMP interface counters. A decrease
+/// from the upper half of the counter's range is treated as a wrap, which adds
+/// the modulus to the reset_sum rather than the last value, so `delta` and
+/// `rate` see the true increase; wraps are counted separately from resets. Any
+/// other decrease is a reset, as for `CounterSummaryBuilder`.
+///
+/// A counter restarted from near the top of its range is indistinguishable from
+/// one that wrapped, and is counted as a wrap.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WrappingCounterSummaryBuilder {
+    summary: MetricSummary,
+    modulus: f64,
+    num_wraps: u64,
+}
+
+impl WrappingCounterSummaryBuilder {
+    pub fn new(pt: &TSPoint, bounds: Option<range::I64Range>, modulus: f64) -> Self {
+        Self {
+            summary: MetricSummary::new(pt, bounds),
+            modulus,
+            num_wraps: 0,
+        }
+    }
+
+    pub fn from_summary(summary: MetricSummary, modulus: f64, num_wraps: u64) -> Self {
+        Self {
+            summary,
+            modulus,
+            num_wraps,
+        }
+    }
+
+    fn wrap(&mut self, incoming: &TSPoint) {
+        if incoming.val >= self.summary.last.val {
+            return;
+        }
+        if self.summary.last.val >= self.modulus / 2.0 {
+            self.summary.reset_sum += self.modulus;
+            self.num_wraps += 1;
+        } else {
+            self.summary.reset(incoming);
+        }
+    }
+
+    /// expects time-ordered input
+    pub fn add_point(&mut self, incoming: &TSPoint) -> Result<(), CounterError> {
+        // out of order and duplicate points are handled by the summary, and
+        // must not count as wraps
+        if incoming.ts > self.summary.last.ts {
+            self.wrap(incoming);
+        }
+        self.summary.add_point(incoming)
+    }
+
+    /// combining can only happen for disjoint time ranges
+    pub fn combine(
+        &mut self,
+        incoming: &MetricSummary,
+        num_wraps: u64,
+    ) -> Result<(), CounterError> {
+        if self.summary.last.ts >= incoming.first.ts {
+            return Err(CounterError::OrderError);
+        }
+        self.wrap(&incoming.first);
+        self.num_wraps += num_wraps;
+        self.summary.combine(incoming)
+    }
+
+    pub fn modulus(&self) -> f64 {
+        self.modulus
+    }
+
+    pub fn num_wraps(&self) -> u64 {
+        self.num_wraps
+    }
+
+    pub fn build(self) -> MetricSummary {
+        self.summary
+    }
+
+    pub fn first(&self) -> &TSPoint {
+        &self.summary.first
+    }
+
+    // TODO build method should check validity rather than caller
+    pub fn bounds_valid(&self) -> bool {
+        self.summary.bounds_valid()
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use tspoint::TSPoint;
+
+use crate::{range::I64Range, to_seconds, CounterError};
+
+/// CounterPoints keeps every point of a counter with resets folded in, ie the
+/// value of each point is its raw value plus the values lost to any resets
+/// before it, so that the increase between any two points is just the
+/// difference of their values. Unlike a MetricSummary this allows recovering
+/// the increase over any sub-interval.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CounterPoints {
+    points: Vec<TSPoint>,
+    reset_sum: f64,
+    pub bounds: Option<I64Range>,
+}
+
+impl CounterPoints {
+    pub fn new(pt: &TSPoint, bounds: Option<I64Range>) -> Self {
+        Self {
+            points: vec![*pt],
+            reset_sum: 0.0,
+            bounds,
+        }
+    }
+
+    /// `points` must be non-empty, time-ordered and already reset-adjusted
+    /// with `reset_sum` being the total adjustment of the last point.
+    pub fn from_parts(points: Vec<TSPoint>, reset_sum: f64, bounds: Option<I64Range>) -> Self {
+        assert!(!points.is_empty());
+        Self {
+            points,
+            reset_sum,
+            bounds,
+        }
+    }
+
+    pub fn points(&self) -> &[TSPoint] {
+        &self.points
+    }
+
+    pub fn reset_sum(&self) -> f64 {
+        self.reset_sum
+    }
+
+    pub fn first(&self) -> &TSPoint {
+        &self.points[0]
+    }
+
+    fn last(&self) -> &TSPoint {
+        self.points.last().unwrap()
+    }
+
+    fn last_raw_val(&self) -> f64 {
+        self.last().val - self.reset_sum
+    }
+
+    /// expects time-ordered input
+    pub fn add_point(&mut self, incoming: &TSPoint) -> Result<(), CounterError> {
+        let last = *self.last();
+        if incoming.ts < last.ts {
+            return Err(CounterError::OrderError);
+        }
+        // as with MetricSummary, if two points are equal we only use the first we see
+        if incoming.ts == last.ts {
+            return Ok(());
+        }
+        let last_raw = self.last_raw_val();
+        if incoming.val < last_raw {
+            self.reset_sum += last_raw;
+        }
+        self.points.push(TSPoint {
+            ts: incoming.ts,
+            val: incoming.val + self.reset_sum,
+        });
+        Ok(())
+    }
+
+    /// combining can only happen for disjoint time ranges
+    pub fn combine(&mut self, incoming: &CounterPoints) -> Result<(), CounterError> {
+        if self.last().ts >= incoming.first().ts {
+            return Err(CounterError::OrderError);
+        }
+        // the first point of a CounterPoints is never adjusted
+        let last_raw = self.last_raw_val();
+        if incoming.first().val < last_raw {
+            self.reset_sum += last_raw;
+        }
+        let offset = self.reset_sum;
+        self.points.extend(incoming.points.iter().map(|p| TSPoint {
+            ts: p.ts,
+            val: p.val + offset,
+        }));
+        self.reset_sum += incoming.reset_sum;
+        self.bounds = match (self.bounds, incoming.bounds) {
+            (Some(mut a), Some(b)) => {
+                a.extend(&b);
+                Some(a)
+            }
+            (a, b) => a.or(b),
+        };
+        Ok(())
+    }
+
+    pub fn bounds_valid(&self) -> bool {
+        match self.bounds {
+            None => true, // unbounded contains everything
+            Some(b) => b.contains(self.last().ts) && b.contains(self.first().ts),
+        }
+    }
+
+    // the reset-adjusted value at `ts`, which must be within the points
+    fn value_at(&self, ts: i64) -> f64 {
+        let idx = self.points.partition_point(|p| p.ts <= ts);
+        let before = &self.points[idx - 1];
+        if before.ts == ts {
+            return before.val;
+        }
+        before.interpolate_linear(&self.points[idx], ts).unwrap()
+    }
+
+    /// The per-second rate of increase over each `step` microseconds, starting
+    /// from the left bound (or the first point if there is none) and ending at
+    /// the right bound (or the last point). Each step's rate is taken over the
+    /// part of the step covered by the points, interpolating linearly between
+    /// them, and is `None` for steps the points don't cover at all.
+    pub fn step_rates(&self, step: i64) -> Vec<(i64, Option<f64>)> {
+        assert!(step > 0);
+        let first = self.first().ts;
+        let last = self.last().ts;
+        let start = self.bounds.and_then(|b| b.left).unwrap_or(first);
+        let end = self.bounds.and_then(|b| b.right).unwrap_or(last);
+
+        let mut rates = vec![];
+        let mut step_start = start;
+        while step_start < end || (step_start == start && start == end) {
+            let step_end = step_start.saturating_add(step).min(end);
+            let from = step_start.max(first);
+            let to = step_end.min(last);
+            let rate = if to > from {
+                let increase = self.value_at(to) - self.value_at(from);
+                Some(increase / to_seconds((to - from) as f64))
+            } else {
+                None
+            };
+            rates.push((step_start, rate));
+            if step_end == end {
+                break;
+            }
+            step_start = step_end;
+        }
+        rates
+    }
+}
@@ -610,3 +610,338 @@ fn test_prometheus_extrapolation_bound_size() {
         to_micro(70.0 / 44000.0)
     );
 }
+
+#[test]
+fn test_window_matches_summary() {
+    use crate::{gauge::GaugeLevels, window::MetricWindow};
+
+    let points: Vec<TSPoint> = [10.0, 20.0, 5.0, 15.0, 15.0, 3.0, 30.0, 40.0, 1.0, 2.0]
+        .iter()
+        .enumerate()
+        .map(|(i, &val)| TSPoint {
+            ts: i as i64 * 1_000_000,
+            val,
+        })
+        .collect();
+
+    for (width, counter) in [(1, true), (3, true), (4, false), (10, true)] {
+        let mut window = if counter {
+            MetricWindow::new_counter()
+        } else {
+            MetricWindow::new_gauge()
+        };
+        for end in 0..points.len() {
+            window.add_point(&points[end]).unwrap();
+            if end >= width {
+                window.remove_point(&points[end - width]).unwrap();
+            }
+            let start = (end + 1).saturating_sub(width);
+            let expected = if counter {
+                let mut builder = CounterSummaryBuilder::new(&points[start], None);
+                for p in &points[start + 1..=end] {
+                    builder.add_point(p).unwrap();
+                }
+                builder.build()
+            } else {
+                let mut builder = GaugeSummaryBuilder::new(&points[start], None);
+                for p in &points[start + 1..=end] {
+                    builder.add_point(p).unwrap();
+                }
+                builder.build()
+            };
+            let actual = window.summary().unwrap();
+            // removal accumulates a little floating point error, so compare loosely
+            assert_eq!(actual.first, expected.first, "first");
+            assert_eq!(actual.second, expected.second, "second");
+            assert_eq!(actual.penultimate, expected.penultimate, "penultimate");
+            assert_eq!(actual.last, expected.last, "last");
+            assert_eq!(actual.num_changes, expected.num_changes, "num_changes");
+            assert_eq!(actual.num_resets, expected.num_resets, "num_resets");
+            assert_eq!(actual.stats.n, expected.stats.n, "n");
+            for (a, e) in [
+                (actual.stats.sx, expected.stats.sx),
+                (actual.stats.sx2, expected.stats.sx2),
+                (actual.stats.sy, expected.stats.sy),
+                (actual.stats.sy2, expected.stats.sy2),
+                (actual.stats.sxy, expected.stats.sxy),
+                (actual.reset_sum, expected.reset_sum),
+                (actual.delta(), expected.delta()),
+            ] {
+                assert_relative_eq!(a, e, epsilon = 1e-9, max_relative = 1e-9);
+            }
+
+            let levels = window.levels().unwrap();
+            let expected = GaugeLevels::from_points(points[start..=end].iter()).unwrap();
+            assert_eq!(levels.min, expected.min, "min");
+            assert_eq!(levels.max, expected.max, "max");
+            assert_relative_eq!(
+                levels.integral,
+                expected.integral,
+                epsilon = 1e-9,
+                max_relative = 1e-9
+            );
+        }
+    }
+}
+
+#[test]
+fn test_window_edge_cases() {
+    use crate::window::MetricWindow;
+
+    let mut window = MetricWindow::new_counter();
+    assert!(window.summary().is_none());
+
+    let a = TSPoint { ts: 0, val: 1.0 };
+    let b = TSPoint { ts: 0, val: 2.0 };
+    let c = TSPoint { ts: 5, val: 3.0 };
+    window.add_point(&a).unwrap();
+    // duplicate timestamps are ignored, as in MetricSummary
+    window.add_point(&b).unwrap();
+    window.add_point(&c).unwrap();
+    assert_eq!(window.summary().unwrap().stats.n, 2);
+    // but removing the point they depend on can't be done incrementally
+    assert_eq!(window.remove_point(&a), None);
+    // nor can removing a point that isn't at the front of the window
+    assert_eq!(window.remove_point(&c), None);
+    assert_eq!(window.summary().unwrap().first, a);
+
+    assert_eq!(
+        window.add_point(&TSPoint { ts: 1, val: 0.0 }),
+        Err(CounterError::OrderError)
+    );
+
+    let mut window = MetricWindow::new_gauge();
+    window.add_point(&a).unwrap();
+    window.remove_point(&a).unwrap();
+    assert!(window.is_empty());
+    assert!(window.summary().is_none());
+}
+
+#[test]
+fn test_bucket_quantile() {
+    use crate::histogram::{bucket_quantile, Bucket};
+
+    let buckets = |counts: &[(f64, f64)]| -> Vec<Bucket> {
+        counts
+            .iter()
+            .map(|&(upper_bound, count)| Bucket { upper_bound, count })
+            .collect()
+    };
+    let mut b = buckets(&[(0.1, 10.0), (0.5, 30.0), (1.0, 40.0), (f64::INFINITY, 40.0)]);
+
+    // linear interpolation inside the bucket the rank falls in
+    assert_relative_eq!(bucket_quantile(0.5, &mut b), 0.1 + 0.4 * (10.0 / 20.0));
+    assert_relative_eq!(bucket_quantile(0.25, &mut b), 0.1);
+    assert_relative_eq!(bucket_quantile(0.1, &mut b), 0.04);
+    assert_relative_eq!(bucket_quantile(1.0, &mut b), 1.0);
+
+    assert_eq!(bucket_quantile(-0.1, &mut b), f64::NEG_INFINITY);
+    assert_eq!(bucket_quantile(1.1, &mut b), f64::INFINITY);
+    assert!(bucket_quantile(f64::NAN, &mut b).is_nan());
+
+    // ranks landing in the +Inf bucket return the highest finite bound
+    let mut b = buckets(&[(0.1, 10.0), (1.0, 20.0), (f64::INFINITY, 40.0)]);
+    assert_relative_eq!(bucket_quantile(0.9, &mut b), 1.0);
+
+    // non-positive lowest bound is returned as is
+    let mut b = buckets(&[(-1.0, 10.0), (1.0, 20.0), (f64::INFINITY, 20.0)]);
+    assert_relative_eq!(bucket_quantile(0.2, &mut b), -1.0);
+
+    // missing +Inf, too few buckets or no observations
+    assert!(bucket_quantile(0.5, &mut buckets(&[(0.1, 10.0), (1.0, 20.0)])).is_nan());
+    assert!(bucket_quantile(0.5, &mut buckets(&[(f64::INFINITY, 20.0)])).is_nan());
+    assert!(bucket_quantile(0.5, &mut buckets(&[(1.0, 0.0), (f64::INFINITY, 0.0)])).is_nan());
+
+    // non-monotonic counts are smoothed to the running max
+    let mut b = buckets(&[(0.1, 10.0), (0.5, 8.0), (1.0, 20.0), (f64::INFINITY, 20.0)]);
+    assert_relative_eq!(bucket_quantile(0.75, &mut b), 0.5 + 0.5 * (5.0 / 10.0));
+    assert_eq!(b[1].count, 10.0);
+}
+
+#[test]
+fn test_wrapping_counter() {
+    let modulus = 4294967296.0;
+    let mut summary = WrappingCounterSummaryBuilder::new(
+        &TSPoint {
+            ts: 0,
+            val: 4294967000.0,
+        },
+        None,
+        modulus,
+    );
+    summary
+        .add_point(&TSPoint {
+            ts: 1_000_000,
+            val: 4294967200.0,
+        })
+        .unwrap();
+    // wraps past 2^32, the true increase is 96 + 104
+    summary
+        .add_point(&TSPoint {
+            ts: 2_000_000,
+            val: 104.0,
+        })
+        .unwrap();
+    // duplicate timestamps are ignored and don't wrap
+    summary
+        .add_point(&TSPoint {
+            ts: 2_000_000,
+            val: 0.0,
+        })
+        .unwrap();
+    assert_eq!(
+        summary.add_point(&TSPoint { ts: 0, val: 0.0 }),
+        Err(CounterError::OrderError)
+    );
+    assert_eq!(summary.num_wraps(), 1);
+    let built = summary.build();
+    assert_relative_eq!(built.delta(), 400.0);
+    assert_relative_eq!(built.rate().unwrap(), 200.0);
+    assert_eq!(built.num_resets, 0);
+    assert_eq!(built.num_changes, 2);
+
+    // a wrap across the boundary of two partials is found when combining
+    let mut first = WrappingCounterSummaryBuilder::new(
+        &TSPoint {
+            ts: 0,
+            val: 4294967000.0,
+        },
+        None,
+        modulus,
+    );
+    first
+        .add_point(&TSPoint {
+            ts: 1_000_000,
+            val: 4294967200.0,
+        })
+        .unwrap();
+    let second = WrappingCounterSummaryBuilder::new(
+        &TSPoint {
+            ts: 2_000_000,
+            val: 104.0,
+        },
+        None,
+        modulus,
+    );
+    first.combine(&second.build(), 0).unwrap();
+    assert_eq!(first.num_wraps(), 1);
+    let combined = first.build();
+    assert_close_enough(&combined, &built);
+    assert_relative_eq!(combined.delta(), 400.0);
+
+    // a drop from the lower half of the range is a reset rather than a wrap
+    let mut reset =
+        WrappingCounterSummaryBuilder::new(&TSPoint { ts: 0, val: 100.0 }, None, modulus);
+    reset
+        .add_point(&TSPoint {
+            ts: 1_000_000,
+            val: 10.0,
+        })
+        .unwrap();
+    assert_eq!(reset.num_wraps(), 0);
+    let reset = reset.build();
+    assert_eq!(reset.num_resets, 1);
+    assert_relative_eq!(reset.delta(), 10.0);
+}
+
+#[test]
+fn test_counter_points() {
+    use crate::points::CounterPoints;
+    let pt = |ts: i64, val: f64| TSPoint {
+        ts: ts * 1_000_000,
+        val,
+    };
+    let mut points = CounterPoints::new(&pt(0, 10.0), None);
+    points.add_point(&pt(10, 30.0)).unwrap();
+    // reset
+    points.add_point(&pt(20, 5.0)).unwrap();
+    points.add_point(&pt(20, 100.0)).unwrap();
+    points.add_point(&pt(30, 25.0)).unwrap();
+    assert_eq!(points.add_point(&pt(0, 0.0)), Err(CounterError::OrderError));
+    assert_relative_eq!(points.reset_sum(), 30.0);
+    let vals: Vec<f64> = points.points().iter().map(|p| p.val).collect();
+    assert_eq!(vals, vec![10.0, 30.0, 35.0, 55.0]);
+
+    // combining partials finds the reset between them
+    let mut first = CounterPoints::new(&pt(0, 10.0), None);
+    first.add_point(&pt(10, 30.0)).unwrap();
+    let mut second = CounterPoints::new(&pt(20, 5.0), None);
+    second.add_point(&pt(30, 25.0)).unwrap();
+    assert_eq!(second.combine(&first), Err(CounterError::OrderError));
+    first.combine(&second).unwrap();
+    assert_eq!(first, points);
+
+    // steps are interpolated between the points
+    let rates = points.step_rates(15_000_000);
+    assert_eq!(rates.len(), 2);
+    assert_eq!(rates[0].0, 0);
+    // 10 -> 32.5 over 15s
+    assert_relative_eq!(rates[0].1.unwrap(), 1.5);
+    assert_eq!(rates[1].0, 15_000_000);
+    // 32.5 -> 55 over 15s
+    assert_relative_eq!(rates[1].1.unwrap(), 1.5);
+
+    // bounds extend the steps past the points, steps without points have no rate
+    points.bounds = Some(I64Range {
+        left: Some(-10_000_000),
+        right: Some(50_000_000),
+    });
+    assert!(points.bounds_valid());
+    let rates = points.step_rates(20_000_000);
+    assert_eq!(
+        rates.iter().map(|r| r.0).collect::<Vec<_>>(),
+        vec![-10_000_000, 10_000_000, 30_000_000]
+    );
+    // 10 -> 30 over the 10s covered by points
+    assert_relative_eq!(rates[0].1.unwrap(), 2.0);
+    assert_relative_eq!(rates[1].1.unwrap(), 1.25);
+    assert_eq!(rates[2].1, None);
+}
+
+#[test]
+fn test_gauge_levels() {
+    use crate::gauge::GaugeLevels;
+    let pt = |ts: i64, val: f64| TSPoint {
+        ts: ts * 1_000_000,
+        val,
+    };
+    let points = [
+        pt(0, 10.0),
+        pt(10, 30.0),
+        pt(20, 10.0),
+        pt(20, 50.0),
+        pt(30, 5.0),
+    ];
+    let levels = GaugeLevels::from_points(points.iter()).unwrap();
+    assert_eq!(levels.min, pt(30, 5.0));
+    assert_eq!(levels.max, pt(10, 30.0));
+    // (20 + 20 + 7.5) * 10s
+    assert_relative_eq!(levels.integral, 475.0 * 1_000_000.0);
+    assert_relative_eq!(levels.average(0, 30_000_000).unwrap(), 475.0 / 30.0);
+    assert_eq!(levels.average(0, 0), None);
+    assert_eq!(
+        GaugeLevels::from_points([pt(10, 1.0), pt(0, 1.0)].iter()),
+        Err(CounterError::OrderError)
+    );
+
+    // combining two halves
+    let mut first = GaugeLevels::from_points(points[..2].iter()).unwrap();
+    let mut second = GaugeLevels::from_points([pt(20, 10.0), pt(30, 5.0)].iter()).unwrap();
+    assert_eq!(
+        second.combine(&pt(30, 5.0), &pt(0, 10.0), &first),
+        Err(CounterError::OrderError)
+    );
+    first
+        .combine(&pt(10, 30.0), &pt(20, 10.0), &second)
+        .unwrap();
+    assert_eq!(first, levels);
+
+    // ties keep the earlier point, both within and across parts
+    let tied = GaugeLevels::from_points([pt(0, 5.0), pt(10, 8.0), pt(20, 5.0)].iter()).unwrap();
+    assert_eq!(tied.min, pt(0, 5.0));
+    let mut first = GaugeLevels::from_points([pt(0, 5.0), pt(10, 8.0)].iter()).unwrap();
+    let second = GaugeLevels::from_points([pt(20, 5.0), pt(30, 8.0)].iter()).unwrap();
+    first.combine(&pt(10, 8.0), &pt(20, 5.0), &second).unwrap();
+    assert_eq!(first.min, pt(0, 5.0));
+    assert_eq!(first.max, pt(10, 8.0));
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+
+use stats_agg::{stats2d::StatsSummary2D, XYPair};
+use tspoint::TSPoint;
+
+use crate::{
+    gauge::{trapezoid, GaugeLevels},
+    ts_to_xy, CounterError, MetricSummary,
+};
+
+/// MetricWindow maintains the same values as a MetricSummary over a sliding
+/// window of points, so that points can be removed from the front of the window
+/// as well as added to the back without recomputing the summary from scratch.
+/// This backs the moving-aggregate form of `counter_agg` and `gauge_agg`, and
+/// likewise maintains the GaugeLevels of the latter.
+///
+/// Points with the same timestamp as their predecessor are ignored, just as
+/// in MetricSummary, but we remember how many of them follow each point so we
+/// can notice when removing a point would have to promote one of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricWindow {
+    // each point paired with the number of ignored duplicates following it
+    points: VecDeque<(TSPoint, u64)>,
+    resets: bool,
+    reset_sum: f64,
+    num_resets: u64,
+    num_changes: u64,
+    // y values are offset by the resets seen since the front of the window
+    stats: StatsSummary2D<f64>,
+    // candidates for the minimum and maximum, increasing and decreasing in
+    // value respectively, so the front of each is the extreme of the window
+    mins: VecDeque<TSPoint>,
+    maxes: VecDeque<TSPoint>,
+    integral: f64,
+}
+
+impl MetricWindow {
+    /// A window that treats every decrease in value as a counter reset.
+    pub fn new_counter() -> Self {
+        Self::new(true)
+    }
+
+    /// A window over a gauge, where decreases are ordinary changes.
+    pub fn new_gauge() -> Self {
+        Self::new(false)
+    }
+
+    fn new(resets: bool) -> Self {
+        Self {
+            points: VecDeque::new(),
+            resets,
+            reset_sum: 0.0,
+            num_resets: 0,
+            num_changes: 0,
+            stats: StatsSummary2D::new(),
+            mins: VecDeque::new(),
+            maxes: VecDeque::new(),
+            integral: 0.0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// expects time-ordered input
+    pub fn add_point(&mut self, incoming: &TSPoint) -> Result<(), CounterError> {
+        let last = match self.points.back_mut() {
+            None => {
+                self.points.push_back((*incoming, 0));
+                self.stats.accum(ts_to_xy(*incoming)).unwrap();
+                self.push_extremes(incoming);
+                return Ok(());
+            }
+            Some(last) => last,
+        };
+        if incoming.ts < last.0.ts {
+            return Err(CounterError::OrderError);
+        }
+        if incoming.ts == last.0.ts {
+            last.1 += 1;
+            return Ok(());
+        }
+        let last = last.0;
+        // These values are not rounded, so direct comparison is valid.
+        if incoming.val != last.val {
+            self.num_changes += 1;
+        }
+        if self.resets && incoming.val < last.val {
+            self.reset_sum += last.val;
+            self.num_resets += 1;
+        }
+        let mut incoming_xy = ts_to_xy(*incoming);
+        incoming_xy.y += self.reset_sum;
+        self.stats.accum(incoming_xy).unwrap();
+        self.integral += trapezoid(&last, incoming);
+        self.push_extremes(incoming);
+        self.points.push_back((*incoming, 0));
+        Ok(())
+    }
+
+    // ties keep the earliest point, as in GaugeLevels
+    fn push_extremes(&mut self, incoming: &TSPoint) {
+        while matches!(self.mins.back(), Some(p) if p.val > incoming.val) {
+            self.mins.pop_back();
+        }
+        self.mins.push_back(*incoming);
+        while matches!(self.maxes.back(), Some(p) if p.val < incoming.val) {
+            self.maxes.pop_back();
+        }
+        self.maxes.push_back(*incoming);
+    }
+
+    /// Removes `outgoing` from the front of the window. Returns `None`, leaving
+    /// the window untouched, if the point can't be removed incrementally, in
+    /// which case the window should be rebuilt from the remaining points.
+    pub fn remove_point(&mut self, outgoing: &TSPoint) -> Option<()> {
+        let &(first, duplicates) = self.points.front()?;
+        if first != *outgoing || duplicates > 0 {
+            return None;
+        }
+        let next = match self.points.get(1) {
+            None => {
+                *self = Self::new(self.resets);
+                return Some(());
+            }
+            Some(&(next, _)) => next,
+        };
+
+        // the first point is never offset by a reset
+        let mut stats = self.stats.remove(ts_to_xy(first))?;
+        if self.resets && next.val < first.val {
+            // every later point was offset by this reset, undo that
+            stats
+                .offset(XYPair {
+                    x: 0.0,
+                    y: -first.val,
+                })
+                .ok()?;
+            self.num_resets -= 1;
+            self.reset_sum = if self.num_resets == 0 {
+                0.0
+            } else {
+                self.reset_sum - first.val
+            };
+        }
+        if next.val != first.val {
+            self.num_changes -= 1;
+        }
+        self.stats = stats;
+        self.integral = if self.points.len() == 2 {
+            0.0
+        } else {
+            self.integral - trapezoid(&first, &next)
+        };
+        if self.mins.front() == Some(&first) {
+            self.mins.pop_front();
+        }
+        if self.maxes.front() == Some(&first) {
+            self.maxes.pop_front();
+        }
+        self.points.pop_front();
+        Some(())
+    }
+
+    /// The distinct points currently in the window, in time order.
+    pub fn points(&self) -> impl Iterator<Item = &TSPoint> + '_ {
+        self.points.iter().map(|(p, _)| p)
+    }
+
+    /// The levels of the points currently in the window, if there are any.
+    pub fn levels(&self) -> Option<GaugeLevels> {
+        Some(GaugeLevels {
+            min: *self.mins.front()?,
+            max: *self.maxes.front()?,
+            integral: self.integral,
+        })
+    }
+
+    /// The summary of the points currently in the window, if there are any.
+    pub fn summary(&self) -> Option<MetricSummary> {
+        let len = self.points.len();
+        let first = self.points.front()?.0;
+        let nth = |i: usize| self.points[i].0;
+        Some(MetricSummary {
+            first,
+            second: if len > 1 { nth(1) } else { first },
+            penultimate: if len > 1 { nth(len - 2) } else { first },
+            last: nth(len - 1),
+            reset_sum: self.reset_sum,
+            num_resets: self.num_resets,
+            num_changes: self.num_changes,
+            stats: self.stats,
+            bounds: None,
+        })
+    }
+}
@@ -230,6 +230,37 @@ impl<'s> Storage<'s> {
     pub fn num_bytes(&self) -> usize {
         self.registers.byte_len()
     }
+
+    /// Fold the registers down to a lower precision. The low bits of each
+    /// register's index become the leading bits of the hash remainder at the
+    /// new precision, so the result is identical to having inserted the same
+    /// hashes into a sketch of the lower precision directly.
+    pub fn fold_to_precision(&self, precision: u8) -> Storage<'static> {
+        assert!(
+            precision <= self.precision,
+            "cannot fold to a higher precision (from={}, to={})",
+            self.precision,
+            precision
+        );
+
+        let mut folded = Storage::new(precision);
+        let shift = self.precision - precision;
+        let dropped_mask = (1usize << shift) - 1;
+        for (i, r) in self.registers.iter().enumerate() {
+            if r == 0 {
+                continue;
+            }
+            let dropped = (i & dropped_mask) as u64;
+            let count = if dropped == 0 {
+                r + shift
+            } else {
+                // leading zeros of the dropped bits, within a `shift`-bit window, plus one
+                shift - (64 - dropped.leading_zeros() as u8) + 1
+            };
+            folded.registers.set_max(i >> shift, count);
+        }
+        folded
+    }
 }
 
 #[cfg(test)]
@@ -273,6 +304,27 @@ mod tests {
         Storage::new(19);
     }
 
+    #[test]
+    fn fold_matches_direct_insertion() {
+        for (from, to) in [(18, 4), (16, 12), (12, 11), (10, 10)] {
+            let mut high = Storage::new(from);
+            let mut low = Storage::new(to);
+            for i in 0..50_000 {
+                high.add_hash(hash(i));
+                low.add_hash(hash(i));
+            }
+            let folded = high.fold_to_precision(to);
+            assert!(folded == low, "{} -> {}", from, to);
+            assert_eq!(folded.estimate_count(), low.estimate_count());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot fold to a higher precision (from=8, to=9)")]
+    fn fold_panics_on_higher_precision() {
+        Storage::new(8).fold_to_precision(9);
+    }
+
     #[test]
     fn empty() {
         assert_eq!(Storage::new(8).estimate_count(), 0);
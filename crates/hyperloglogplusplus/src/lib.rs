@@ -79,6 +79,32 @@ impl<'s, T, B> HyperLogLog<'s, T, B> {
         }
     }
 
+    pub fn precision(&self) -> u8 {
+        use HyperLogLogStorage::*;
+
+        match &self.storage {
+            Sparse(s) => s.precision,
+            Dense(s) => s.precision,
+        }
+    }
+
+    /// Reduce the precision of the sketch, this is lossless with regards to
+    /// what a sketch of the lower precision would have recorded.
+    pub fn fold_to_precision(&mut self, precision: u8) {
+        use HyperLogLogStorage::*;
+
+        if precision == self.precision() {
+            return;
+        }
+        self.storage = match &mut self.storage {
+            Sparse(s) => match s.fold_to_precision(precision) {
+                (mut folded, true) => Dense(folded.to_dense()),
+                (folded, false) => Sparse(folded),
+            },
+            Dense(s) => Dense(s.fold_to_precision(precision)),
+        };
+    }
+
     pub fn is_sparse(&self) -> bool {
         use HyperLogLogStorage::*;
 
@@ -420,6 +446,56 @@ mod tests {
         assert_eq!(hll_b.estimate_count(), baseline.estimate_count())
     }
 
+    #[quickcheck]
+    fn quick_fold_merge_hll(values_a: Vec<u64>, values_b: Vec<u64>) {
+        let mut hll_a = HyperLogLog::new(16, FnvBuildHasher::default());
+        let mut baseline = HyperLogLog::new(12, FnvBuildHasher::default());
+        for value in values_a {
+            hll_a.add(&value);
+            baseline.add(&value)
+        }
+
+        let mut hll_b = HyperLogLog::new(12, FnvBuildHasher::default());
+        for value in values_b {
+            hll_b.add(&value);
+            baseline.add(&value)
+        }
+
+        hll_a.fold_to_precision(12);
+        assert_eq!(hll_a.precision(), 12);
+        hll_b.merge_in(&hll_a);
+        assert_eq!(hll_b.estimate_count(), baseline.estimate_count())
+    }
+
+    #[test]
+    fn test_fold_dense() {
+        let mut hll = HyperLogLog::new(16, FnvBuildHasher::default());
+        let mut baseline = HyperLogLog::new(8, FnvBuildHasher::default());
+        for i in 0..100_000 {
+            hll.add(&i);
+            baseline.add(&i);
+        }
+        assert!(!hll.is_sparse());
+        hll.fold_to_precision(8);
+        assert!(!hll.is_sparse());
+        assert_eq!(hll.estimate_count(), baseline.estimate_count());
+        assert_eq!(hll.estimate_count(), 121_578);
+    }
+
+    #[test]
+    fn test_fold_sparse_to_dense() {
+        let mut hll = HyperLogLog::new(16, FnvBuildHasher::default());
+        let mut baseline = HyperLogLog::new(4, FnvBuildHasher::default());
+        for i in 0..1_000 {
+            hll.add(&i);
+            baseline.add(&i);
+        }
+        assert!(hll.is_sparse());
+        hll.fold_to_precision(4);
+        assert!(!hll.is_sparse());
+        assert_eq!(hll.estimate_count(), baseline.estimate_count());
+    }
+
     #[test]
     fn precision_for_error() {
         for precision in 4..=18 {
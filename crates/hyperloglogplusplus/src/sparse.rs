@@ -158,6 +158,41 @@ impl<'s> Storage<'s> {
         self.immutable_to_dense()
     }
 
+    /// Re-encode the storage for a lower precision. Sparse entries always keep
+    /// 25 bits of index, so only those entries which store an explicit count
+    /// need to change: that count is only valid if every index bit below the
+    /// precision is zero, otherwise it is recomputed from the index.
+    pub fn fold_to_precision(&mut self, precision: u8) -> (Storage<'static>, Overflowing) {
+        assert!(
+            precision <= self.precision,
+            "cannot fold to a higher precision (from={}, to={})",
+            self.precision,
+            precision
+        );
+        self.merge_buffers();
+
+        let low_bits_mask = (1u32 << (NUM_HIGH_BITS - precision)) - 1;
+        let mut compressed = compressor();
+        compressed.extend(self.iter().map(|encoded| {
+            if encoded.stores_count() && encoded.idx() & low_bits_mask != 0 {
+                Encoded(encoded.idx() << 1)
+            } else {
+                encoded
+            }
+        }));
+        let (compressed, num_compressed) = compressed.into_compressed();
+
+        let max_sparse_bitsize = (1u64 << precision) * 6;
+        let overflowing = compressed.num_bytes() as u64 * 8 > max_sparse_bitsize;
+        let folded = Storage {
+            to_merge: Default::default(),
+            compressed,
+            num_compressed,
+            precision,
+        };
+        (folded, overflowing)
+    }
+
     pub fn immutable_to_dense(&self) -> dense::Storage<'static> {
         if !self.to_merge.is_empty() {
             panic!("tried to generate dense storage with unmerged state")
@@ -402,6 +437,32 @@ mod tests {
         TestResult::passed()
     }
 
+    #[quickcheck]
+    fn quick_sparse_fold(values: Vec<u64>) -> TestResult {
+        if values.len() >= (1 << NUM_HASH_BITS) {
+            return TestResult::discard();
+        }
+        let mut high = Storage::new(16);
+        let mut low = Storage::new(10);
+        for value in &values {
+            high.add_hash(*value);
+            low.add_hash(*value);
+        }
+        low.merge_buffers();
+        let (folded, _) = high.fold_to_precision(10);
+
+        let expected: Vec<_> = low.iter().collect();
+        let actual: Vec<_> = folded.iter().collect();
+        if expected != actual {
+            println!("expected {:?}, got {:?}", expected, actual);
+            return TestResult::failed();
+        }
+        if folded.immutable_to_dense() != low.immutable_to_dense() {
+            return TestResult::failed();
+        }
+        TestResult::passed()
+    }
+
     // fn encoded_order() {
 
     // }
@@ -559,6 +559,102 @@ impl TDigest {
         }
     }
 
+    /// Kolmogorov-Smirnov statistic between two digests, i.e. the largest difference
+    /// between their estimated cumulative distributions, checked at the bounds
+    /// and centroids of both digests.
+    pub fn ks_distance(&self, other: &TDigest) -> f64 {
+        self.comparison_points(other)
+            .into_iter()
+            .map(|v| (self.cdf(v) - other.cdf(v)).abs())
+            .fold(0.0, f64::max)
+    }
+
+    /// First Wasserstein (earth mover's) distance between two digests, the area
+    /// between their estimated cumulative distributions. The distributions are
+    /// treated as linear between the bounds and centroids of both digests.
+    pub fn wasserstein_distance(&self, other: &TDigest) -> f64 {
+        let points = self.comparison_points(other);
+        let differences: Vec<f64> = points
+            .iter()
+            .map(|v| self.cdf(*v) - other.cdf(*v))
+            .collect();
+
+        let mut distance = 0.0;
+        for i in 1..points.len() {
+            let width = points[i] - points[i - 1];
+            let (d0, d1) = (differences[i - 1], differences[i]);
+            // if the distributions cross between the points only the area on
+            // either side of the crossing counts
+            let area = if d0 * d1 >= 0.0 {
+                (d0.abs() + d1.abs()) / 2.0
+            } else {
+                (d0 * d0 + d1 * d1) / (2.0 * (d0.abs() + d1.abs()))
+            };
+            distance += area * width;
+        }
+        distance
+    }
+
+    // `estimate_quantile_at_value` can divide by zero exactly at the bounds of
+    // a digest, we know the answer there.
+    fn cdf(&self, v: f64) -> f64 {
+        if v <= self.min() {
+            0.0
+        } else if v >= self.max() {
+            1.0
+        } else {
+            self.estimate_quantile_at_value(v)
+        }
+    }
+
+    fn comparison_points(&self, other: &TDigest) -> Vec<f64> {
+        let mut points: Vec<OrderedFloat<f64>> = [self, other]
+            .iter()
+            .filter(|digest| !digest.is_empty())
+            .flat_map(|digest| {
+                digest
+                    .centroids
+                    .iter()
+                    .map(|c| c.mean)
+                    .chain([digest.min, digest.max])
+            })
+            .collect();
+        points.sort();
+        points.dedup();
+        points.into_iter().map(OrderedFloat::into_inner).collect()
+    }
+
+    /// To estimate the mean of the values located between the `low` and `high` quantiles.
+    /// Each centroid contributes its mean weighted by the portion of its weight
+    /// that falls between the two quantiles.
+    pub fn estimate_trimmed_mean(&self, low: f64, high: f64) -> f64 {
+        assert!(0.0 <= low && low < high && high <= 1.0);
+
+        let start_rank = low * self.count as f64;
+        let end_rank = high * self.count as f64;
+        let mut seen = 0.0;
+        let mut sum = 0.0;
+        let mut weight = 0.0;
+        for centroid in &self.centroids {
+            let centroid_start = seen;
+            seen += centroid.weight() as f64;
+            let overlap = seen.min(end_rank) - centroid_start.max(start_rank);
+            if overlap > 0.0 {
+                sum += centroid.mean() * overlap;
+                weight += overlap;
+            }
+            if seen >= end_rank {
+                break;
+            }
+        }
+
+        if weight == 0.0 {
+            0.0
+        } else {
+            sum / weight
+        }
+    }
+
     /// To estimate the value located at `q` quantile
     pub fn estimate_quantile(&self, q: f64) -> f64 {
         if self.centroids.is_empty() {
@@ -763,6 +859,48 @@ mod tests {
         assert!(percentage < 0.01);
     }
 
+    #[test]
+    fn test_trimmed_mean() {
+        let t = TDigest::new_with_size(100);
+        let mut values: Vec<f64> = (1..=10_000).map(f64::from).collect();
+        values.extend([1e12; 10]);
+        let t = t.merge_unsorted(values);
+
+        let full = t.estimate_trimmed_mean(0.0, 1.0);
+        assert!((full - t.mean()).abs() / t.mean() < 0.0001);
+
+        let trimmed = t.estimate_trimmed_mean(0.0, 0.99);
+        assert!((trimmed - 4955.0).abs() / 4955.0 < 0.01);
+
+        let middle = t.estimate_trimmed_mean(0.25, 0.75);
+        assert!((middle - 5005.5).abs() / 5005.5 < 0.01);
+
+        assert_eq!(
+            TDigest::new_with_size(10).estimate_trimmed_mean(0.1, 0.9),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_distribution_distances() {
+        let values: Vec<f64> = (1..=10_000).map(f64::from).collect();
+        let a = TDigest::new_with_size(100).merge_sorted(values.clone());
+        let b =
+            TDigest::new_with_size(100).merge_sorted(values.iter().map(|v| v + 1000.0).collect());
+        let c =
+            TDigest::new_with_size(100).merge_sorted(values.iter().map(|v| v + 50_000.0).collect());
+
+        assert_eq!(a.ks_distance(&a), 0.0);
+        assert_eq!(a.wasserstein_distance(&a), 0.0);
+
+        assert!((a.ks_distance(&b) - 0.1).abs() < 0.01);
+        assert!((a.ks_distance(&b) - b.ks_distance(&a)).abs() < f64::EPSILON);
+        assert!((a.wasserstein_distance(&b) - 1000.0).abs() < 1000.0 * 0.01);
+
+        assert_eq!(a.ks_distance(&c), 1.0);
+        assert!((a.wasserstein_distance(&c) - 50_000.0).abs() < 50_000.0 * 0.01);
+    }
+
     #[test]
     fn test_merge_sorted_against_uniform_distro() {
         let t = TDigest::new_with_size(100);
@@ -3,11 +3,15 @@ use tspoint::TSPoint;
 
 use flat_serialize_macro::FlatSerializable;
 
+pub mod stats;
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, FlatSerializable)]
 #[repr(u8)]
 pub enum TimeWeightMethod {
     LOCF = 0,
     Linear,
+    // next observation carried backward
+    NOCB,
 }
 
 #[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
@@ -26,6 +30,7 @@ pub enum TimeWeightError {
     InterpolateMissingPoint,
     ZeroDuration,
     EmptyIterator,
+    MaxGapMismatch,
 }
 
 impl TimeWeightSummary {
@@ -125,11 +130,11 @@ impl TimeWeightSummary {
     ) -> Result<Self, TimeWeightError> {
         let mut calc = *self;
         if let Some((start, prev)) = start_prev {
-            calc = self.with_prev(start, prev)?
+            calc = calc.with_prev(start, prev)?
         }
 
         if let Some((end, next)) = end_next {
-            calc = self.with_next(end, next)?
+            calc = calc.with_next(end, next)?
         }
         Ok(calc)
     }
@@ -224,6 +229,10 @@ impl TimeWeightMethod {
                 (TimeWeightMethod::Linear, None) => {
                     return Err(TimeWeightError::InterpolateMissingPoint)
                 }
+                (TimeWeightMethod::NOCB, Some(second)) => second.val,
+                (TimeWeightMethod::NOCB, None) => {
+                    return Err(TimeWeightError::InterpolateMissingPoint)
+                }
             },
         };
         Ok(pt)
@@ -242,10 +251,174 @@ impl TimeWeightMethod {
             //midpoint of the two.
             //TODO: Stable midpoint calc? http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2018/p0811r2.html
             TimeWeightMethod::Linear => (first.val + second.val) / 2.0 * duration,
+            TimeWeightMethod::NOCB => second.val * duration,
+        }
+    }
+
+    /// The integral of the square of the interpolated value between two points.
+    pub fn weighted_sum_sq(&self, first: TSPoint, second: TSPoint) -> f64 {
+        debug_assert!(second.ts > first.ts);
+        let duration = (second.ts - first.ts) as f64;
+        match self {
+            TimeWeightMethod::LOCF => first.val * first.val * duration,
+            // integrating (a + (b - a)t)^2 over [0, 1] gives (a^2 + ab + b^2) / 3
+            TimeWeightMethod::Linear => {
+                (first.val * first.val + first.val * second.val + second.val * second.val) / 3.0
+                    * duration
+            }
+            TimeWeightMethod::NOCB => second.val * second.val * duration,
         }
     }
 }
 
+/// A TimeWeightSummary that leaves any gap between consecutive points longer
+/// than `max_gap` out of both the weighted sum and the duration, so that a
+/// source going offline doesn't get interpolated across. `excluded` is the
+/// total length of the gaps left out so far.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TimeWeightGapSummary {
+    pub summary: TimeWeightSummary,
+    pub max_gap: i64,
+    pub excluded: i64,
+}
+
+impl TimeWeightGapSummary {
+    pub fn new(pt: TSPoint, method: TimeWeightMethod, max_gap: i64) -> Self {
+        TimeWeightGapSummary {
+            summary: TimeWeightSummary::new(pt, method),
+            max_gap,
+            excluded: 0,
+        }
+    }
+
+    fn is_gap(&self, duration: i64) -> bool {
+        duration > self.max_gap
+    }
+
+    pub fn accum(&mut self, pt: TSPoint) -> Result<(), TimeWeightError> {
+        let gap = pt.ts - self.summary.last.ts;
+        if gap > 0 && self.is_gap(gap) {
+            self.excluded += gap;
+            self.summary.last = pt;
+            return Ok(());
+        }
+        self.summary.accum(pt)
+    }
+
+    // as with TimeWeightSummary::combine the time ranges must be disjoint
+    pub fn combine(
+        &self,
+        next: &TimeWeightGapSummary,
+    ) -> Result<TimeWeightGapSummary, TimeWeightError> {
+        if self.max_gap != next.max_gap {
+            return Err(TimeWeightError::MaxGapMismatch);
+        }
+        let gap = next.summary.first.ts - self.summary.last.ts;
+        let (summary, excluded) = if gap > 0 && self.is_gap(gap) {
+            if self.summary.method != next.summary.method {
+                return Err(TimeWeightError::MethodMismatch);
+            }
+            let summary = TimeWeightSummary {
+                last: next.summary.last,
+                w_sum: self.summary.w_sum + next.summary.w_sum,
+                ..self.summary
+            };
+            (summary, self.excluded + next.excluded + gap)
+        } else {
+            (
+                self.summary.combine(&next.summary)?,
+                self.excluded + next.excluded,
+            )
+        };
+        Ok(TimeWeightGapSummary {
+            summary,
+            max_gap: self.max_gap,
+            excluded,
+        })
+    }
+
+    pub fn new_from_sorted_iter<'a>(
+        iter: impl IntoIterator<Item = &'a TSPoint>,
+        method: TimeWeightMethod,
+        max_gap: i64,
+    ) -> Result<TimeWeightGapSummary, TimeWeightError> {
+        let mut t = iter.into_iter();
+        let mut s = match t.next() {
+            None => {
+                return Err(TimeWeightError::EmptyIterator);
+            }
+            Some(val) => TimeWeightGapSummary::new(*val, method, max_gap),
+        };
+        for p in t {
+            s.accum(*p)?;
+        }
+        Ok(s)
+    }
+
+    pub fn combine_sorted_iter<'a>(
+        iter: impl IntoIterator<Item = &'a TimeWeightGapSummary>,
+    ) -> Result<TimeWeightGapSummary, TimeWeightError> {
+        let mut t = iter.into_iter();
+        let mut s = match t.next() {
+            None => {
+                return Err(TimeWeightError::EmptyIterator);
+            }
+            Some(val) => *val,
+        };
+        for p in t {
+            s = s.combine(p)?;
+        }
+        Ok(s)
+    }
+
+    /// As TimeWeightSummary::with_bounds, but an extrapolated segment is left
+    /// out if the real gap it spans, from `prev` to the first point or from
+    /// the last point to `next`, is longer than `max_gap`. Without a `next`
+    /// point (LOCF only) the gap is taken to run up to the end.
+    pub fn with_bounds(
+        &self,
+        start_prev: Option<(i64, TSPoint)>,
+        end_next: Option<(i64, Option<TSPoint>)>,
+    ) -> Result<Self, TimeWeightError> {
+        let mut calc = *self;
+        if let Some((start, prev)) = start_prev {
+            let extended = calc.summary.with_prev(start, prev)?;
+            if self.is_gap(calc.summary.first.ts - prev.ts) {
+                calc.excluded += calc.summary.first.ts - extended.first.ts;
+                calc.summary.first = extended.first;
+            } else {
+                calc.summary = extended;
+            }
+        }
+
+        if let Some((end, next)) = end_next {
+            let extended = calc.summary.with_next(end, next)?;
+            let gap_end = next.map_or(end, |next| next.ts);
+            if self.is_gap(gap_end - calc.summary.last.ts) {
+                calc.excluded += extended.last.ts - calc.summary.last.ts;
+                calc.summary.last = extended.last;
+            } else {
+                calc.summary = extended;
+            }
+        }
+        Ok(calc)
+    }
+
+    /// The time-weighted average over the time not spent in gaps.
+    pub fn time_weighted_average(&self) -> Result<f64, TimeWeightError> {
+        let duration = self.summary.last.ts - self.summary.first.ts - self.excluded;
+        if duration == 0 {
+            return Err(TimeWeightError::ZeroDuration);
+        }
+        Ok(self.summary.w_sum / duration as f64)
+    }
+
+    /// Evaluate the integral in microseconds, gaps contribute nothing.
+    pub fn time_weighted_integral(&self) -> f64 {
+        self.summary.time_weighted_integral()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -552,6 +725,9 @@ mod tests {
 
         let linear = TimeWeightMethod::Linear.weighted_sum(pt1, pt2);
         assert_eq!(linear, -100.0);
+
+        let nocb = TimeWeightMethod::NOCB.weighted_sum(pt1, pt2);
+        assert_eq!(nocb, -400.0);
     }
 
     fn with_prev_common_test(t: TimeWeightMethod) {
@@ -758,6 +934,33 @@ mod tests {
         with_next_common_test(TimeWeightMethod::LOCF);
     }
 
+    #[test]
+    fn test_with_prev_and_next() {
+        // both bounds are extrapolated when both are given
+        let test = TimeWeightSummary::new_from_sorted_iter(
+            vec![&TSPoint { ts: 10, val: 1.0 }, &TSPoint { ts: 20, val: 2.0 }],
+            TimeWeightMethod::Linear,
+        )
+        .unwrap();
+        let prev = TSPoint { ts: 0, val: 0.0 };
+        let next = TSPoint { ts: 30, val: 3.0 };
+        let expected = TimeWeightSummary::new_from_sorted_iter(
+            vec![
+                &TSPoint { ts: 5, val: 0.5 },
+                &TSPoint { ts: 10, val: 1.0 },
+                &TSPoint { ts: 20, val: 2.0 },
+                &TSPoint { ts: 25, val: 2.5 },
+            ],
+            TimeWeightMethod::Linear,
+        )
+        .unwrap();
+        assert_eq!(
+            test.with_bounds(Some((5, prev)), Some((25, Some(next))))
+                .unwrap(),
+            expected
+        );
+    }
+
     // add average tests
     fn average_common_tests(t: TimeWeightMethod) {
         let single = TimeWeightSummary::new(TSPoint { ts: 20, val: 2.0 }, t);
@@ -793,5 +996,170 @@ mod tests {
         .unwrap();
         let expected = (10.0 * 1.5 + 10.0 * 2.5) / (30.0 - 10.0);
         assert_eq!(test.time_weighted_average().unwrap(), expected);
+        let test = TimeWeightSummary::new_from_sorted_iter(
+            vec![
+                &TSPoint { ts: 10, val: 1.0 },
+                &TSPoint { ts: 20, val: 2.0 },
+                &TSPoint { ts: 30, val: 3.0 },
+            ],
+            TimeWeightMethod::NOCB,
+        )
+        .unwrap();
+        let expected = (10.0 * 2.0 + 10.0 * 3.0) / (30.0 - 10.0);
+        assert_eq!(test.time_weighted_average().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_nocb_with_bounds() {
+        // NOCB takes the value of the next point, so it needs one to extrapolate to the end
+        let test = TimeWeightSummary::new_from_sorted_iter(
+            vec![&TSPoint { ts: 10, val: 1.0 }, &TSPoint { ts: 20, val: 2.0 }],
+            TimeWeightMethod::NOCB,
+        )
+        .unwrap();
+        let bounded = test
+            .with_bounds(
+                Some((5, TSPoint { ts: 0, val: 5.0 })),
+                Some((25, Some(TSPoint { ts: 30, val: 3.0 }))),
+            )
+            .unwrap();
+        assert_eq!(bounded.first, TSPoint { ts: 5, val: 1.0 });
+        assert_eq!(bounded.last, TSPoint { ts: 25, val: 3.0 });
+        assert_eq!(bounded.w_sum, 5.0 * 1.0 + 10.0 * 2.0 + 5.0 * 3.0);
+        assert_eq!(
+            test.with_bounds(None, Some((25, None))).unwrap_err(),
+            TimeWeightError::InterpolateMissingPoint
+        );
+    }
+
+    fn gap_points() -> Vec<TSPoint> {
+        // the gap between 20 and 100 is longer than max_gap
+        vec![
+            TSPoint { ts: 0, val: 1.0 },
+            TSPoint { ts: 10, val: 2.0 },
+            TSPoint { ts: 20, val: 3.0 },
+            TSPoint { ts: 100, val: 4.0 },
+            TSPoint { ts: 110, val: 5.0 },
+        ]
+    }
+
+    #[test]
+    fn test_max_gap_accum() {
+        let pts = gap_points();
+        let s =
+            TimeWeightGapSummary::new_from_sorted_iter(&pts, TimeWeightMethod::Linear, 50).unwrap();
+        assert_eq!(s.excluded, 80);
+        assert_eq!(s.summary.w_sum, 15.0 + 25.0 + 45.0);
+        assert_eq!(s.time_weighted_average().unwrap(), 85.0 / 30.0);
+        assert_eq!(s.time_weighted_integral(), 85.0);
+
+        // a gap of exactly max_gap is still interpolated across
+        let s =
+            TimeWeightGapSummary::new_from_sorted_iter(&pts, TimeWeightMethod::Linear, 80).unwrap();
+        assert_eq!(s.excluded, 0);
+        assert_eq!(
+            s.summary,
+            TimeWeightSummary::new_from_sorted_iter(&pts, TimeWeightMethod::Linear).unwrap()
+        );
+
+        let s =
+            TimeWeightGapSummary::new_from_sorted_iter(&pts, TimeWeightMethod::NOCB, 50).unwrap();
+        assert_eq!(s.summary.w_sum, 20.0 + 30.0 + 50.0);
+        let s =
+            TimeWeightGapSummary::new_from_sorted_iter(&pts, TimeWeightMethod::LOCF, 50).unwrap();
+        assert_eq!(s.summary.w_sum, 10.0 + 20.0 + 40.0);
+
+        // only gaps, no duration left
+        let s = TimeWeightGapSummary::new_from_sorted_iter(
+            &[TSPoint { ts: 0, val: 1.0 }, TSPoint { ts: 100, val: 2.0 }],
+            TimeWeightMethod::Linear,
+            50,
+        )
+        .unwrap();
+        assert_eq!(
+            s.time_weighted_average().unwrap_err(),
+            TimeWeightError::ZeroDuration
+        );
+    }
+
+    #[test]
+    fn test_max_gap_combine() {
+        let pts = gap_points();
+        for method in [
+            TimeWeightMethod::LOCF,
+            TimeWeightMethod::Linear,
+            TimeWeightMethod::NOCB,
+        ] {
+            let expected = TimeWeightGapSummary::new_from_sorted_iter(&pts, method, 50).unwrap();
+            // split within the gap and elsewhere
+            for split in 1..pts.len() {
+                let a =
+                    TimeWeightGapSummary::new_from_sorted_iter(&pts[..split], method, 50).unwrap();
+                let b =
+                    TimeWeightGapSummary::new_from_sorted_iter(&pts[split..], method, 50).unwrap();
+                assert_eq!(a.combine(&b).unwrap(), expected);
+            }
+        }
+
+        let a = TimeWeightGapSummary::new_from_sorted_iter(&pts[..2], TimeWeightMethod::LOCF, 50)
+            .unwrap();
+        let b = TimeWeightGapSummary::new_from_sorted_iter(&pts[3..], TimeWeightMethod::LOCF, 60)
+            .unwrap();
+        assert_eq!(a.combine(&b), Err(TimeWeightError::MaxGapMismatch));
+        let b = TimeWeightGapSummary::new_from_sorted_iter(&pts[3..], TimeWeightMethod::Linear, 50)
+            .unwrap();
+        assert_eq!(a.combine(&b), Err(TimeWeightError::MethodMismatch));
+        let b = TimeWeightGapSummary::new_from_sorted_iter(&pts[3..], TimeWeightMethod::LOCF, 50)
+            .unwrap();
+        assert_eq!(b.combine(&a), Err(TimeWeightError::OrderError));
+    }
+
+    #[test]
+    fn test_max_gap_with_bounds() {
+        let s = TimeWeightGapSummary::new_from_sorted_iter(
+            &[TSPoint { ts: 100, val: 1.0 }, TSPoint { ts: 110, val: 2.0 }],
+            TimeWeightMethod::LOCF,
+            50,
+        )
+        .unwrap();
+
+        // close enough neighbours are extrapolated to as usual
+        let bounded = s
+            .with_bounds(
+                Some((90, TSPoint { ts: 80, val: 5.0 })),
+                Some((120, Some(TSPoint { ts: 130, val: 3.0 }))),
+            )
+            .unwrap();
+        assert_eq!(bounded.excluded, 0);
+        assert_eq!(
+            bounded.summary,
+            s.summary
+                .with_bounds(
+                    Some((90, TSPoint { ts: 80, val: 5.0 })),
+                    Some((120, Some(TSPoint { ts: 130, val: 3.0 })))
+                )
+                .unwrap()
+        );
+
+        // far away ones only move the bounds
+        let bounded = s
+            .with_bounds(
+                Some((90, TSPoint { ts: 0, val: 5.0 })),
+                Some((120, Some(TSPoint { ts: 200, val: 3.0 }))),
+            )
+            .unwrap();
+        assert_eq!(bounded.excluded, 20);
+        assert_eq!(bounded.summary.w_sum, 10.0);
+        assert_eq!(bounded.summary.first.ts, 90);
+        assert_eq!(bounded.summary.last.ts, 120);
+        assert_eq!(bounded.time_weighted_average().unwrap(), 1.0);
+
+        // without a next point LOCF only excludes the end if it is far away
+        let bounded = s.with_bounds(None, Some((150, None))).unwrap();
+        assert_eq!(bounded.excluded, 0);
+        assert_eq!(bounded.summary.w_sum, 10.0 + 80.0);
+        let bounded = s.with_bounds(None, Some((200, None))).unwrap();
+        assert_eq!(bounded.excluded, 90);
+        assert_eq!(bounded.summary.w_sum, 10.0);
     }
 }
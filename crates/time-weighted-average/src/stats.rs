@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use tspoint::TSPoint;
+
+use crate::{TimeWeightError, TimeWeightMethod, TimeWeightSummary};
+
+/// TimeWeightStats extends a TimeWeightSummary with the integral of the square
+/// of the value, from which the time-weighted variance follows, and keeps every
+/// point so that the time spent above or below any threshold can be found.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeWeightStats {
+    pub summary: TimeWeightSummary,
+    pub w_sum_sq: f64,
+    points: Vec<TSPoint>,
+}
+
+impl TimeWeightStats {
+    pub fn new(pt: TSPoint, method: TimeWeightMethod) -> Self {
+        TimeWeightStats {
+            summary: TimeWeightSummary::new(pt, method),
+            w_sum_sq: 0.0,
+            points: vec![pt],
+        }
+    }
+
+    /// `points` must be non-empty and strictly time-ordered, with `w_sum` and
+    /// `w_sum_sq` the sums they were accumulated into.
+    pub fn from_parts(
+        points: Vec<TSPoint>,
+        method: TimeWeightMethod,
+        w_sum: f64,
+        w_sum_sq: f64,
+    ) -> Self {
+        assert!(!points.is_empty());
+        TimeWeightStats {
+            summary: TimeWeightSummary {
+                method,
+                first: points[0],
+                last: *points.last().unwrap(),
+                w_sum,
+            },
+            w_sum_sq,
+            points,
+        }
+    }
+
+    pub fn points(&self) -> &[TSPoint] {
+        &self.points
+    }
+
+    pub fn accum(&mut self, pt: TSPoint) -> Result<(), TimeWeightError> {
+        let last = self.summary.last;
+        self.summary.accum(pt)?;
+        // as with TimeWeightSummary, only the first of several equal timestamps is used
+        if pt.ts > last.ts {
+            self.w_sum_sq += self.summary.method.weighted_sum_sq(last, pt);
+            self.points.push(pt);
+        }
+        Ok(())
+    }
+
+    // as with TimeWeightSummary::combine the time ranges must be disjoint
+    pub fn combine(&self, next: &TimeWeightStats) -> Result<TimeWeightStats, TimeWeightError> {
+        let summary = self.summary.combine(&next.summary)?;
+        let w_sum_sq = self.w_sum_sq
+            + next.w_sum_sq
+            + summary
+                .method
+                .weighted_sum_sq(self.summary.last, next.summary.first);
+        let mut points = Vec::with_capacity(self.points.len() + next.points.len());
+        points.extend_from_slice(&self.points);
+        points.extend_from_slice(&next.points);
+        Ok(TimeWeightStats {
+            summary,
+            w_sum_sq,
+            points,
+        })
+    }
+
+    pub fn new_from_sorted_iter<'a>(
+        iter: impl IntoIterator<Item = &'a TSPoint>,
+        method: TimeWeightMethod,
+    ) -> Result<TimeWeightStats, TimeWeightError> {
+        let mut t = iter.into_iter();
+        let mut s = match t.next() {
+            None => {
+                return Err(TimeWeightError::EmptyIterator);
+            }
+            Some(val) => TimeWeightStats::new(*val, method),
+        };
+        for p in t {
+            s.accum(*p)?;
+        }
+        Ok(s)
+    }
+
+    pub fn combine_sorted_iter<'a>(
+        iter: impl IntoIterator<Item = &'a TimeWeightStats>,
+    ) -> Result<TimeWeightStats, TimeWeightError> {
+        let mut t = iter.into_iter();
+        let mut s = match t.next() {
+            None => {
+                return Err(TimeWeightError::EmptyIterator);
+            }
+            Some(val) => val.clone(),
+        };
+        for p in t {
+            s = s.combine(p)?;
+        }
+        Ok(s)
+    }
+
+    pub fn time_weighted_average(&self) -> Result<f64, TimeWeightError> {
+        self.summary.time_weighted_average()
+    }
+
+    /// The population variance of the interpolated value over time.
+    pub fn time_weighted_variance(&self) -> Result<f64, TimeWeightError> {
+        let average = self.summary.time_weighted_average()?;
+        let duration = (self.summary.last.ts - self.summary.first.ts) as f64;
+        // rounding can take this just below zero when the value is constant
+        Ok((self.w_sum_sq / duration - average * average).max(0.0))
+    }
+
+    pub fn time_weighted_stddev(&self) -> Result<f64, TimeWeightError> {
+        self.time_weighted_variance().map(f64::sqrt)
+    }
+
+    /// Microseconds during which the interpolated value is strictly above `threshold`.
+    pub fn duration_above(&self, threshold: f64) -> f64 {
+        self.duration_where(|val| val - threshold)
+    }
+
+    /// Microseconds during which the interpolated value is strictly below `threshold`.
+    pub fn duration_below(&self, threshold: f64) -> f64 {
+        self.duration_where(|val| threshold - val)
+    }
+
+    // total time for which `margin` of the interpolated value is positive
+    fn duration_where(&self, margin: impl Fn(f64) -> f64) -> f64 {
+        self.points
+            .windows(2)
+            .map(|w| {
+                let duration = (w[1].ts - w[0].ts) as f64;
+                let (first, second) = (margin(w[0].val), margin(w[1].val));
+                match self.summary.method {
+                    TimeWeightMethod::LOCF if first > 0.0 => duration,
+                    TimeWeightMethod::NOCB if second > 0.0 => duration,
+                    TimeWeightMethod::Linear if first > 0.0 && second > 0.0 => duration,
+                    // the line crosses the threshold, only the part on the positive side counts
+                    TimeWeightMethod::Linear if first > 0.0 || second > 0.0 => {
+                        duration * first.max(second) / (first - second).abs()
+                    }
+                    _ => 0.0,
+                }
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<TSPoint> {
+        vec![
+            TSPoint { ts: 0, val: 1.0 },
+            TSPoint { ts: 10, val: 3.0 },
+            TSPoint { ts: 20, val: 3.0 },
+            TSPoint { ts: 40, val: -1.0 },
+        ]
+    }
+
+    #[test]
+    fn test_accum_matches_summary() {
+        for method in [
+            TimeWeightMethod::LOCF,
+            TimeWeightMethod::Linear,
+            TimeWeightMethod::NOCB,
+        ] {
+            let s = TimeWeightStats::new_from_sorted_iter(&points(), method).unwrap();
+            assert_eq!(
+                s.summary,
+                TimeWeightSummary::new_from_sorted_iter(&points(), method).unwrap()
+            );
+            assert_eq!(s.points(), &points()[..]);
+
+            // duplicate timestamps are dropped like they are by the summary
+            let mut d = TimeWeightStats::new_from_sorted_iter(&points()[..2], method).unwrap();
+            d.accum(TSPoint { ts: 10, val: 7.0 }).unwrap();
+            assert_eq!(
+                d,
+                TimeWeightStats::new_from_sorted_iter(&points()[..2], method).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_variance() {
+        // LOCF: 1 for 10, 3 for 30
+        let s = TimeWeightStats::new_from_sorted_iter(&points(), TimeWeightMethod::LOCF).unwrap();
+        assert_eq!(s.w_sum_sq, 10.0 + 9.0 * 30.0);
+        let mean = (10.0 + 90.0) / 40.0;
+        let variance = (10.0 + 270.0) / 40.0 - mean * mean;
+        assert_eq!(s.time_weighted_average().unwrap(), mean);
+        assert!((s.time_weighted_variance().unwrap() - variance).abs() < 1e-12);
+        assert!((s.time_weighted_stddev().unwrap() - variance.sqrt()).abs() < 1e-12);
+
+        // Linear: 1->3 over 10, 3 for 10, 3->-1 over 20
+        let s = TimeWeightStats::new_from_sorted_iter(&points(), TimeWeightMethod::Linear).unwrap();
+        let sq = 10.0 * (1.0 + 3.0 + 9.0) / 3.0 + 10.0 * 9.0 + 20.0 * (9.0 - 3.0 + 1.0) / 3.0;
+        assert!((s.w_sum_sq - sq).abs() < 1e-12);
+
+        // a constant value has no variance
+        let s = TimeWeightStats::new_from_sorted_iter(
+            &[TSPoint { ts: 0, val: 0.1 }, TSPoint { ts: 30, val: 0.1 }],
+            TimeWeightMethod::Linear,
+        )
+        .unwrap();
+        assert_eq!(s.time_weighted_variance().unwrap(), 0.0);
+
+        let single = TimeWeightStats::new(TSPoint { ts: 0, val: 1.0 }, TimeWeightMethod::LOCF);
+        assert_eq!(
+            single.time_weighted_variance(),
+            Err(TimeWeightError::ZeroDuration)
+        );
+    }
+
+    #[test]
+    fn test_combine() {
+        let pts = points();
+        for method in [
+            TimeWeightMethod::LOCF,
+            TimeWeightMethod::Linear,
+            TimeWeightMethod::NOCB,
+        ] {
+            let expected = TimeWeightStats::new_from_sorted_iter(&pts, method).unwrap();
+            for split in 1..pts.len() {
+                let a = TimeWeightStats::new_from_sorted_iter(&pts[..split], method).unwrap();
+                let b = TimeWeightStats::new_from_sorted_iter(&pts[split..], method).unwrap();
+                let c = a.combine(&b).unwrap();
+                assert_eq!(c.summary, expected.summary);
+                assert_eq!(c.points(), expected.points());
+                assert!((c.w_sum_sq - expected.w_sum_sq).abs() < 1e-12);
+                assert_eq!(b.combine(&a), Err(TimeWeightError::OrderError));
+            }
+        }
+    }
+
+    #[test]
+    fn test_duration_above_below() {
+        let pts = points();
+        let locf = TimeWeightStats::new_from_sorted_iter(&pts, TimeWeightMethod::LOCF).unwrap();
+        assert_eq!(locf.duration_above(2.0), 30.0);
+        assert_eq!(locf.duration_below(2.0), 10.0);
+        // equal to the threshold is neither
+        assert_eq!(locf.duration_above(3.0), 0.0);
+        assert_eq!(locf.duration_below(3.0), 10.0);
+
+        let nocb = TimeWeightStats::new_from_sorted_iter(&pts, TimeWeightMethod::NOCB).unwrap();
+        assert_eq!(nocb.duration_above(2.0), 20.0);
+        assert_eq!(nocb.duration_below(2.0), 20.0);
+
+        // Linear crosses 2 at 5 and 25
+        let linear = TimeWeightStats::new_from_sorted_iter(&pts, TimeWeightMethod::Linear).unwrap();
+        assert_eq!(linear.duration_above(2.0), 20.0);
+        assert_eq!(linear.duration_below(2.0), 20.0);
+        assert_eq!(linear.duration_above(3.0), 0.0);
+        assert_eq!(linear.duration_below(3.0), 30.0);
+        assert_eq!(linear.duration_above(-5.0), 40.0);
+
+        let single = TimeWeightStats::new(TSPoint { ts: 0, val: 1.0 }, TimeWeightMethod::LOCF);
+        assert_eq!(single.duration_above(0.0), 0.0);
+    }
+}
@@ -118,6 +118,33 @@ impl SketchHashMap {
         self.entry(key).count += 1;
     }
 
+    // Decrease the count at a key by `count`, dropping the entry (and
+    // unlinking it from the list of keys) if it becomes empty.
+    // Returns false if the key isn't present or holds fewer than `count` values.
+    fn decrement(&mut self, key: SketchHashKey, count: u64) -> bool {
+        let entry = match self.map.get_mut(&key) {
+            Some(entry) if entry.count >= count => entry,
+            _ => return false,
+        };
+        entry.count -= count;
+        if entry.count > 0 {
+            return true;
+        }
+
+        let next = entry.next;
+        self.map.remove(&key);
+        if self.head == key {
+            self.head = next;
+        } else {
+            let mut prev = self.head;
+            while self.map[&prev].next != key {
+                prev = self.map[&prev].next;
+            }
+            self.map.get_mut(&prev).expect("Invalid key found").next = next;
+        }
+        true
+    }
+
     fn iter(&self) -> SketchHashIterator {
         SketchHashIterator {
             container: self,
@@ -248,7 +275,7 @@ impl UDDSketch {
                 })
                 .count = counts[i];
         }
-        sketch.buckets.head = keys[0];
+        sketch.buckets.head = keys.first().copied().unwrap_or(SketchHashKey::Invalid);
 
         sketch
     }
@@ -285,18 +312,60 @@ impl UDDSketch {
         self.values_sum += value;
     }
 
+    /// Add `count` occurrences of `value` to the sketch, e.g. for pre-aggregated data.
+    pub fn add_weighted_value(&mut self, value: f64, count: u64) {
+        if count == 0 {
+            return;
+        }
+        self.buckets.entry(self.key(value)).count += count;
+
+        while self.buckets.len() > self.max_buckets as usize {
+            self.compact_buckets();
+        }
+
+        self.num_values += count;
+        self.values_sum += value * count as f64;
+    }
+
+    /// Remove a value previously added to the sketch.
+    /// Returns false, leaving the sketch unchanged, if the value's bucket doesn't hold it.
+    pub fn remove_value(&mut self, value: f64) -> bool {
+        self.remove_weighted_value(value, 1)
+    }
+
+    /// Remove `count` occurrences of a value previously added to the sketch.
+    /// Buckets that become empty are dropped.  Note that this cannot undo any
+    /// compactions, so the error bound of the sketch will not improve.
+    /// Returns false, leaving the sketch unchanged, if the value's bucket doesn't hold `count` values.
+    pub fn remove_weighted_value(&mut self, value: f64, count: u64) -> bool {
+        if count == 0 {
+            return true;
+        }
+        if !self.buckets.decrement(self.key(value), count) {
+            return false;
+        }
+
+        self.num_values -= count;
+        self.values_sum -= value * count as f64;
+        true
+    }
+
+    /// Whether the two sketches were created with the same initial error, and
+    /// can thus be brought to the same bucket boundaries by compacting.
+    pub fn has_same_initial_error(&self, other: &UDDSketch) -> bool {
+        (self
+            .gamma
+            .powf(1.0 / f64::powi(2.0, self.compactions as i32))
+            - other
+                .gamma
+                .powf(1.0 / f64::powi(2.0, other.compactions as i32)))
+        .abs()
+            < 1e-9 // f64::EPSILON too small, see issue #396
+    }
+
     pub fn merge_sketch(&mut self, other: &UDDSketch) {
         // Require matching initial parameters
-        assert!(
-            (self
-                .gamma
-                .powf(1.0 / f64::powi(2.0, self.compactions as i32))
-                - other
-                    .gamma
-                    .powf(1.0 / f64::powi(2.0, other.compactions as i32)))
-            .abs()
-                < 1e-9 // f64::EPSILON too small, see issue #396
-        );
+        assert!(self.has_same_initial_error(other));
         assert!(self.max_buckets == other.max_buckets);
 
         if other.num_values == 0 {
@@ -342,6 +411,99 @@ impl UDDSketch {
     }
 }
 
+// Distribution comparisons
+impl UDDSketch {
+    /// The Kolmogorov-Smirnov statistic between the two sketches, i.e. the
+    /// largest difference between their cumulative distributions as measured
+    /// at the bucket boundaries.
+    /// The sketches must have been created with the same initial error.
+    pub fn ks_distance(&self, other: &UDDSketch) -> f64 {
+        let mut distance: f64 = 0.0;
+        let (mut seen_a, mut seen_b) = (0, 0);
+        for (_, count_a, count_b) in self.joint_buckets(other) {
+            seen_a += count_a;
+            seen_b += count_b;
+            let cdf_a = seen_a as f64 / self.num_values as f64;
+            let cdf_b = seen_b as f64 / other.num_values as f64;
+            distance = distance.max((cdf_a - cdf_b).abs());
+        }
+        distance
+    }
+
+    /// The first Wasserstein (earth mover's) distance between the two sketches,
+    /// treating every bucket as a point mass at its estimated value.
+    /// The sketches must have been created with the same initial error.
+    pub fn wasserstein_distance(&self, other: &UDDSketch) -> f64 {
+        let buckets = self.joint_buckets(other);
+        let (alpha, gamma) = if self.compactions >= other.compactions {
+            (self.alpha, self.gamma)
+        } else {
+            (other.alpha, other.gamma)
+        };
+
+        let mut distance = 0.0;
+        let (mut seen_a, mut seen_b) = (0, 0);
+        for window in buckets.windows(2) {
+            let (key, count_a, count_b) = window[0];
+            seen_a += count_a;
+            seen_b += count_b;
+            let cdf_a = seen_a as f64 / self.num_values as f64;
+            let cdf_b = seen_b as f64 / other.num_values as f64;
+            let width =
+                bucket_to_value(alpha, gamma, window[1].0) - bucket_to_value(alpha, gamma, key);
+            distance += (cdf_a - cdf_b).abs() * width;
+        }
+        distance
+    }
+
+    // The union of the buckets of both sketches, in increasing order, along with
+    // the count each sketch has for the bucket. The less compacted sketch is
+    // compacted to match the other first.
+    fn joint_buckets(&self, other: &UDDSketch) -> Vec<(SketchHashKey, u64, u64)> {
+        assert!(self.has_same_initial_error(other));
+
+        let mut a = self.clone();
+        let mut b = other.clone();
+        while a.compactions < b.compactions {
+            a.compact_buckets();
+        }
+        while b.compactions < a.compactions {
+            b.compact_buckets();
+        }
+
+        let mut a = a.buckets.iter().peekable();
+        let mut b = b.buckets.iter().peekable();
+        let mut buckets = vec![];
+        loop {
+            let next_a = a.peek().map(|(key, _)| *key);
+            let next_b = b.peek().map(|(key, _)| *key);
+            let key = match (next_a, next_b) {
+                (None, None) => break,
+                (Some(key), None) | (None, Some(key)) => key,
+                (Some(key_a), Some(key_b)) => {
+                    if key_a < key_b {
+                        key_a
+                    } else {
+                        key_b
+                    }
+                }
+            };
+            let count_a = if next_a == Some(key) {
+                a.next().unwrap().1
+            } else {
+                0
+            };
+            let count_b = if next_b == Some(key) {
+                b.next().unwrap().1
+            } else {
+                0
+            };
+            buckets.push((key, count_a, count_b));
+        }
+        buckets
+    }
+}
+
 impl UDDSketch {
     #[inline]
     pub fn mean(&self) -> f64 {
@@ -380,6 +542,17 @@ impl UDDSketch {
     pub fn estimate_quantile_at_value(&self, value: f64) -> f64 {
         estimate_quantile_at_value(value, self.gamma, self.num_values, self.buckets.iter())
     }
+
+    pub fn estimate_trimmed_mean(&self, low: f64, high: f64) -> f64 {
+        estimate_trimmed_mean(
+            low,
+            high,
+            self.alpha,
+            self.gamma,
+            self.num_values,
+            self.buckets.iter(),
+        )
+    }
 }
 
 pub fn estimate_quantile(
@@ -407,6 +580,44 @@ pub fn estimate_quantile(
     unreachable!();
 }
 
+/// Estimate the mean of the values between the `low` and `high` quantiles.
+/// Every bucket contributes its estimated value weighted by the portion of its
+/// count that falls between the two quantiles.
+pub fn estimate_trimmed_mean(
+    low: f64,
+    high: f64,
+    alpha: f64,
+    gamma: f64,
+    num_values: u64,
+    buckets: impl Iterator<Item = (SketchHashKey, u64)>,
+) -> f64 {
+    assert!(0.0 <= low && low < high && high <= 1.0);
+
+    let start_rank = num_values as f64 * low;
+    let end_rank = num_values as f64 * high;
+    let mut seen = 0.0;
+    let mut sum = 0.0;
+    let mut weight = 0.0;
+    for (key, count) in buckets {
+        let bucket_start = seen;
+        seen += count as f64;
+        let overlap = seen.min(end_rank) - bucket_start.max(start_rank);
+        if overlap > 0.0 {
+            sum += bucket_to_value(alpha, gamma, key) * overlap;
+            weight += overlap;
+        }
+        if seen >= end_rank {
+            break;
+        }
+    }
+
+    if weight == 0.0 {
+        0.0
+    } else {
+        sum / weight
+    }
+}
+
 // Look up the value of the last bucket
 // This is not an efficient operation
 fn last_bucket_value(
@@ -454,6 +665,39 @@ pub fn estimate_quantile_at_value(
     1.0 // Greater than anything in the sketch
 }
 
+/// The range of values `(lower, upper)` that a bucket covers.
+/// Positive buckets hold values in `(lower, upper]`, negative buckets in `[lower, upper)`
+/// and the zero bucket only holds zero.
+pub fn bucket_bounds(gamma: f64, bucket: SketchHashKey) -> (f64, f64) {
+    // as in `bucket_to_value` i can exceed 2^32, so we need powf
+    match bucket {
+        SketchHashKey::Zero => (0.0, 0.0),
+        SketchHashKey::Positive(i) => (gamma.powf(i as f64 - 1.0), gamma.powf(i as f64)),
+        SketchHashKey::Negative(i) => (-gamma.powf(i as f64), -gamma.powf(i as f64 - 1.0)),
+        SketchHashKey::Invalid => panic!("Unable to find bounds of invalid bucket id"),
+    }
+}
+
+/// Estimate the number of values less than or equal to `value`.
+/// A bucket is counted in its entirety if its estimated value is at or below
+/// `value`, so the result is the exact count for some threshold within the
+/// sketch's relative error of `value`.
+pub fn estimate_count_at_or_below(
+    value: f64,
+    alpha: f64,
+    gamma: f64,
+    buckets: impl Iterator<Item = (SketchHashKey, u64)>,
+) -> u64 {
+    let mut count = 0;
+    for (key, bucket_count) in buckets {
+        if bucket_to_value(alpha, gamma, key) > value {
+            break;
+        }
+        count += bucket_count;
+    }
+    count
+}
+
 fn key(value: f64, gamma: f64) -> SketchHashKey {
     let negative = value < 0.0;
     let value = value.abs();
@@ -674,6 +918,209 @@ mod tests {
         }
     }
 
+    #[test]
+    fn add_weighted_values() {
+        let mut weighted = UDDSketch::new(20, 0.1);
+        let mut unweighted = UDDSketch::new(20, 0.1);
+        for (value, count) in [(1.1, 3), (400.0, 1), (-2.5, 7), (0.0, 2), (7.3, 0)] {
+            weighted.add_weighted_value(value, count);
+            for _ in 0..count {
+                unweighted.add_value(value);
+            }
+        }
+
+        assert_eq!(weighted.count(), 13);
+        assert_eq!(weighted.sum(), unweighted.sum());
+        assert_eq!(
+            weighted.bucket_iter().collect::<Vec<_>>(),
+            unweighted.bucket_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            weighted.estimate_quantile(0.5),
+            unweighted.estimate_quantile(0.5)
+        );
+    }
+
+    #[test]
+    fn remove_values() {
+        let mut sketch = UDDSketch::new(20, 0.1);
+        for v in [1.1, 1.5, 1.6, 4.2, -3.0, 0.0] {
+            sketch.add_value(v);
+        }
+
+        let mut expected = UDDSketch::new(20, 0.1);
+        for v in [1.5, 4.2] {
+            expected.add_value(v);
+        }
+
+        // middle, head, tail and zero buckets
+        assert!(sketch.remove_value(1.6));
+        assert!(sketch.remove_value(-3.0));
+        assert!(sketch.remove_value(0.0));
+        assert!(sketch.remove_value(1.1));
+        assert_eq!(sketch.count(), 2);
+        assert_eq!(sketch.current_buckets_count(), 2);
+        assert_eq!(
+            sketch.bucket_iter().collect::<Vec<_>>(),
+            expected.bucket_iter().collect::<Vec<_>>()
+        );
+        assert!((sketch.sum() - expected.sum()).abs() < 1e-12);
+
+        // values not in the sketch are rejected without modifying it
+        assert!(!sketch.remove_value(100.0));
+        assert!(!sketch.remove_weighted_value(1.5, 2));
+        assert_eq!(sketch.count(), 2);
+
+        assert!(sketch.remove_value(1.5));
+        assert!(sketch.remove_value(4.2));
+        assert_eq!(sketch.count(), 0);
+        assert_eq!(sketch.current_buckets_count(), 0);
+        assert_eq!(sketch.bucket_iter().count(), 0);
+
+        // an emptied sketch can still be added to
+        sketch.add_value(2.0);
+        assert_eq!(sketch.count(), 1);
+        assert_eq!(sketch.bucket_iter().count(), 1);
+    }
+
+    #[test]
+    fn remove_values_after_compaction() {
+        let mut sketch = UDDSketch::new(20, 0.1);
+        for i in 0..30 {
+            sketch.add_value(1000.0 * 1.23_f64.powi(i));
+        }
+        assert!(sketch.times_compacted() > 0);
+        let error = sketch.max_error();
+
+        for i in 0..29 {
+            assert!(sketch.remove_value(1000.0 * 1.23_f64.powi(i)));
+        }
+        assert_eq!(sketch.count(), 1);
+        assert_eq!(sketch.current_buckets_count(), 1);
+        assert_eq!(sketch.max_error(), error);
+
+        let last = 1000.0 * 1.23_f64.powi(29);
+        let estimate = sketch.estimate_quantile(0.5);
+        assert!((estimate - last).abs() / last <= error);
+    }
+
+    #[test]
+    fn test_bucket_bounds() {
+        let mut sketch = UDDSketch::new(1000, 0.05);
+        let values = [-1234.5, -7.25, -0.003, 0.0, 0.0021, 0.9, 1.7, 42.0, 98765.4];
+        for v in values {
+            sketch.add_value(v);
+        }
+
+        let mut previous_upper = f64::NEG_INFINITY;
+        for ((key, count), v) in sketch.bucket_iter().zip(values) {
+            assert_eq!(count, 1);
+            let (lower, upper) = bucket_bounds(sketch.gamma, key);
+            assert!(lower <= v && v <= upper, "{v} not in [{lower}, {upper}]");
+            assert!(lower >= previous_upper);
+            previous_upper = upper;
+
+            let estimate = bucket_to_value(sketch.alpha, sketch.gamma, key);
+            assert!(lower <= estimate && estimate <= upper);
+        }
+
+        // after compaction buckets cover the values of all the buckets compacted into them
+        let (lower, upper) = bucket_bounds(sketch.gamma, sketch.key(1.7));
+        sketch.compact_buckets();
+        let (compacted_lower, compacted_upper) = bucket_bounds(sketch.gamma, sketch.key(1.7));
+        assert!(
+            compacted_lower <= lower * (1.0 + 1e-12) && upper <= compacted_upper * (1.0 + 1e-12)
+        );
+    }
+
+    #[test]
+    fn test_count_at_or_below() {
+        let mut sketch = UDDSketch::new(100, 0.01);
+        for v in 1..=1000 {
+            sketch.add_value(v as f64);
+        }
+
+        let count = |value| {
+            estimate_count_at_or_below(value, sketch.alpha, sketch.gamma, sketch.bucket_iter())
+        };
+        assert_eq!(count(0.0), 0);
+        assert_eq!(count(-5.0), 0);
+        assert_eq!(count(1000.0 * (1.0 + sketch.max_error())), 1000);
+        assert_eq!(count(f64::INFINITY), 1000);
+        for bound in [10.0, 50.0, 100.0, 250.0, 500.0, 999.0] {
+            // the count must be the true count for some threshold within the error bounds
+            let estimate = count(bound) as f64;
+            assert!(estimate >= (bound * (1.0 - sketch.max_error())).floor());
+            assert!(estimate <= (bound * (1.0 + sketch.max_error())).floor());
+        }
+    }
+
+    #[test]
+    fn test_trimmed_mean() {
+        let mut sketch = UDDSketch::new(200, 0.001);
+        for v in 1..=1000 {
+            sketch.add_value(v as f64);
+        }
+        // a few huge outliers that trimming should remove
+        for _ in 0..10 {
+            sketch.add_value(1e12);
+        }
+
+        let full = sketch.estimate_trimmed_mean(0.0, 1.0);
+        assert!((full - sketch.mean()).abs() / sketch.mean() < sketch.max_error());
+
+        let trimmed = sketch.estimate_trimmed_mean(0.0, 0.99);
+        assert!((trimmed - 500.5).abs() / 500.5 < sketch.max_error());
+
+        // mean of the values from 250.5 through 750.5 ranks
+        let middle = sketch.estimate_trimmed_mean(0.25, 0.75);
+        assert!((middle - 505.5).abs() / 505.5 < sketch.max_error());
+
+        assert_eq!(UDDSketch::new(20, 0.1).estimate_trimmed_mean(0.1, 0.9), 0.0);
+    }
+
+    #[test]
+    fn test_distribution_distances() {
+        let mut a = UDDSketch::new(1000, 0.001);
+        let mut b = UDDSketch::new(1000, 0.001);
+        let mut c = UDDSketch::new(1000, 0.001);
+        for v in 1..=1000 {
+            a.add_value(v as f64);
+            b.add_value(v as f64 + 100.0);
+            c.add_value(v as f64 + 5000.0);
+        }
+
+        assert_eq!(a.ks_distance(&a), 0.0);
+        assert_eq!(a.wasserstein_distance(&a), 0.0);
+
+        assert!((a.ks_distance(&b) - 0.1).abs() < 0.01);
+        assert_eq!(a.ks_distance(&b), b.ks_distance(&a));
+        assert!((a.wasserstein_distance(&b) - 100.0).abs() < 100.0 * 0.01);
+        assert_eq!(a.wasserstein_distance(&b), b.wasserstein_distance(&a));
+
+        assert_eq!(a.ks_distance(&c), 1.0);
+        assert!((a.wasserstein_distance(&c) - 5000.0).abs() < 5000.0 * 0.01);
+    }
+
+    #[test]
+    fn test_distances_with_different_compactions() {
+        let mut a = UDDSketch::new(20, 0.01);
+        let mut b = UDDSketch::new(1000, 0.01);
+        for v in 1..=1000 {
+            a.add_value(v as f64);
+            b.add_value(v as f64);
+        }
+        assert!(a.times_compacted() > b.times_compacted());
+        assert!(a.has_same_initial_error(&b));
+
+        // once aligned the sketches hold exactly the same buckets
+        assert_eq!(a.ks_distance(&b), 0.0);
+        assert_eq!(a.wasserstein_distance(&b), 0.0);
+
+        let other = UDDSketch::new(20, 0.05);
+        assert!(!a.has_same_initial_error(&other));
+    }
+
     use quickcheck::*;
 
     #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
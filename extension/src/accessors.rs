@@ -662,3 +662,36 @@ pub fn accessor_percentiles(unit: Vec<f64>) -> AccessorPercentileArray<'static>
         }
     }
 }
+
+#[pg_schema]
+pub mod toolkit_experimental {
+    use super::*;
+
+    accessor! { trimmed_mean(
+        low: f64,
+        high: f64,
+    ) }
+
+    accessor! { iqr() }
+
+    pg_type! {
+        struct AccessorApproxCdf<'input> {
+            len: u64,
+            values: [f64; self.len],
+        }
+    }
+
+    ron_inout_funcs!(AccessorApproxCdf);
+
+    #[pg_extern(immutable, parallel_safe, name = "approx_cdf")]
+    pub fn accessor_approx_cdf(values: Vec<f64>) -> AccessorApproxCdf<'static> {
+        unsafe {
+            flatten! {
+                AccessorApproxCdf {
+                    len: values.len().try_into().unwrap(),
+                    values: values.into(),
+                }
+            }
+        }
+    }
+}
@@ -21,6 +21,20 @@ flat_serialize_macro::flat_serialize! {
         unused_but_required_by_flat_serialize: u64,
         Missing: 1 {},
         Transaction: 2 { vol: f64, vwap: f64 },
+        // Transaction plus statistics that depend on the order of the trades,
+        // only built from sorted ticks. `price_integral` is the integral of the
+        // last traded price over time, from which the TWAP follows, and
+        // `sum_sq_log_returns` is the sum of squared log returns between
+        // consecutive trades, the square of the realized volatility.
+        Market: 3 {
+            vol: f64,
+            vwap: f64,
+            buy_vol: f64,
+            sell_vol: f64,
+            trade_count: u64,
+            price_integral: f64,
+            sum_sq_log_returns: f64,
+        },
     }
 }
 
@@ -92,6 +106,8 @@ impl Candlestick<'_> {
     }
 
     pub fn combine(&mut self, candlestick: &Candlestick) {
+        self.volume = self.combined_volume(candlestick);
+
         if candlestick.open.ts < self.open.ts {
             self.open = candlestick.open;
         }
@@ -107,25 +123,116 @@ impl Candlestick<'_> {
         if candlestick.close.ts > self.close.ts {
             self.close = candlestick.close;
         }
+    }
 
-        if let (
-            VolKind::Transaction {
-                vol: vol1,
-                vwap: vwap1,
-            },
-            VolKind::Transaction {
-                vol: vol2,
-                vwap: vwap2,
-            },
-        ) = (self.volume, candlestick.volume)
-        {
-            self.volume = VolKind::Transaction {
+    fn combined_volume(&self, candlestick: &Candlestick) -> VolKind {
+        use VolKind::*;
+        match (self.volume, candlestick.volume) {
+            (
+                Market {
+                    vol: vol1,
+                    vwap: vwap1,
+                    buy_vol: buy_vol1,
+                    sell_vol: sell_vol1,
+                    trade_count: trade_count1,
+                    price_integral: price_integral1,
+                    sum_sq_log_returns: sum_sq_log_returns1,
+                },
+                Market {
+                    vol: vol2,
+                    vwap: vwap2,
+                    buy_vol: buy_vol2,
+                    sell_vol: sell_vol2,
+                    trade_count: trade_count2,
+                    price_integral: price_integral2,
+                    sum_sq_log_returns: sum_sq_log_returns2,
+                },
+            ) => {
+                // the order dependent statistics need the trades between the
+                // candles, which we only know if one comes entirely before the other
+                let (earlier, later) = if self.close.ts <= candlestick.open.ts {
+                    (self.close, candlestick.open)
+                } else if candlestick.close.ts <= self.open.ts {
+                    (candlestick.close, self.open)
+                } else {
+                    pgrx::error!("cannot combine overlapping candlesticks with market statistics")
+                };
+                Market {
+                    vol: vol1 + vol2,
+                    vwap: vwap1 + vwap2,
+                    buy_vol: buy_vol1 + buy_vol2,
+                    sell_vol: sell_vol1 + sell_vol2,
+                    trade_count: trade_count1 + trade_count2,
+                    price_integral: price_integral1
+                        + price_integral2
+                        + earlier.val * (later.ts - earlier.ts) as f64,
+                    sum_sq_log_returns: sum_sq_log_returns1
+                        + sum_sq_log_returns2
+                        + (later.val / earlier.val).ln().powi(2),
+                }
+            }
+            (
+                Transaction {
+                    vol: vol1,
+                    vwap: vwap1,
+                }
+                | Market {
+                    vol: vol1,
+                    vwap: vwap1,
+                    ..
+                },
+                Transaction {
+                    vol: vol2,
+                    vwap: vwap2,
+                }
+                | Market {
+                    vol: vol2,
+                    vwap: vwap2,
+                    ..
+                },
+            ) => Transaction {
                 vol: vol1 + vol2,
                 vwap: vwap1 + vwap2,
-            };
-        } else {
-            self.volume = VolKind::Missing {};
+            },
+            _ => Missing {},
+        }
+    }
+
+    /// Builds a candlestick with market statistics from time-ordered ticks,
+    /// `is_buy` being the side of each trade if known. Ticks without a volume
+    /// count as trades with no volume.
+    pub fn from_sorted_ticks(ticks: &[MarketTick]) -> Candlestick<'static> {
+        let first = ticks.first().expect("no ticks to build a candlestick from");
+        let mut cs = Candlestick::from_tick(first.ts, first.price, None);
+        let (mut vol, mut vwap, mut buy_vol, mut sell_vol) = (0.0, 0.0, 0.0, 0.0);
+        let (mut price_integral, mut sum_sq_log_returns) = (0.0, 0.0);
+        let mut prev: Option<&MarketTick> = None;
+        for tick in ticks {
+            if let Some(prev) = prev {
+                cs.add_tick_data(tick.ts, tick.price, None);
+                price_integral += prev.price * (tick.ts - prev.ts) as f64;
+                sum_sq_log_returns += (tick.price / prev.price).ln().powi(2);
+            }
+            let volume = tick.volume.unwrap_or(0.0);
+            vol += volume;
+            vwap += volume * tick.price;
+            match tick.is_buy {
+                Some(true) => buy_vol += volume,
+                Some(false) => sell_vol += volume,
+                None => {}
+            }
+            prev = Some(tick);
+        }
+        cs.volume = VolKind::Market {
+            vol,
+            vwap,
+            buy_vol,
+            sell_vol,
+            trade_count: ticks.len() as u64,
+            price_integral,
+            sum_sq_log_returns,
         };
+        cs
     }
 
     pub fn open(&self) -> f64 {
@@ -162,14 +269,14 @@ impl Candlestick<'_> {
 
     pub fn volume(&self) -> Option<f64> {
         match self.volume {
-            VolKind::Transaction { vol, .. } => Some(vol),
+            VolKind::Transaction { vol, .. } | VolKind::Market { vol, .. } => Some(vol),
             VolKind::Missing {} => None,
         }
     }
 
     pub fn vwap(&self) -> Option<f64> {
         match self.volume {
-            VolKind::Transaction { vol, vwap } => {
+            VolKind::Transaction { vol, vwap } | VolKind::Market { vol, vwap, .. } => {
                 if vol > 0.0 && vwap.is_finite() {
                     Some(vwap / vol)
                 } else {
@@ -179,6 +286,54 @@ impl Candlestick<'_> {
             VolKind::Missing {} => None,
         }
     }
+
+    pub fn trade_count(&self) -> Option<u64> {
+        match self.volume {
+            VolKind::Market { trade_count, .. } => Some(trade_count),
+            _ => None,
+        }
+    }
+
+    pub fn twap(&self) -> Option<f64> {
+        match self.volume {
+            // a single instant has no time to weight by
+            VolKind::Market { price_integral, .. } if self.close.ts > self.open.ts => {
+                Some(price_integral / (self.close.ts - self.open.ts) as f64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn realized_volatility(&self) -> Option<f64> {
+        match self.volume {
+            VolKind::Market {
+                sum_sq_log_returns, ..
+            } => Some(sum_sq_log_returns.sqrt()),
+            _ => None,
+        }
+    }
+
+    pub fn buy_volume(&self) -> Option<f64> {
+        match self.volume {
+            VolKind::Market { buy_vol, .. } => Some(buy_vol),
+            _ => None,
+        }
+    }
+
+    pub fn sell_volume(&self) -> Option<f64> {
+        match self.volume {
+            VolKind::Market { sell_vol, .. } => Some(sell_vol),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MarketTick {
+    ts: i64,
+    price: f64,
+    volume: Option<f64>,
+    is_buy: Option<bool>,
 }
 
 ron_inout_funcs!(Candlestick);
@@ -227,25 +382,21 @@ pub fn tick_data_transition(
 }
 
 pub fn tick_data_transition_inner(
-    state: Option<Inner<Candlestick>>,
+    state: Option<Inner<CandlestickTransState>>,
     ts: Option<crate::raw::TimestampTz>,
     price: Option<f64>,
     volume: Option<f64>,
     fcinfo: pg_sys::FunctionCallInfo,
-) -> Option<Inner<Candlestick>> {
+) -> Option<Inner<CandlestickTransState>> {
     unsafe {
         in_aggregate_context(fcinfo, || {
             if let (Some(ts), Some(price)) = (ts, price) {
-                match state {
-                    None => {
-                        let cs = Candlestick::from_tick(ts.into(), price, volume);
-                        Some(cs.into())
-                    }
-                    Some(mut cs) => {
-                        cs.add_tick_data(ts.into(), price, volume);
-                        Some(cs)
-                    }
+                let mut state = state.unwrap_or_else(|| CandlestickTransState::default().into());
+                match state.combined.as_mut() {
+                    None => state.combined = Some(Candlestick::from_tick(ts.into(), price, volume)),
+                    Some(cs) => cs.add_tick_data(ts.into(), price, volume),
                 }
+                Some(state)
             } else {
                 state
             }
@@ -253,6 +404,78 @@ pub fn tick_data_transition_inner(
     }
 }
 
+// Candles are combined as they arrive, except for those with market
+// statistics: they can only be combined in time order, which the input of
+// rollup and the partial aggregates from parallel workers needn't be in, so
+// they're kept until the final function and sorted there.
+#[derive(Clone, Debug, Default)]
+pub struct CandlestickTransState {
+    combined: Option<Candlestick<'static>>,
+    market: Vec<Candlestick<'static>>,
+}
+
+impl CandlestickTransState {
+    fn push(&mut self, cs: Candlestick<'static>) {
+        if let VolKind::Market { .. } = cs.volume {
+            self.market.push(cs);
+            return;
+        }
+        match self.combined.as_mut() {
+            None => self.combined = Some(cs),
+            Some(combined) => combined.combine(&cs),
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if let Some(cs) = other.combined {
+            self.push(cs);
+        }
+        self.market.extend_from_slice(&other.market);
+    }
+
+    fn finish(&self) -> Option<Candlestick<'static>> {
+        let mut market = self.market.clone();
+        market.sort_by_key(|cs| cs.open_time());
+        let market = market.into_iter().reduce(|mut earlier, later| {
+            earlier.combine(&later);
+            earlier
+        });
+        match (self.combined, market) {
+            (None, None) => None,
+            (Some(cs), None) | (None, Some(cs)) => Some(cs),
+            (Some(mut combined), Some(market)) => {
+                combined.combine(&market);
+                Some(combined)
+            }
+        }
+    }
+}
+
+impl Serialize for CandlestickTransState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let combined = self.combined.as_ref().map(|cs| &cs.0);
+        let market: Vec<_> = self.market.iter().map(|cs| &cs.0).collect();
+        (combined, market).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CandlestickTransState {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let (combined, market): (Option<CandlestickData>, Vec<CandlestickData>) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(Self {
+            combined: combined.map(Candlestick::from),
+            market: market.into_iter().map(Candlestick::from).collect(),
+        })
+    }
+}
+
 #[pg_extern(immutable, parallel_safe)]
 pub fn candlestick_rollup_trans<'a>(
     state: Internal,
@@ -262,20 +485,20 @@ pub fn candlestick_rollup_trans<'a>(
     candlestick_rollup_trans_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
 }
 
-pub fn candlestick_rollup_trans_inner<'input>(
-    state: Option<Inner<Candlestick<'input>>>,
-    value: Option<Candlestick<'input>>,
+pub fn candlestick_rollup_trans_inner(
+    state: Option<Inner<CandlestickTransState>>,
+    value: Option<Candlestick<'_>>,
     fcinfo: pg_sys::FunctionCallInfo,
-) -> Option<Inner<Candlestick<'input>>> {
+) -> Option<Inner<CandlestickTransState>> {
     unsafe {
-        in_aggregate_context(fcinfo, || match (state, value) {
-            (state, None) => state,
-            (None, Some(value)) => Some(value.into()),
-            (Some(state), Some(value)) => {
-                let mut state = *state;
-                state.combine(&value);
-                Some(state.into())
-            }
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => Candlestick::from(*value),
+            };
+            let mut state = state.unwrap_or_else(|| CandlestickTransState::default().into());
+            state.push(value);
+            Some(state)
         })
     }
 }
@@ -289,18 +512,10 @@ pub fn candlestick_final(
 }
 
 pub fn candlestick_final_inner(
-    state: Option<Inner<Candlestick<'static>>>,
+    state: Option<Inner<CandlestickTransState>>,
     fcinfo: pg_sys::FunctionCallInfo,
 ) -> Option<Candlestick<'static>> {
-    unsafe {
-        in_aggregate_context(fcinfo, || {
-            let state = match state {
-                None => return None,
-                Some(state) => *state,
-            };
-            Some(state)
-        })
-    }
+    unsafe { in_aggregate_context(fcinfo, || state?.finish()) }
 }
 
 #[pg_extern(immutable, parallel_safe)]
@@ -312,18 +527,18 @@ pub fn candlestick_combine(
     unsafe { candlestick_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal() }
 }
 
-pub fn candlestick_combine_inner<'input>(
-    state1: Option<Inner<Candlestick<'input>>>,
-    state2: Option<Inner<Candlestick<'input>>>,
+pub fn candlestick_combine_inner(
+    state1: Option<Inner<CandlestickTransState>>,
+    state2: Option<Inner<CandlestickTransState>>,
     fcinfo: pg_sys::FunctionCallInfo,
-) -> Option<Inner<Candlestick<'input>>> {
+) -> Option<Inner<CandlestickTransState>> {
     unsafe {
         in_aggregate_context(fcinfo, || match (state1, state2) {
             (None, None) => None,
-            (None, Some(only)) | (Some(only), None) => Some((*only).into()),
+            (None, Some(only)) | (Some(only), None) => Some(only.clone().into()),
             (Some(a), Some(b)) => {
-                let (mut a, b) = (*a, *b);
-                a.combine(&b);
+                let mut a = a.clone();
+                a.merge(&b);
                 Some(a.into())
             }
         })
@@ -332,9 +547,16 @@ pub fn candlestick_combine_inner<'input>(
 
 #[pg_extern(immutable, parallel_safe, strict)]
 pub fn candlestick_serialize(state: Internal) -> bytea {
-    let cs: &mut Candlestick = unsafe { state.get_mut().unwrap() };
-    let ser = &**cs;
-    crate::do_serialize!(ser)
+    let state: &mut CandlestickTransState = unsafe { state.get_mut().unwrap() };
+    // without market candles the state is written as the bare candlestick
+    // earlier versions used, version 2 adds the market candles
+    match (&state.combined, state.market.is_empty()) {
+        (Some(cs), true) => {
+            let ser = &cs.0;
+            crate::do_serialize!(ser)
+        }
+        _ => crate::do_serialize!(state, version: 2),
+    }
 }
 
 #[pg_extern(immutable, parallel_safe, strict)]
@@ -342,10 +564,18 @@ pub fn candlestick_deserialize(bytes: bytea, _internal: Internal) -> Option<Inte
     candlestick_deserialize_inner(bytes).internal()
 }
 
-pub fn candlestick_deserialize_inner(bytes: bytea) -> Inner<Candlestick<'static>> {
-    let de: CandlestickData = crate::do_deserialize!(bytes, CandlestickData);
-    let cs: Candlestick = de.into();
-    cs.into()
+pub fn candlestick_deserialize_inner(bytes: bytea) -> Inner<CandlestickTransState> {
+    let state = match crate::type_builder::serialized_version(bytes) {
+        1 => {
+            let de: CandlestickData = crate::do_deserialize!(bytes, CandlestickData);
+            CandlestickTransState {
+                combined: Some(de.into()),
+                market: vec![],
+            }
+        }
+        _ => crate::do_deserialize!(bytes, CandlestickTransState, version: 2),
+    };
+    state.into()
 }
 
 extension_sql!(
@@ -396,6 +626,151 @@ extension_sql!(
     ],
 );
 
+// Trades may arrive in any order, and partial aggregates from parallel workers
+// overlap in time, so the ticks are only turned into a candlestick once sorted.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MarketTransState {
+    ticks: Vec<MarketTick>,
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn market_tick_transition(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    price: Option<f64>,
+    volume: Option<f64>,
+    is_buy: Option<bool>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    market_tick_transition_inner(
+        unsafe { state.to_inner() },
+        ts,
+        price,
+        volume,
+        is_buy,
+        fcinfo,
+    )
+    .internal()
+}
+
+pub fn market_tick_transition_inner(
+    state: Option<Inner<MarketTransState>>,
+    ts: Option<crate::raw::TimestampTz>,
+    price: Option<f64>,
+    volume: Option<f64>,
+    is_buy: Option<bool>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<MarketTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let tick = match (ts, price) {
+                (Some(ts), Some(price)) => MarketTick {
+                    ts: ts.into(),
+                    price,
+                    volume,
+                    is_buy,
+                },
+                _ => return state,
+            };
+            let mut state = state.unwrap_or_else(|| MarketTransState::default().into());
+            state.ticks.push(tick);
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn market_tick_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe { market_tick_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal() }
+}
+
+pub fn market_tick_combine_inner(
+    state1: Option<Inner<MarketTransState>>,
+    state2: Option<Inner<MarketTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<MarketTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(only)) | (Some(only), None) => Some(only.clone().into()),
+            (Some(a), Some(b)) => {
+                let mut a = a.clone();
+                a.ticks.extend_from_slice(&b.ticks);
+                Some(a.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn market_tick_serialize(state: Internal) -> bytea {
+    let state: Inner<MarketTransState> = unsafe { state.to_inner().unwrap() };
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn market_tick_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    market_tick_deserialize_inner(bytes).internal()
+}
+
+pub fn market_tick_deserialize_inner(bytes: bytea) -> Inner<MarketTransState> {
+    let state: MarketTransState = crate::do_deserialize!(bytes, MarketTransState);
+    state.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn market_tick_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Candlestick<'static>> {
+    unsafe { market_tick_final_inner(state.to_inner(), fcinfo) }
+}
+
+pub fn market_tick_final_inner(
+    state: Option<Inner<MarketTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Candlestick<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut ticks = state?.ticks.clone();
+            // stable, so trades sharing a timestamp keep their arrival order
+            ticks.sort_by_key(|tick| tick.ts);
+            Some(Candlestick::from_sorted_ticks(&ticks))
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.candlestick_agg( \n\
+        ts TIMESTAMPTZ,\n\
+        price DOUBLE PRECISION,\n\
+        volume DOUBLE PRECISION,\n\
+        is_buy BOOLEAN\n\
+    )\n\
+    (\n\
+        sfunc = toolkit_experimental.market_tick_transition,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.market_tick_final,\n\
+        combinefunc = toolkit_experimental.market_tick_combine,\n\
+        serialfunc = toolkit_experimental.market_tick_serialize,\n\
+        deserialfunc = toolkit_experimental.market_tick_deserialize,\n\
+        parallel = safe\n\
+    );\n",
+    name = "market_candlestick_agg",
+    requires = [
+        market_tick_transition,
+        market_tick_final,
+        market_tick_combine,
+        market_tick_serialize,
+        market_tick_deserialize
+    ],
+);
+
 #[pg_extern(immutable, parallel_safe)]
 #[opname(->)]
 pub fn arrow_open(
@@ -521,12 +896,219 @@ pub fn vwap(candlestick: Option<Candlestick<'_>>) -> Option<f64> {
     }
 }
 
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn trade_count(candlestick: Option<Candlestick<'_>>) -> Option<i64> {
+    candlestick?.trade_count().map(|count| count as i64)
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn twap(candlestick: Option<Candlestick<'_>>) -> Option<f64> {
+    candlestick?.twap()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn realized_volatility(candlestick: Option<Candlestick<'_>>) -> Option<f64> {
+    candlestick?.realized_volatility()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn buy_volume(candlestick: Option<Candlestick<'_>>) -> Option<f64> {
+    candlestick?.buy_volume()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn sell_volume(candlestick: Option<Candlestick<'_>>) -> Option<f64> {
+    candlestick?.sell_volume()
+}
+
+// The candle for `[start, start + interval)` as seen by a chart: the previous
+// close is carried forward over the part of the interval before the first tick,
+// or over all of it as a zero-volume candle when there were no ticks at all.
+// Given the candle after the interval, the close is likewise held until the end
+// of the interval, so that `close_time` and `twap` cover all of it.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn interpolated_candlestick<'a>(
+    candlestick: Option<Candlestick<'a>>,
+    start: crate::raw::TimestampTz,
+    interval: crate::raw::Interval,
+    prev: default!(Option<Candlestick<'a>>, "NULL"),
+    next: default!(Option<Candlestick<'a>>, "NULL"),
+) -> Option<Candlestick<'static>> {
+    let interval_start: i64 = start.into();
+    let interval_end = interval_start + crate::datum_utils::interval_to_ms(&start, &interval);
+    interpolate(
+        candlestick,
+        interval_start,
+        interval_end,
+        prev,
+        next.is_some(),
+    )
+}
+
+fn interpolate(
+    candlestick: Option<Candlestick<'_>>,
+    interval_start: i64,
+    interval_end: i64,
+    prev: Option<Candlestick<'_>>,
+    hold_to_end: bool,
+) -> Option<Candlestick<'static>> {
+    let cs = match (candlestick, prev) {
+        (None, None) => return None,
+        (None, Some(prev)) => {
+            let close = prev.close();
+            let mut empty = Candlestick::new(interval_start, close, close, close, close, Some(0.0));
+            if let VolKind::Market { .. } = prev.volume {
+                empty.volume = VolKind::Market {
+                    vol: 0.0,
+                    vwap: 0.0,
+                    buy_vol: 0.0,
+                    sell_vol: 0.0,
+                    trade_count: 0,
+                    price_integral: 0.0,
+                    sum_sq_log_returns: 0.0,
+                };
+            }
+            if hold_to_end {
+                hold_close(&mut empty, interval_end);
+            }
+            return Some(empty);
+        }
+        (Some(cs), _) => cs,
+    };
+    assert!(
+        interval_start <= cs.open_time(),
+        "Interval start ({}) must be at or before open time ({})",
+        interval_start,
+        cs.open_time()
+    );
+    assert!(
+        interval_end > cs.close_time(),
+        "Interval end ({}) must be after close time ({})",
+        interval_end,
+        cs.close_time()
+    );
+
+    let (mut open, mut high, mut low, mut volume) = (cs.open, cs.high, cs.low, cs.volume);
+    if let Some(prev) = prev {
+        if interval_start < open.ts {
+            let carried = TSPoint {
+                ts: interval_start,
+                val: prev.close(),
+            };
+            // the carried price is held until the first trade
+            if let VolKind::Market {
+                price_integral,
+                sum_sq_log_returns,
+                ..
+            } = &mut volume
+            {
+                *price_integral += carried.val * (open.ts - carried.ts) as f64;
+                *sum_sq_log_returns += (open.val / carried.val).ln().powi(2);
+            }
+            open = carried;
+            if open.val > high.val {
+                high = open;
+            }
+            if open.val < low.val {
+                low = open;
+            }
+        }
+    }
+    let mut cs = unsafe {
+        flatten!(Candlestick {
+            open,
+            high,
+            low,
+            close: cs.close,
+            volume,
+        })
+    };
+    if hold_to_end {
+        hold_close(&mut cs, interval_end);
+    }
+    Some(cs)
+}
+
+// the close price is held until `end`, so it moves there without any return
+fn hold_close(cs: &mut Candlestick<'_>, end: i64) {
+    let close = cs.close;
+    if let VolKind::Market { price_integral, .. } = &mut cs.volume {
+        *price_integral += close.val * (end - close.ts) as f64;
+    }
+    cs.close = TSPoint {
+        ts: end,
+        val: close.val,
+    };
+}
+
+// Rolls `candles` up into consecutive periods of length `period` from `start`
+// until `end`, returning every period, empty ones included, as it would be by
+// `interpolated_candlestick` with the period before it as `prev`. Each candle
+// goes to the period containing its open, so candles crossing a period
+// boundary are an error; candles outside of `[start, end)` are ignored, except
+// that the last one before `start` is carried into the first period.
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn candlestick_resample<'a>(
+    candles: Array<'a, Candlestick<'a>>,
+    period: crate::raw::Interval,
+    start: crate::raw::TimestampTz,
+    end: crate::raw::TimestampTz,
+) -> TableIterator<
+    'static,
+    (
+        name!(time, crate::raw::TimestampTz),
+        name!(candlestick, Option<Candlestick<'static>>),
+    ),
+> {
+    let (start, end): (i64, i64) = (start.into(), end.into());
+    let mut candles: Vec<Candlestick<'static>> = candles
+        .iter()
+        .flatten()
+        .map(|cs| Candlestick::from(*cs))
+        .collect();
+    candles.sort_by_key(|cs| cs.open_time());
+
+    let mut candles = candles.into_iter().peekable();
+    let mut prev = None;
+    while let Some(cs) = candles.next_if(|cs| cs.open_time() < start) {
+        prev = Some(cs);
+    }
+
+    let mut periods = vec![];
+    let mut period_start = start;
+    while period_start < end {
+        let period_end =
+            period_start + crate::datum_utils::interval_to_ms(&period_start.into(), &period);
+        if period_end <= period_start {
+            pgrx::error!("period must be a positive interval")
+        }
+        let mut bucket: Option<Candlestick<'static>> = None;
+        while let Some(cs) = candles.next_if(|cs| cs.open_time() < period_end) {
+            if cs.close_time() >= period_end {
+                pgrx::error!("candlesticks must not cross a period boundary")
+            }
+            match bucket.as_mut() {
+                Some(bucket) => bucket.combine(&cs),
+                None => bucket = Some(cs),
+            }
+        }
+        let cs = interpolate(bucket, period_start, period_end, prev, false);
+        if cs.is_some() {
+            prev = cs;
+        }
+        periods.push((period_start.into(), cs));
+        period_start = period_end;
+    }
+    TableIterator::new(periods.into_iter())
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
     use std::ptr;
 
     use super::*;
+    use approx::assert_relative_eq;
     use pgrx_macros::pg_test;
 
     macro_rules! select_one {
@@ -1061,7 +1643,7 @@ mod tests {
             ptr::null_mut(),
         );
 
-        let output_buffer = state.unwrap().to_pg_bytes();
+        let output_buffer = state.unwrap().combined.unwrap().to_pg_bytes();
         let expected = [
             128, 1, 0, 0, 1, 0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 36, 64, 100, 0,
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 36, 64, 200, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -1070,4 +1652,370 @@ mod tests {
         ];
         assert_eq!(*output_buffer, expected);
     }
+
+    #[pg_test]
+    fn candlestick_partial_byte_io() {
+        use crate::type_builder::serialized_version;
+
+        let mut state = CandlestickTransState::default();
+        state.push(Candlestick::from_tick(100, 10.0, Some(1.0)));
+        let check = |state: &CandlestickTransState, version: u8| {
+            let buffer = candlestick_serialize(Inner::from(state.clone()).internal().unwrap());
+            assert_eq!(serialized_version(buffer), version);
+            let new_state = candlestick_deserialize_inner(buffer);
+            assert_eq!(
+                format!("{:?}", new_state.finish()),
+                format!("{:?}", state.finish())
+            );
+        };
+
+        // without market candles the partial is readable by earlier versions
+        check(&state, 1);
+
+        state.push(Candlestick::from_sorted_ticks(&[MarketTick {
+            ts: 200,
+            price: 1.0,
+            volume: Some(2.0),
+            is_buy: Some(true),
+        }]));
+        check(&state, 2);
+    }
+
+    #[pg_test]
+    fn candlestick_interpolated() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    r#"CREATE TABLE ticks AS
+                       SELECT * FROM (
+                           VALUES ('2022-08-01 00:10:00+00'::timestamptz, 10.0, 1.0),
+                                  ('2022-08-01 00:50:00+00'::timestamptz, 12.0, 1.0),
+                                  ('2022-08-01 02:30:00+00'::timestamptz, 15.0, 2.0),
+                                  ('2022-08-01 02:40:00+00'::timestamptz, 9.0, 1.0)
+                       ) AS v(ts, price, volume)"#,
+                    None,
+                    None,
+                )
+                .unwrap();
+            let candle = |hour: i32| {
+                format!(
+                    "(SELECT candlestick_agg(ts, price, volume) FROM ticks \
+                      WHERE date_trunc('hour', ts) = '2022-08-01 0{hour}:00:00+00')"
+                )
+            };
+            let interpolated = |candle: &str, hour: i32, prev: &str| {
+                format!(
+                    "SELECT toolkit_experimental.interpolated_candlestick(\
+                        {candle}, '2022-08-01 0{hour}:00:00+00', '1 hour', {prev})::text"
+                )
+            };
+
+            // no trades in the bucket, the previous close is carried across it
+            let stmt = interpolated(&candle(1), 1, &candle(0));
+            let expected = "(\
+                            version:1,\
+                            open:(ts:\"2022-08-01 01:00:00+00\",val:12),\
+                            high:(ts:\"2022-08-01 01:00:00+00\",val:12),\
+                            low:(ts:\"2022-08-01 01:00:00+00\",val:12),\
+                            close:(ts:\"2022-08-01 01:00:00+00\",val:12),\
+                            volume:Transaction(vol:0,vwap:0)\
+                            )";
+            assert_eq!(expected, select_one!(client, &stmt, &str).unwrap());
+
+            // trades start part way through, open becomes the previous close
+            let stmt = interpolated(&candle(2), 2, &candle(0));
+            let expected = "(\
+                            version:1,\
+                            open:(ts:\"2022-08-01 02:00:00+00\",val:12),\
+                            high:(ts:\"2022-08-01 02:30:00+00\",val:15),\
+                            low:(ts:\"2022-08-01 02:40:00+00\",val:9),\
+                            close:(ts:\"2022-08-01 02:40:00+00\",val:9),\
+                            volume:Transaction(vol:3,vwap:39)\
+                            )";
+            assert_eq!(expected, select_one!(client, &stmt, &str).unwrap());
+
+            // the carried close can be the high or low of the bucket
+            let stmt = interpolated(
+                &candle(2),
+                2,
+                "candlestick('2022-08-01 01:00:00+00', 20, 20, 20, 20, 1)",
+            );
+            let expected = "(\
+                            version:1,\
+                            open:(ts:\"2022-08-01 02:00:00+00\",val:20),\
+                            high:(ts:\"2022-08-01 02:00:00+00\",val:20),\
+                            low:(ts:\"2022-08-01 02:40:00+00\",val:9),\
+                            close:(ts:\"2022-08-01 02:40:00+00\",val:9),\
+                            volume:Transaction(vol:3,vwap:39)\
+                            )";
+            assert_eq!(expected, select_one!(client, &stmt, &str).unwrap());
+
+            // without a previous candle there is nothing to carry forward
+            let stmt = interpolated(&candle(2), 2, "NULL");
+            let expected = "(\
+                            version:1,\
+                            open:(ts:\"2022-08-01 02:30:00+00\",val:15),\
+                            high:(ts:\"2022-08-01 02:30:00+00\",val:15),\
+                            low:(ts:\"2022-08-01 02:40:00+00\",val:9),\
+                            close:(ts:\"2022-08-01 02:40:00+00\",val:9),\
+                            volume:Transaction(vol:3,vwap:39)\
+                            )";
+            assert_eq!(expected, select_one!(client, &stmt, &str).unwrap());
+            let stmt = interpolated(&candle(1), 1, "NULL");
+            assert!(select_one!(client, &stmt, &str).is_none());
+
+            // given a next candle, the close is held to the end of the bucket
+            let next = "candlestick('2022-08-01 03:00:00+00', 10, 10, 10, 10, 1)";
+            let stmt = format!(
+                "SELECT toolkit_experimental.interpolated_candlestick(\
+                    {}, '2022-08-01 02:00:00+00', '1 hour', {}, {next})::text",
+                candle(2),
+                candle(0)
+            );
+            let expected = "(\
+                            version:1,\
+                            open:(ts:\"2022-08-01 02:00:00+00\",val:12),\
+                            high:(ts:\"2022-08-01 02:30:00+00\",val:15),\
+                            low:(ts:\"2022-08-01 02:40:00+00\",val:9),\
+                            close:(ts:\"2022-08-01 03:00:00+00\",val:9),\
+                            volume:Transaction(vol:3,vwap:39)\
+                            )";
+            assert_eq!(expected, select_one!(client, &stmt, &str).unwrap());
+            let stmt = format!(
+                "SELECT close_time(toolkit_experimental.interpolated_candlestick(\
+                    {}, '2022-08-01 01:00:00+00', '1 hour', {}, {next}))::text",
+                candle(1),
+                candle(0)
+            );
+            assert_eq!(
+                "2022-08-01 02:00:00+00",
+                select_one!(client, &stmt, &str).unwrap()
+            );
+        });
+    }
+
+    #[pg_test]
+    fn candlestick_market_statistics() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    r#"CREATE TABLE trades AS
+                       SELECT * FROM (
+                           VALUES ('2022-08-01 00:01:00+00'::timestamptz, 12.0, 2.0, false),
+                                  ('2022-08-01 00:00:00+00'::timestamptz, 10.0, 1.0, true),
+                                  ('2022-08-01 00:03:00+00'::timestamptz, 11.0, 3.0, NULL)
+                       ) AS v(ts, price, volume, is_buy)"#,
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let check = |client: &mut pgrx::spi::SpiClient, candle: &str| {
+                let stmt = format!(
+                    "SELECT toolkit_experimental.trade_count(cs), \
+                            toolkit_experimental.buy_volume(cs), \
+                            toolkit_experimental.sell_volume(cs), \
+                            volume(cs), \
+                            vwap(cs) \
+                     FROM ({candle}) AS c(cs)"
+                );
+                let mut rows = client.update(&stmt, None, None).unwrap();
+                let row = rows.next().unwrap();
+                assert_eq!(row.get::<i64>(1).unwrap(), Some(3));
+                assert_eq!(row.get::<f64>(2).unwrap(), Some(1.0));
+                assert_eq!(row.get::<f64>(3).unwrap(), Some(2.0));
+                assert_eq!(row.get::<f64>(4).unwrap(), Some(6.0));
+                assert_relative_eq!(row.get::<f64>(5).unwrap().unwrap(), 67.0 / 6.0);
+
+                // 10 for a minute then 12 for two
+                let stmt = format!(
+                    "SELECT toolkit_experimental.twap(cs), \
+                            toolkit_experimental.realized_volatility(cs) \
+                     FROM ({candle}) AS c(cs)"
+                );
+                let (twap, rv) = select_two!(client, &stmt, f64, f64);
+                assert_relative_eq!(twap.unwrap(), 34.0 / 3.0);
+                let expected = (1.2f64.ln().powi(2) + (11.0f64 / 12.0).ln().powi(2)).sqrt();
+                assert_relative_eq!(rv.unwrap(), expected);
+            };
+
+            check(
+                &mut client,
+                "SELECT toolkit_experimental.candlestick_agg(ts, price, volume, is_buy) \
+                 FROM trades",
+            );
+
+            // rolling up disjoint buckets gives the same statistics
+            check(
+                &mut client,
+                "SELECT rollup(cs) FROM ( \
+                     SELECT toolkit_experimental.candlestick_agg(ts, price, volume, is_buy) \
+                     FROM trades GROUP BY date_trunc('minute', ts) \
+                 ) AS b(cs)",
+            );
+
+            // in any order, even with the middle bucket coming last
+            check(
+                &mut client,
+                "SELECT rollup(cs ORDER BY open_time(cs) = '2022-08-01 00:01:00+00') FROM ( \
+                     SELECT toolkit_experimental.candlestick_agg(ts, price, volume, is_buy) \
+                     FROM trades GROUP BY date_trunc('minute', ts) \
+                 ) AS b(cs)",
+            );
+
+            // a single trade has no duration for the TWAP and no returns
+            let stmt = "SELECT toolkit_experimental.twap(cs), \
+                               toolkit_experimental.realized_volatility(cs) \
+                        FROM (SELECT toolkit_experimental.candlestick_agg(ts, price, volume, is_buy) \
+                              FROM trades WHERE price = 10) AS c(cs)";
+            assert_eq!(select_two!(client, stmt, f64, f64), (None, Some(0.0)));
+
+            // candlesticks without the statistics roll up into ordinary ones
+            let stmt = "SELECT toolkit_experimental.trade_count(cs), volume(cs) FROM ( \
+                            SELECT rollup(cs) FROM ( \
+                                SELECT toolkit_experimental.candlestick_agg(ts, price, volume, is_buy) \
+                                FROM trades \
+                                UNION ALL \
+                                SELECT candlestick('2022-08-02 00:00:00+00', 1, 1, 1, 1, 4) \
+                            ) AS b(cs) \
+                        ) AS c(cs)";
+            assert_eq!(select_two!(client, stmt, i64, f64), (None, Some(10.0)));
+            let stmt =
+                "SELECT toolkit_experimental.trade_count(candlestick_agg(ts, price, volume)) \
+                        FROM trades";
+            assert!(select_one!(client, stmt, i64).is_none());
+        });
+    }
+
+    #[pg_test(error = "cannot combine overlapping candlesticks with market statistics")]
+    fn candlestick_market_statistics_overlap() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT rollup(cs) FROM ( \
+                         SELECT toolkit_experimental.candlestick_agg(ts, price, 1.0, NULL) \
+                         FROM (VALUES ('2022-08-01 00:00:00+00'::timestamptz, 1.0, 0), \
+                                      ('2022-08-01 00:02:00+00'::timestamptz, 2.0, 0), \
+                                      ('2022-08-01 00:01:00+00'::timestamptz, 3.0, 1)) AS v(ts, price, g) \
+                         GROUP BY g \
+                     ) AS b(cs)",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn candlestick_resample() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    r#"CREATE TABLE minutes AS
+                       SELECT date_trunc('minute', ts) AS minute,
+                              candlestick_agg(ts, price, volume) AS candle
+                       FROM (
+                           VALUES ('2022-08-01 00:01:10+00'::timestamptz, 10.0, 1.0),
+                                  ('2022-08-01 00:03:20+00'::timestamptz, 12.0, 1.0),
+                                  ('2022-08-01 00:04:50+00'::timestamptz, 11.0, 2.0),
+                                  ('2022-08-01 00:12:00+00'::timestamptz, 8.0, 1.0)
+                       ) AS v(ts, price, volume)
+                       GROUP BY 1"#,
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let mut rows = client
+                .update(
+                    "SELECT time::text, open(candlestick), high(candlestick), \
+                            low(candlestick), close(candlestick), volume(candlestick) \
+                     FROM toolkit_experimental.candlestick_resample( \
+                         (SELECT array_agg(candle) FROM minutes), '5 minutes', \
+                         '2022-08-01 00:00:00+00', '2022-08-01 00:20:00+00')",
+                    None,
+                    None,
+                )
+                .unwrap();
+            let mut next = || {
+                let row = rows.next().unwrap();
+                (
+                    row.get::<&str>(1).unwrap().unwrap(),
+                    row.get::<f64>(2).unwrap().unwrap(),
+                    row.get::<f64>(3).unwrap().unwrap(),
+                    row.get::<f64>(4).unwrap().unwrap(),
+                    row.get::<f64>(5).unwrap().unwrap(),
+                    row.get::<f64>(6).unwrap().unwrap(),
+                )
+            };
+            // there is no earlier close to carry into the first period
+            assert_eq!(
+                next(),
+                ("2022-08-01 00:00:00+00", 10.0, 12.0, 10.0, 11.0, 4.0)
+            );
+            assert_eq!(
+                next(),
+                ("2022-08-01 00:05:00+00", 11.0, 11.0, 11.0, 11.0, 0.0)
+            );
+            assert_eq!(
+                next(),
+                ("2022-08-01 00:10:00+00", 11.0, 11.0, 8.0, 8.0, 1.0)
+            );
+            assert_eq!(next(), ("2022-08-01 00:15:00+00", 8.0, 8.0, 8.0, 8.0, 0.0));
+            assert!(rows.next().is_none());
+
+            // leading empty periods have nothing to carry and stay NULL
+            let stmt = "SELECT count(*), count(candlestick) \
+                        FROM toolkit_experimental.candlestick_resample( \
+                            (SELECT array_agg(candle) FROM minutes WHERE minute > '2022-08-01 00:05:00+00'), \
+                            '5 minutes', '2022-08-01 00:00:00+00', '2022-08-01 00:20:00+00')";
+            assert_eq!(select_two!(client, stmt, i64, i64), (Some(4), Some(2)));
+
+            // the last candle before the range opens the first period
+            let stmt = "SELECT open(candlestick) \
+                        FROM toolkit_experimental.candlestick_resample( \
+                            (SELECT array_agg(candle) FROM minutes), '5 minutes', \
+                            '2022-08-01 00:05:00+00', '2022-08-01 00:10:00+00')";
+            assert_eq!(select_one!(client, stmt, f64), Some(11.0));
+
+            let stmt = "SELECT count(*) FROM toolkit_experimental.candlestick_resample( \
+                            NULL, '5 minutes', '2022-08-01 00:00:00+00', '2022-08-01 00:20:00+00')";
+            assert_eq!(select_one!(client, stmt, i64), Some(0));
+        });
+    }
+
+    #[pg_test(error = "candlesticks must not cross a period boundary")]
+    fn candlestick_resample_crossing_boundary() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT * FROM toolkit_experimental.candlestick_resample( \
+                         ARRAY[(SELECT rollup(c) FROM (VALUES \
+                             (candlestick('2022-08-01 00:05:00+00', 1, 1, 1, 1, 1)), \
+                             (candlestick('2022-08-01 00:15:00+00', 2, 2, 2, 2, 1))) v(c))], \
+                         '10 minutes', '2022-08-01 00:00:00+00', '2022-08-01 00:20:00+00')",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test(error = "period must be a positive interval")]
+    fn candlestick_resample_bad_period() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT * FROM toolkit_experimental.candlestick_resample( \
+                         ARRAY[candlestick('2022-08-01 00:00:00+00', 1, 1, 1, 1, 1)], '0 minutes', \
+                         '2022-08-01 00:00:00+00', '2022-08-01 00:20:00+00')",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
 }
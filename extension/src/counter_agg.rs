@@ -20,7 +20,7 @@ use crate::{
 
 use tspoint::TSPoint;
 
-use counter_agg::{range::I64Range, CounterSummaryBuilder, MetricSummary};
+use counter_agg::{range::I64Range, window::MetricWindow, CounterSummaryBuilder, MetricSummary};
 use stats_agg::stats2d::StatsSummary2D;
 
 use self::Method::*;
@@ -30,6 +30,9 @@ use crate::raw::tstzrange;
 use crate::raw::bytea;
 
 mod accessors;
+mod keyed;
+mod points;
+mod wrapping;
 
 use accessors::{CounterInterpolatedDeltaAccessor, CounterInterpolatedRateAccessor};
 
@@ -400,6 +403,98 @@ fn counter_agg_final_inner(
     }
 }
 
+// moving-aggregate transition function for counter_agg: rather than buffering
+// every point and rebuilding the summary in the final function we keep a window
+// of points that can be updated incrementally as the frame moves
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn counter_agg_moving_trans(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    metric_window_trans_inner(
+        unsafe { state.to_inner() },
+        ts,
+        val,
+        MetricWindow::new_counter,
+        fcinfo,
+    )
+    .internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn counter_agg_inv_trans(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    metric_window_inv_trans_inner(unsafe { state.to_inner() }, ts, val, fcinfo).internal()
+}
+
+pub(crate) fn metric_window_trans_inner(
+    state: Option<Inner<MetricWindow>>,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    make: fn() -> MetricWindow,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<MetricWindow>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (Some(ts), Some(val)) => TSPoint { ts: ts.into(), val },
+                _ => return state,
+            };
+            let mut state = state.unwrap_or_else(|| make().into());
+            state
+                .add_point(&p)
+                .unwrap_or_else(|e| pgrx::error!("{}, order the window by the time column", e));
+            Some(state)
+        })
+    }
+}
+
+pub(crate) fn metric_window_inv_trans_inner(
+    state: Option<Inner<MetricWindow>>,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<MetricWindow>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = match state {
+                None => panic!("Inverse function should never be called with NULL state"),
+                Some(state) => state,
+            };
+            let p = match (ts, val) {
+                (Some(ts), Some(val)) => TSPoint { ts: ts.into(), val },
+                _ => return Some(state),
+            };
+            // Returning NULL tells postgres to recompute the window from
+            // scratch, which is the correct fallback whenever the point
+            // can't be removed incrementally.
+            state.remove_point(&p)?;
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn counter_agg_moving_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<CounterSummary<'static>> {
+    let state: Option<Inner<MetricWindow>> = unsafe { state.to_inner() };
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            state?
+                .summary()
+                .map(CounterSummary::from_internal_counter_summary)
+        })
+    }
+}
+
 extension_sql!(
     "\n\
     CREATE AGGREGATE counter_agg( ts timestamptz, value DOUBLE PRECISION, bounds tstzrange )\n\
@@ -446,6 +541,38 @@ extension_sql!(
     ],
 );
 
+// The moving-aggregate form is only used inside window functions whose frame
+// start can move, everywhere else this behaves exactly like counter_agg(ts, value).
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.counter_agg( ts timestamptz, value DOUBLE PRECISION )\n\
+    (\n\
+        sfunc = counter_agg_trans_no_bounds,\n\
+        stype = internal,\n\
+        finalfunc = counter_agg_final,\n\
+        combinefunc = counter_agg_combine,\n\
+        serialfunc = counter_summary_trans_serialize,\n\
+        deserialfunc = counter_summary_trans_deserialize,\n\
+        msfunc = toolkit_experimental.counter_agg_moving_trans,\n\
+        minvfunc = toolkit_experimental.counter_agg_inv_trans,\n\
+        mstype = internal,\n\
+        mfinalfunc = toolkit_experimental.counter_agg_moving_final,\n\
+        parallel = restricted\n\
+    );\n\
+",
+    name = "counter_moving_agg",
+    requires = [
+        counter_agg_trans_no_bounds,
+        counter_agg_final,
+        counter_agg_combine,
+        counter_summary_trans_serialize,
+        counter_summary_trans_deserialize,
+        counter_agg_moving_trans,
+        counter_agg_inv_trans,
+        counter_agg_moving_final
+    ],
+);
+
 extension_sql!(
     "\n\
     CREATE AGGREGATE rollup(cs CounterSummary)\n\
@@ -1070,6 +1197,79 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn test_counter_moving_aggregate() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    "CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO test \
+                    SELECT '2020-01-01 00:00:00+00'::timestamptz + i * '1 minute'::interval, v \
+                    FROM unnest(ARRAY[10, 20, 5, 15, 15, NULL, 3, 30, 40, 1, 2]::float8[]) \
+                        WITH ORDINALITY AS t(v, i)",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            // the stable counter_agg has no moving form, so it rebuilds every frame from scratch
+            let stmt = "SELECT \
+                    delta(toolkit_experimental.counter_agg(ts, val) OVER w), \
+                    delta(counter_agg(ts, val) OVER w), \
+                    rate(toolkit_experimental.counter_agg(ts, val) OVER w), \
+                    rate(counter_agg(ts, val) OVER w), \
+                    slope(toolkit_experimental.counter_agg(ts, val) OVER w), \
+                    slope(counter_agg(ts, val) OVER w), \
+                    num_resets(toolkit_experimental.counter_agg(ts, val) OVER w)::float8, \
+                    num_resets(counter_agg(ts, val) OVER w)::float8, \
+                    num_changes(toolkit_experimental.counter_agg(ts, val) OVER w)::float8, \
+                    num_changes(counter_agg(ts, val) OVER w)::float8 \
+                FROM test \
+                WINDOW w AS (ORDER BY ts ROWS BETWEEN 2 PRECEDING AND CURRENT ROW) \
+                ORDER BY ts";
+            let mut rows = 0;
+            for row in client.update(stmt, None, None).unwrap() {
+                rows += 1;
+                for i in (1..=10).step_by(2) {
+                    let moving = row.get::<f64>(i).unwrap();
+                    let expected = row.get::<f64>(i + 1).unwrap();
+                    match (moving, expected) {
+                        (Some(moving), Some(expected)) => {
+                            assert_relative_eq!(moving, expected, epsilon = 1e-9)
+                        }
+                        (moving, expected) => assert_eq!(moving, expected),
+                    }
+                }
+            }
+            assert_eq!(rows, 11);
+        });
+    }
+
+    #[pg_test(
+        error = "out of order points: points must be submitted in time-order, order the window by the time column"
+    )]
+    fn test_counter_moving_aggregate_out_of_order() {
+        Spi::connect(|mut client| {
+            make_test_table(&mut client, "test");
+            client
+                .update(
+                    "SELECT toolkit_experimental.counter_agg(ts, val) \
+                        OVER (ORDER BY ts DESC ROWS BETWEEN 1 PRECEDING AND CURRENT ROW) \
+                    FROM test",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
     #[pg_test]
     fn test_counter_io() {
         Spi::connect(|mut client| {
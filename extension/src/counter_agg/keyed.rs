@@ -0,0 +1,470 @@
+use pgrx::{iter::TableIterator, *};
+
+use serde::{Deserialize, Serialize};
+
+use counter_agg::{CounterSummaryBuilder, MetricSummary};
+use flat_serialize_macro::FlatSerializable;
+use stats_agg::stats2d::StatsSummary2D;
+use tspoint::TSPoint;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    build,
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_type,
+    raw::bytea,
+    ron_inout_funcs,
+};
+
+// A MetricSummary for a single series, whose key is stored as the
+// `[key_start, key_end)` range of the KeyedCounterSummary's keys.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, FlatSerializable)]
+#[repr(C)]
+pub struct FlatSeriesSummary {
+    key_start: u32,
+    key_end: u32,
+    stats: StatsSummary2D<f64>,
+    first: TSPoint,
+    second: TSPoint,
+    penultimate: TSPoint,
+    last: TSPoint,
+    reset_sum: f64,
+    num_resets: u64,
+    num_changes: u64,
+}
+
+#[pg_schema]
+mod toolkit_experimental {
+    use super::*;
+
+    pg_type! {
+        #[derive(Debug)]
+        struct KeyedCounterSummary<'input> {
+            num_series: u32,
+            internal_padding: [u8; 4],
+            series: [FlatSeriesSummary; self.num_series],
+            keys_len: u64,
+            keys: [u8; self.keys_len],
+        }
+    }
+
+    ron_inout_funcs!(KeyedCounterSummary);
+}
+
+use toolkit_experimental::*;
+
+impl<'input> KeyedCounterSummary<'input> {
+    // the per-series summaries in key order
+    fn series_summaries(&self) -> impl Iterator<Item = (&str, MetricSummary)> + '_ {
+        let keys = std::str::from_utf8(self.keys.as_slice()).expect("invalid series keys");
+        self.series.iter().map(move |s| {
+            (
+                &keys[s.key_start as usize..s.key_end as usize],
+                MetricSummary {
+                    first: s.first,
+                    second: s.second,
+                    penultimate: s.penultimate,
+                    last: s.last,
+                    reset_sum: s.reset_sum,
+                    num_resets: s.num_resets,
+                    num_changes: s.num_changes,
+                    stats: s.stats,
+                    bounds: None,
+                },
+            )
+        })
+    }
+
+    fn from_series_summaries(summaries: Vec<(String, MetricSummary)>) -> Self {
+        let mut keys = String::new();
+        let series: Vec<_> = summaries
+            .into_iter()
+            .map(|(key, s)| {
+                let key_start = keys.len() as u32;
+                keys.push_str(&key);
+                FlatSeriesSummary {
+                    key_start,
+                    key_end: keys.len() as u32,
+                    stats: s.stats,
+                    first: s.first,
+                    second: s.second,
+                    penultimate: s.penultimate,
+                    last: s.last,
+                    reset_sum: s.reset_sum,
+                    num_resets: s.num_resets,
+                    num_changes: s.num_changes,
+                }
+            })
+            .collect();
+        build! {
+            KeyedCounterSummary {
+                num_series: series.len() as _,
+                internal_padding: [0; 4],
+                series: series.into(),
+                keys_len: keys.len() as _,
+                keys: keys.into_bytes().into(),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct KeyedCounterTransState {
+    #[serde(skip)]
+    point_buffer: Vec<(String, TSPoint)>,
+    // Summaries of each series, possibly several per series from different
+    // partials, which are combined series-by-series in time order.
+    summary_buffer: Vec<(String, MetricSummary)>,
+}
+
+impl KeyedCounterTransState {
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer
+            .sort_unstable_by(|(k1, p1), (k2, p2)| k1.cmp(k2).then(p1.ts.cmp(&p2.ts)));
+        let mut points = self.point_buffer.drain(..).peekable();
+        while let Some((key, first)) = points.next() {
+            let mut summary = CounterSummaryBuilder::new(&first, None);
+            while let Some((_, p)) = points.next_if(|(next, _)| *next == key) {
+                summary
+                    .add_point(&p)
+                    .unwrap_or_else(|e| pgrx::error!("{}", e));
+            }
+            self.summary_buffer.push((key, summary.build()));
+        }
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+
+        self.summary_buffer
+            .sort_unstable_by(|(k1, s1), (k2, s2)| k1.cmp(k2).then(s1.first.ts.cmp(&s2.first.ts)));
+        let mut combined = Vec::new();
+        let mut summaries = std::mem::take(&mut self.summary_buffer)
+            .into_iter()
+            .peekable();
+        while let Some((key, first)) = summaries.next() {
+            let mut summary = CounterSummaryBuilder::from(first);
+            while let Some((_, s)) = summaries.next_if(|(next, _)| *next == key) {
+                summary
+                    .combine(&s)
+                    .unwrap_or_else(|e| pgrx::error!("{}", e));
+            }
+            combined.push((key, summary.build()));
+        }
+        self.summary_buffer = combined;
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn keyed_counter_trans_serialize(state: Internal) -> bytea {
+    let state: &mut KeyedCounterTransState = unsafe { state.get_mut().unwrap() };
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_counter_trans_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    keyed_counter_trans_deserialize_inner(bytes).internal()
+}
+pub fn keyed_counter_trans_deserialize_inner(bytes: bytea) -> Inner<KeyedCounterTransState> {
+    let c: KeyedCounterTransState = crate::do_deserialize!(bytes, KeyedCounterTransState);
+    c.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_counter_agg_trans(
+    state: Internal,
+    key: Option<String>,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    keyed_counter_agg_trans_inner(unsafe { state.to_inner() }, key, ts, val, fcinfo).internal()
+}
+pub fn keyed_counter_agg_trans_inner(
+    state: Option<Inner<KeyedCounterTransState>>,
+    key: Option<String>,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<KeyedCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let (key, p) = match (key, ts, val) {
+                (Some(key), Some(ts), Some(val)) => (key, TSPoint { ts: ts.into(), val }),
+                _ => return state,
+            };
+            let mut state = state.unwrap_or_else(|| KeyedCounterTransState::default().into());
+            state.point_buffer.push((key, p));
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_counter_agg_summary_trans<'a>(
+    state: Internal,
+    value: Option<KeyedCounterSummary<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    keyed_counter_agg_summary_trans_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
+}
+pub fn keyed_counter_agg_summary_trans_inner(
+    state: Option<Inner<KeyedCounterTransState>>,
+    value: Option<KeyedCounterSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<KeyedCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state = state.unwrap_or_else(|| KeyedCounterTransState::default().into());
+            state.summary_buffer.extend(
+                value
+                    .series_summaries()
+                    .map(|(key, s)| (key.to_string(), s)),
+            );
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_counter_agg_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        keyed_counter_agg_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+pub fn keyed_counter_agg_combine_inner(
+    state1: Option<Inner<KeyedCounterTransState>>,
+    state2: Option<Inner<KeyedCounterTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<KeyedCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state)) | (Some(state), None) => {
+                let mut s = state.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                s2.summary_buffer.append(&mut s1.summary_buffer);
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn keyed_counter_agg_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<KeyedCounterSummary<'static>> {
+    keyed_counter_agg_final_inner(unsafe { state.to_inner() }, fcinfo)
+}
+fn keyed_counter_agg_final_inner(
+    state: Option<Inner<KeyedCounterTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<KeyedCounterSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.combine_summaries();
+            if state.summary_buffer.is_empty() {
+                return None;
+            }
+            Some(KeyedCounterSummary::from_series_summaries(
+                state.summary_buffer,
+            ))
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.counter_agg_by( series_key TEXT, ts timestamptz, value DOUBLE PRECISION )\n\
+    (\n\
+        sfunc = toolkit_experimental.keyed_counter_agg_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.keyed_counter_agg_final,\n\
+        combinefunc = toolkit_experimental.keyed_counter_agg_combine,\n\
+        serialfunc = toolkit_experimental.keyed_counter_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.keyed_counter_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "keyed_counter_agg",
+    requires = [
+        keyed_counter_agg_trans,
+        keyed_counter_agg_final,
+        keyed_counter_agg_combine,
+        keyed_counter_trans_serialize,
+        keyed_counter_trans_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(kcs toolkit_experimental.KeyedCounterSummary)\n\
+    (\n\
+        sfunc = toolkit_experimental.keyed_counter_agg_summary_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.keyed_counter_agg_final,\n\
+        combinefunc = toolkit_experimental.keyed_counter_agg_combine,\n\
+        serialfunc = toolkit_experimental.keyed_counter_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.keyed_counter_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "keyed_counter_rollup",
+    requires = [
+        keyed_counter_agg_summary_trans,
+        keyed_counter_agg_final,
+        keyed_counter_agg_combine,
+        keyed_counter_trans_serialize,
+        keyed_counter_trans_deserialize
+    ],
+);
+
+// Series with a single point have no rate, like a missing series in PromQL's
+// `sum(rate(...))` they don't contribute to the sum.
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+fn sum_rate<'a>(summary: KeyedCounterSummary<'a>) -> Option<f64> {
+    summary
+        .series_summaries()
+        .filter_map(|(_, s)| s.rate())
+        .reduce(|a, b| a + b)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+fn sum_delta<'a>(summary: KeyedCounterSummary<'a>) -> f64 {
+    summary.series_summaries().map(|(_, s)| s.delta()).sum()
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+fn rates<'a>(
+    summary: KeyedCounterSummary<'a>,
+) -> TableIterator<'static, (name!(series_key, String), name!(rate, Option<f64>))> {
+    let rates: Vec<_> = summary
+        .series_summaries()
+        .map(|(key, s)| (key.to_string(), s.rate()))
+        .collect();
+    TableIterator::new(rates.into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use approx::assert_relative_eq;
+    use pgrx_macros::pg_test;
+
+    use super::*;
+
+    fn make_series_table(client: &mut pgrx::spi::SpiClient) {
+        client.update("SET timezone TO 'UTC'", None, None).unwrap();
+        client
+            .update(
+                "CREATE TABLE test(series TEXT, ts timestamptz, val DOUBLE PRECISION)",
+                None,
+                None,
+            )
+            .unwrap();
+        // series b resets between its second and third points, c only has one point
+        client
+            .update(
+                "INSERT INTO test VALUES \
+                    ('a', '2020-01-01 00:00:00+00', 0), \
+                    ('a', '2020-01-01 00:01:00+00', 60), \
+                    ('a', '2020-01-01 00:02:00+00', 120), \
+                    ('b', '2020-01-01 00:00:00+00', 100), \
+                    ('b', '2020-01-01 00:01:00+00', 160), \
+                    ('b', '2020-01-01 00:02:00+00', 20), \
+                    ('c', '2020-01-01 00:01:00+00', 5)",
+                None,
+                None,
+            )
+            .unwrap();
+    }
+
+    #[pg_test]
+    fn test_counter_agg_by() {
+        Spi::connect(|mut client| {
+            make_series_table(&mut client);
+
+            for agg in [
+                "SELECT toolkit_experimental.counter_agg_by(series, ts, val) FROM test",
+                // rollup combines the partials of each series, including the reset between them
+                "SELECT toolkit_experimental.rollup(agg) FROM (\
+                    SELECT toolkit_experimental.counter_agg_by(series, ts, val) AS agg \
+                    FROM test GROUP BY ts) a",
+            ] {
+                let (sum_rate, sum_delta) = client
+                    .update(
+                        &format!(
+                            "SELECT \
+                                toolkit_experimental.sum_rate(agg), \
+                                toolkit_experimental.sum_delta(agg) \
+                            FROM ({agg}) a(agg)"
+                        ),
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                    .first()
+                    .get_two::<f64, f64>()
+                    .unwrap();
+                // a: 120 over 120s, b: 60 + 20 over 120s, c: 0
+                assert_relative_eq!(sum_rate.unwrap(), 200.0 / 120.0);
+                assert_relative_eq!(sum_delta.unwrap(), 200.0);
+
+                let rates: Vec<(Option<String>, Option<f64>)> = client
+                    .update(
+                        &format!("SELECT (toolkit_experimental.rates(agg)).* FROM ({agg}) a(agg)"),
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                    .map(|row| (row.get(1).unwrap(), row.get(2).unwrap()))
+                    .collect();
+                assert_eq!(rates.len(), 3);
+                assert_eq!(rates[0], (Some("a".to_string()), Some(1.0)));
+                assert_eq!(rates[1].0.as_deref(), Some("b"));
+                assert_relative_eq!(rates[1].1.unwrap(), 80.0 / 120.0);
+                assert_eq!(rates[2], (Some("c".to_string()), None));
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_counter_agg_by_null_key() {
+        Spi::connect(|mut client| {
+            make_series_table(&mut client);
+            // rows without a key are ignored like those without a value
+            let delta = client
+                .update(
+                    "SELECT toolkit_experimental.sum_delta(\
+                        toolkit_experimental.counter_agg_by(NULLIF(series, 'a'), ts, val)) \
+                    FROM test",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<f64>()
+                .unwrap();
+            assert_eq!(delta, Some(80.0));
+        });
+    }
+}
@@ -0,0 +1,504 @@
+use pgrx::*;
+
+use serde::{Deserialize, Serialize};
+
+use counter_agg::{points::CounterPoints, range::I64Range};
+use tspoint::TSPoint;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    build,
+    datum_utils::interval_to_ms,
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_type,
+    range::{get_range, I64RangeWrapper},
+    raw::{bytea, tstzrange, Interval},
+    ron_inout_funcs,
+    time_vector::{Timevector_TSTZ_F64, FLAG_HAS_NULLS, FLAG_IS_SORTED},
+};
+
+#[pg_schema]
+mod toolkit_experimental {
+    use super::*;
+
+    // Every point of a counter, with the values adjusted for resets.
+    pg_type! {
+        #[derive(Debug)]
+        struct CounterPointsSummary<'input> {
+            reset_sum: f64,
+            num_points: u32,
+            internal_padding: [u8; 4],
+            points: [TSPoint; self.num_points],
+            #[flat_serialize::flatten]
+            bounds: I64RangeWrapper,
+        }
+    }
+
+    ron_inout_funcs!(CounterPointsSummary);
+}
+
+use toolkit_experimental::*;
+
+impl<'input> CounterPointsSummary<'input> {
+    fn to_counter_points(&self) -> CounterPoints {
+        CounterPoints::from_parts(
+            self.points.iter().collect(),
+            self.reset_sum,
+            self.bounds.to_i64range(),
+        )
+    }
+
+    fn from_counter_points(points: CounterPoints) -> CounterPointsSummary<'static> {
+        build! {
+            CounterPointsSummary {
+                reset_sum: points.reset_sum(),
+                num_points: points.points().len() as _,
+                internal_padding: [0; 4],
+                points: points.points().to_vec().into(),
+                bounds: I64RangeWrapper::from_i64range(points.bounds),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CounterPointsTransState {
+    #[serde(skip)]
+    point_buffer: Vec<TSPoint>,
+    #[serde(skip)]
+    bounds: Option<I64Range>, // stores bounds until we combine points, after which, the bounds are stored in each summary
+    summary_buffer: Vec<CounterPoints>,
+}
+
+impl CounterPointsTransState {
+    fn new() -> Self {
+        Self {
+            point_buffer: vec![],
+            bounds: None,
+            summary_buffer: vec![],
+        }
+    }
+
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer.sort_unstable_by_key(|p| p.ts);
+        let mut iter = self.point_buffer.iter();
+        let mut summary = CounterPoints::new(iter.next().unwrap(), self.bounds);
+        for p in iter {
+            summary
+                .add_point(p)
+                .unwrap_or_else(|e| pgrx::error!("{}", e));
+        }
+        self.point_buffer.clear();
+        // check bounds only after we've combined all the points, so we aren't doing it all the time.
+        if !summary.bounds_valid() {
+            panic!("counter bounds invalid")
+        }
+        self.summary_buffer.push(summary);
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+
+        if self.summary_buffer.len() <= 1 {
+            return;
+        }
+        self.summary_buffer.sort_unstable_by_key(|s| s.first().ts);
+        let mut sum_iter = self.summary_buffer.drain(..);
+        let mut new_summary = sum_iter.next().unwrap();
+        for sum in sum_iter {
+            new_summary
+                .combine(&sum)
+                .unwrap_or_else(|e| pgrx::error!("{}", e));
+        }
+        self.summary_buffer = vec![new_summary];
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn counter_points_trans_serialize(state: Internal) -> bytea {
+    let state: &mut CounterPointsTransState = unsafe { state.get_mut().unwrap() };
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn counter_points_trans_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    counter_points_trans_deserialize_inner(bytes).internal()
+}
+pub fn counter_points_trans_deserialize_inner(bytes: bytea) -> Inner<CounterPointsTransState> {
+    let c: CounterPointsTransState = crate::do_deserialize!(bytes, CounterPointsTransState);
+    c.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn counter_points_agg_trans(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    counter_points_agg_trans_inner(unsafe { state.to_inner() }, ts, val, bounds, fcinfo).internal()
+}
+pub fn counter_points_agg_trans_inner(
+    state: Option<Inner<CounterPointsTransState>>,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<CounterPointsTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (Some(ts), Some(val)) => TSPoint { ts: ts.into(), val },
+                _ => return state,
+            };
+            match state {
+                None => {
+                    let mut s = CounterPointsTransState::new();
+                    if let Some(r) = bounds {
+                        s.bounds = get_range(r.0.cast_mut_ptr());
+                    }
+                    s.point_buffer.push(p);
+                    Some(s.into())
+                }
+                Some(mut s) => {
+                    s.point_buffer.push(p);
+                    Some(s)
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn counter_points_agg_trans_no_bounds(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    counter_points_agg_trans_inner(unsafe { state.to_inner() }, ts, val, None, fcinfo).internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn counter_points_agg_summary_trans<'a>(
+    state: Internal,
+    value: Option<CounterPointsSummary<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    counter_points_agg_summary_trans_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
+}
+pub fn counter_points_agg_summary_trans_inner(
+    state: Option<Inner<CounterPointsTransState>>,
+    value: Option<CounterPointsSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<CounterPointsTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state, value) {
+            (state, None) => state,
+            (None, Some(value)) => {
+                let mut state = CounterPointsTransState::new();
+                state.summary_buffer.push(value.to_counter_points());
+                Some(state.into())
+            }
+            (Some(mut state), Some(value)) => {
+                state.summary_buffer.push(value.to_counter_points());
+                Some(state)
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn counter_points_agg_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        counter_points_agg_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+pub fn counter_points_agg_combine_inner(
+    state1: Option<Inner<CounterPointsTransState>>,
+    state2: Option<Inner<CounterPointsTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<CounterPointsTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state)) | (Some(state), None) => {
+                let mut s = state.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                s2.summary_buffer.append(&mut s1.summary_buffer);
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn counter_points_agg_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<CounterPointsSummary<'static>> {
+    counter_points_agg_final_inner(unsafe { state.to_inner() }, fcinfo)
+}
+fn counter_points_agg_final_inner(
+    state: Option<Inner<CounterPointsTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<CounterPointsSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.combine_summaries();
+            debug_assert!(state.summary_buffer.len() <= 1);
+            state
+                .summary_buffer
+                .pop()
+                .map(CounterPointsSummary::from_counter_points)
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.counter_points_agg( ts timestamptz, value DOUBLE PRECISION, bounds tstzrange )\n\
+    (\n\
+        sfunc = toolkit_experimental.counter_points_agg_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.counter_points_agg_final,\n\
+        combinefunc = toolkit_experimental.counter_points_agg_combine,\n\
+        serialfunc = toolkit_experimental.counter_points_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.counter_points_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "counter_points_agg",
+    requires = [
+        counter_points_agg_trans,
+        counter_points_agg_final,
+        counter_points_agg_combine,
+        counter_points_trans_serialize,
+        counter_points_trans_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.counter_points_agg( ts timestamptz, value DOUBLE PRECISION )\n\
+    (\n\
+        sfunc = toolkit_experimental.counter_points_agg_trans_no_bounds,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.counter_points_agg_final,\n\
+        combinefunc = toolkit_experimental.counter_points_agg_combine,\n\
+        serialfunc = toolkit_experimental.counter_points_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.counter_points_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "counter_points_agg2",
+    requires = [
+        counter_points_agg_trans_no_bounds,
+        counter_points_agg_final,
+        counter_points_agg_combine,
+        counter_points_trans_serialize,
+        counter_points_trans_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(cps toolkit_experimental.CounterPointsSummary)\n\
+    (\n\
+        sfunc = toolkit_experimental.counter_points_agg_summary_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.counter_points_agg_final,\n\
+        combinefunc = toolkit_experimental.counter_points_agg_combine,\n\
+        serialfunc = toolkit_experimental.counter_points_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.counter_points_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "counter_points_rollup",
+    requires = [
+        counter_points_agg_summary_trans,
+        counter_points_agg_final,
+        counter_points_agg_combine,
+        counter_points_trans_serialize,
+        counter_points_trans_deserialize
+    ],
+);
+
+#[pg_extern(
+    name = "with_bounds",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn counter_points_with_bounds<'a>(
+    summary: CounterPointsSummary<'a>,
+    bounds: tstzrange,
+) -> CounterPointsSummary<'static> {
+    let mut points = summary.to_counter_points();
+    points.bounds = unsafe { get_range(bounds.0.cast_mut_ptr()) };
+    if !points.bounds_valid() {
+        panic!("counter bounds invalid")
+    }
+    CounterPointsSummary::from_counter_points(points)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+fn into_rate_timevector<'a>(
+    summary: CounterPointsSummary<'a>,
+    step: Interval,
+) -> Timevector_TSTZ_F64<'static> {
+    let points = summary.to_counter_points();
+    let start = points
+        .bounds
+        .and_then(|b| b.left)
+        .unwrap_or(points.first().ts);
+    let step = interval_to_ms(&start.into(), &step);
+    if step <= 0 {
+        pgrx::error!("step must be a positive interval")
+    }
+
+    let rates = points.step_rates(step);
+    let mut flags = FLAG_IS_SORTED;
+    let mut null_val = vec![0u8; (rates.len() + 7) / 8];
+    let rates: Vec<_> = rates
+        .into_iter()
+        .enumerate()
+        .map(|(i, (ts, rate))| match rate {
+            Some(val) => TSPoint { ts, val },
+            None => {
+                flags |= FLAG_HAS_NULLS;
+                null_val[i / 8] |= 1 << (i % 8);
+                TSPoint { ts, val: f64::NAN }
+            }
+        })
+        .collect();
+
+    build! {
+        Timevector_TSTZ_F64 {
+            num_points: rates.len() as _,
+            flags,
+            internal_padding: [0; 3],
+            points: rates.into(),
+            null_val: null_val.into(),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use approx::assert_relative_eq;
+    use pgrx_macros::pg_test;
+
+    use super::*;
+
+    fn setup(client: &mut pgrx::spi::SpiClient) {
+        client.update("SET timezone TO 'UTC'", None, None).unwrap();
+        client
+            .update(
+                "CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)",
+                None,
+                None,
+            )
+            .unwrap();
+        // resets between 00:01 and 00:02
+        client
+            .update(
+                "INSERT INTO test VALUES \
+                    ('2020-01-01 00:00:00+00', 10), \
+                    ('2020-01-01 00:01:00+00', 70), \
+                    ('2020-01-01 00:02:00+00', 30), \
+                    ('2020-01-01 00:03:00+00', 150)",
+                None,
+                None,
+            )
+            .unwrap();
+    }
+
+    fn rates(client: &mut pgrx::spi::SpiClient, agg: &str, step: &str) -> Vec<Option<f64>> {
+        client
+            .update(
+                &format!(
+                    "SELECT value FROM unnest(toolkit_experimental.into_rate_timevector(({agg}), '{step}'))"
+                ),
+                None,
+                None,
+            )
+            .unwrap()
+            .map(|row| row.get::<f64>(1).unwrap())
+            .collect()
+    }
+
+    #[pg_test]
+    fn test_counter_points_rate_timevector() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            // adjusted values are 10, 70, 100, 220
+            let r = rates(
+                &mut client,
+                "SELECT toolkit_experimental.counter_points_agg(ts, val) FROM test",
+                "90 seconds",
+            );
+            assert_eq!(r.len(), 2);
+            // 10 -> 85 over 90s, 85 -> 220 over 90s
+            assert_relative_eq!(r[0].unwrap(), 75.0 / 90.0);
+            assert_relative_eq!(r[1].unwrap(), 135.0 / 90.0);
+
+            // rollup matches aggregating the points directly, including the
+            // reset between partials
+            let r = rates(
+                &mut client,
+                "SELECT toolkit_experimental.rollup(agg) FROM (\
+                    SELECT toolkit_experimental.counter_points_agg(ts, val) AS agg \
+                    FROM test GROUP BY ts) a",
+                "1 minute",
+            );
+            assert_eq!(r.len(), 3);
+            assert_relative_eq!(r[0].unwrap(), 1.0);
+            assert_relative_eq!(r[1].unwrap(), 0.5);
+            assert_relative_eq!(r[2].unwrap(), 2.0);
+
+            // steps outside of the points have no rate, unnest shows them as NaN
+            let r = rates(
+                &mut client,
+                "SELECT toolkit_experimental.with_bounds(\
+                    toolkit_experimental.counter_points_agg(ts, val), \
+                    '[2020-01-01 00:00:00+00, 2020-01-01 00:05:00+00)') \
+                FROM test",
+                "2 minutes",
+            );
+            assert_eq!(r.len(), 3);
+            assert_relative_eq!(r[0].unwrap(), 0.75);
+            assert_relative_eq!(r[1].unwrap(), 2.0);
+            assert!(r[2].unwrap().is_nan());
+        });
+    }
+
+    #[pg_test(error = "step must be a positive interval")]
+    fn test_counter_points_bad_step() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+            rates(
+                &mut client,
+                "SELECT toolkit_experimental.counter_points_agg(ts, val) FROM test",
+                "0 seconds",
+            );
+        });
+    }
+}
@@ -0,0 +1,536 @@
+use pgrx::*;
+
+use serde::{Deserialize, Serialize};
+
+use counter_agg::{range::I64Range, MetricSummary, WrappingCounterSummaryBuilder};
+use tspoint::TSPoint;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    flatten,
+    gauge_agg::FlatSummary,
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_type,
+    range::get_range,
+    raw::{bytea, tstzrange},
+    ron_inout_funcs,
+};
+
+#[pg_schema]
+mod toolkit_experimental {
+    use super::*;
+
+    // A CounterSummary along with the modulus the counter wraps around at and
+    // how many times it did so, wraps are folded into the reset_sum.
+    pg_type! {
+        #[derive(Debug, PartialEq)]
+        struct WrappingCounterSummary {
+            modulus: f64,
+            num_wraps: u64,
+            #[flat_serialize::flatten]
+            summary: FlatSummary,
+        }
+    }
+
+    ron_inout_funcs!(WrappingCounterSummary);
+}
+
+use toolkit_experimental::*;
+
+impl<'input> WrappingCounterSummary<'input> {
+    fn to_builder(&self) -> WrappingCounterSummaryBuilder {
+        WrappingCounterSummaryBuilder::from_summary(
+            MetricSummary::from(&self.summary),
+            self.modulus,
+            self.num_wraps,
+        )
+    }
+
+    fn from_builder(builder: WrappingCounterSummaryBuilder) -> Self {
+        let modulus = builder.modulus();
+        let num_wraps = builder.num_wraps();
+        unsafe {
+            flatten!(WrappingCounterSummary {
+                modulus,
+                num_wraps,
+                summary: builder.build().into(),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WrappingCounterTransState {
+    #[serde(skip)]
+    point_buffer: Vec<TSPoint>,
+    #[serde(skip)]
+    bounds: Option<I64Range>, // stores bounds until we combine points, after which, the bounds are stored in each summary
+    modulus: f64,
+    summary_buffer: Vec<WrappingCounterSummaryBuilder>,
+}
+
+impl WrappingCounterTransState {
+    fn new(modulus: f64) -> Self {
+        Self {
+            point_buffer: vec![],
+            bounds: None,
+            modulus,
+            summary_buffer: vec![],
+        }
+    }
+
+    fn push_summary(&mut self, summary: WrappingCounterSummaryBuilder) {
+        if summary.modulus() != self.modulus {
+            pgrx::error!("cannot combine counter summaries that wrap at different values")
+        }
+        self.summary_buffer.push(summary);
+    }
+
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer.sort_unstable_by_key(|p| p.ts);
+        let mut iter = self.point_buffer.iter();
+        let mut summary =
+            WrappingCounterSummaryBuilder::new(iter.next().unwrap(), self.bounds, self.modulus);
+        for p in iter {
+            summary
+                .add_point(p)
+                .unwrap_or_else(|e| pgrx::error!("{}", e));
+        }
+        self.point_buffer.clear();
+        // check bounds only after we've combined all the points, so we aren't doing it all the time.
+        if !summary.bounds_valid() {
+            panic!("counter bounds invalid")
+        }
+        self.summary_buffer.push(summary);
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+
+        if self.summary_buffer.len() <= 1 {
+            return;
+        }
+        self.summary_buffer.sort_unstable_by_key(|s| s.first().ts);
+        let mut sum_iter = self.summary_buffer.drain(..);
+        let mut new_summary = sum_iter.next().unwrap();
+        for sum in sum_iter {
+            let num_wraps = sum.num_wraps();
+            new_summary
+                .combine(&sum.build(), num_wraps)
+                .unwrap_or_else(|e| pgrx::error!("{}", e));
+        }
+        self.summary_buffer = vec![new_summary];
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn wrapping_counter_trans_serialize(state: Internal) -> bytea {
+    let state: &mut WrappingCounterTransState = unsafe { state.get_mut().unwrap() };
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn wrapping_counter_trans_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    wrapping_counter_trans_deserialize_inner(bytes).internal()
+}
+pub fn wrapping_counter_trans_deserialize_inner(bytes: bytea) -> Inner<WrappingCounterTransState> {
+    let c: WrappingCounterTransState = crate::do_deserialize!(bytes, WrappingCounterTransState);
+    c.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn wrapping_counter_agg_trans(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    wrap: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    wrapping_counter_agg_trans_inner(unsafe { state.to_inner() }, ts, val, bounds, wrap, fcinfo)
+        .internal()
+}
+pub fn wrapping_counter_agg_trans_inner(
+    state: Option<Inner<WrappingCounterTransState>>,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    wrap: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<WrappingCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (Some(ts), Some(val)) => TSPoint { ts: ts.into(), val },
+                _ => return state,
+            };
+            match state {
+                None => {
+                    let wrap = match wrap {
+                        Some(wrap) if wrap.is_finite() && wrap > 0.0 => wrap,
+                        _ => pgrx::error!("counter wrap value must be a positive number"),
+                    };
+                    let mut s = WrappingCounterTransState::new(wrap);
+                    if let Some(r) = bounds {
+                        s.bounds = get_range(r.0.cast_mut_ptr());
+                    }
+                    s.point_buffer.push(p);
+                    Some(s.into())
+                }
+                Some(mut s) => {
+                    s.point_buffer.push(p);
+                    Some(s)
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn wrapping_counter_agg_summary_trans<'a>(
+    state: Internal,
+    value: Option<WrappingCounterSummary<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    wrapping_counter_agg_summary_trans_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
+}
+pub fn wrapping_counter_agg_summary_trans_inner(
+    state: Option<Inner<WrappingCounterTransState>>,
+    value: Option<WrappingCounterSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<WrappingCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state, value) {
+            (state, None) => state,
+            (None, Some(value)) => {
+                let mut state = WrappingCounterTransState::new(value.modulus);
+                state.push_summary(value.to_builder());
+                Some(state.into())
+            }
+            (Some(mut state), Some(value)) => {
+                state.push_summary(value.to_builder());
+                Some(state)
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn wrapping_counter_agg_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        wrapping_counter_agg_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+pub fn wrapping_counter_agg_combine_inner(
+    state1: Option<Inner<WrappingCounterTransState>>,
+    state2: Option<Inner<WrappingCounterTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<WrappingCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state)) | (Some(state), None) => {
+                let mut s = state.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                for summary in s1.summary_buffer {
+                    s2.push_summary(summary);
+                }
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn wrapping_counter_agg_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<WrappingCounterSummary<'static>> {
+    wrapping_counter_agg_final_inner(unsafe { state.to_inner() }, fcinfo)
+}
+fn wrapping_counter_agg_final_inner(
+    state: Option<Inner<WrappingCounterTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<WrappingCounterSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.combine_summaries();
+            debug_assert!(state.summary_buffer.len() <= 1);
+            state
+                .summary_buffer
+                .pop()
+                .map(WrappingCounterSummary::from_builder)
+        })
+    }
+}
+
+// Aggregates can't be called with named arguments, so the wrap value always
+// comes after the (possibly NULL) bounds; a 3-argument form would be ambiguous
+// with counter_agg(ts, value, bounds) for untyped literals.
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.counter_agg( ts timestamptz, value DOUBLE PRECISION, bounds tstzrange, wrap DOUBLE PRECISION )\n\
+    (\n\
+        sfunc = toolkit_experimental.wrapping_counter_agg_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.wrapping_counter_agg_final,\n\
+        combinefunc = toolkit_experimental.wrapping_counter_agg_combine,\n\
+        serialfunc = toolkit_experimental.wrapping_counter_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.wrapping_counter_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "wrapping_counter_agg",
+    requires = [
+        wrapping_counter_agg_trans,
+        wrapping_counter_agg_final,
+        wrapping_counter_agg_combine,
+        wrapping_counter_trans_serialize,
+        wrapping_counter_trans_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(wcs toolkit_experimental.WrappingCounterSummary)\n\
+    (\n\
+        sfunc = toolkit_experimental.wrapping_counter_agg_summary_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.wrapping_counter_agg_final,\n\
+        combinefunc = toolkit_experimental.wrapping_counter_agg_combine,\n\
+        serialfunc = toolkit_experimental.wrapping_counter_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.wrapping_counter_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "wrapping_counter_rollup",
+    requires = [
+        wrapping_counter_agg_summary_trans,
+        wrapping_counter_agg_final,
+        wrapping_counter_agg_combine,
+        wrapping_counter_trans_serialize,
+        wrapping_counter_trans_deserialize
+    ],
+);
+
+#[pg_extern(
+    name = "delta",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn wrapping_counter_delta<'a>(summary: WrappingCounterSummary<'a>) -> f64 {
+    MetricSummary::from(&summary.summary).delta()
+}
+
+#[pg_extern(
+    name = "rate",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn wrapping_counter_rate<'a>(summary: WrappingCounterSummary<'a>) -> Option<f64> {
+    MetricSummary::from(&summary.summary).rate()
+}
+
+#[pg_extern(
+    name = "num_resets",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn wrapping_counter_num_resets<'a>(summary: WrappingCounterSummary<'a>) -> i64 {
+    MetricSummary::from(&summary.summary).num_resets as i64
+}
+
+#[pg_extern(
+    name = "num_wraps",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn wrapping_counter_num_wraps<'a>(summary: WrappingCounterSummary<'a>) -> i64 {
+    summary.num_wraps as i64
+}
+
+#[pg_extern(
+    name = "num_changes",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn wrapping_counter_num_changes<'a>(summary: WrappingCounterSummary<'a>) -> i64 {
+    MetricSummary::from(&summary.summary).num_changes as i64
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use approx::assert_relative_eq;
+    use pgrx_macros::pg_test;
+
+    use super::*;
+
+    #[pg_test]
+    fn test_wrapping_counter() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    "CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            // a 32-bit counter that wraps twice
+            client
+                .update(
+                    "INSERT INTO test VALUES \
+                        ('2020-01-01 00:00:00+00', 4294967000), \
+                        ('2020-01-01 00:01:00+00', 4294967200), \
+                        ('2020-01-01 00:02:00+00', 104), \
+                        ('2020-01-01 00:03:00+00', 4294967000), \
+                        ('2020-01-01 00:04:00+00', 96)",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let (delta, rate, resets) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.delta(agg), \
+                        toolkit_experimental.rate(agg), \
+                        toolkit_experimental.num_resets(agg)::float8 \
+                    FROM (SELECT toolkit_experimental.counter_agg(ts, val, NULL, 4294967296) AS agg FROM test) a",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_three::<f64, f64, f64>()
+                .unwrap();
+            let increase = 96.0 + 2.0 * 4294967296.0 - 4294967000.0;
+            assert_relative_eq!(delta.unwrap(), increase);
+            assert_relative_eq!(rate.unwrap(), increase / 240.0);
+            assert_eq!(resets.unwrap(), 0.0);
+
+            // a drop that isn't near the modulus is a reset
+            let (resets, wraps) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.num_resets(agg), \
+                        toolkit_experimental.num_wraps(agg) \
+                    FROM (SELECT toolkit_experimental.counter_agg(ts, val, NULL, 4294967296) AS agg \
+                        FROM (VALUES \
+                            ('2020-01-01 00:00:00+00'::timestamptz, 100.0::float8), \
+                            ('2020-01-01 00:01:00+00', 10.0)) v(ts, val)) a",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            assert_eq!(resets, Some(1));
+            assert_eq!(wraps, Some(0));
+
+            let (wraps, changes) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.num_wraps(agg), \
+                        toolkit_experimental.num_changes(agg) \
+                    FROM (SELECT toolkit_experimental.counter_agg(ts, val, NULL, 4294967296) AS agg FROM test) a",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            assert_eq!(wraps, Some(2));
+            assert_eq!(changes, Some(4));
+
+            // the plain counter_agg treats the wraps as resets
+            let resets = client
+                .update(
+                    "SELECT num_resets(counter_agg(ts, val)) FROM test",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<i64>()
+                .unwrap();
+            assert_eq!(resets, Some(2));
+
+            // wraps at the boundary between partials are found by rollup
+            let (rolled_up, wraps) = client
+                .update(
+                    "WITH t AS (\
+                        SELECT toolkit_experimental.counter_agg(ts, val, NULL, 4294967296) AS agg \
+                        FROM test GROUP BY date_trunc('minute', ts)) \
+                    SELECT toolkit_experimental.delta(toolkit_experimental.rollup(agg)), \
+                        toolkit_experimental.num_wraps(toolkit_experimental.rollup(agg))::float8 \
+                    FROM t",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, f64>()
+                .unwrap();
+            assert_relative_eq!(rolled_up.unwrap(), increase);
+            assert_eq!(wraps, Some(2.0));
+        });
+    }
+
+    #[pg_test(error = "counter wrap value must be a positive number")]
+    fn test_wrapping_counter_bad_modulus() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.counter_agg('2020-01-01 00:00:00+00'::timestamptz, 1, NULL, 0)",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test(error = "cannot combine counter summaries that wrap at different values")]
+    fn test_wrapping_counter_mismatched_modulus() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.rollup(agg) FROM (\
+                        SELECT toolkit_experimental.counter_agg('2020-01-01 00:00:00+00'::timestamptz, 1, NULL, 256) AS agg \
+                        UNION ALL \
+                        SELECT toolkit_experimental.counter_agg('2020-01-01 00:01:00+00'::timestamptz, 2, NULL, 65536) \
+                    ) v",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+}
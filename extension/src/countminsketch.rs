@@ -1,16 +1,28 @@
-use pgrx::*;
+use std::hash::{BuildHasher, Hasher};
+
+use pgrx::{iter::SetOfIterator, *};
+
+use pg_sys::{Datum, Oid};
+use serde::{Deserialize, Serialize};
 
-use aggregate_builder::aggregate;
 use countminsketch::{CountMinHashFn, CountMinSketch as CountMinSketchInternal};
 
 use crate::{
-    flatten,
-    palloc::{Inner, Internal},
+    aggregate_utils::{get_collation, in_aggregate_context},
+    build,
+    datum_utils::{deep_copy_datum, DatumHashBuilder, DatumStore},
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_any_element::PgAnyElementHashMap,
     pg_type,
     raw::bytea,
     ron_inout_funcs,
+    serialization::{PgCollationId, ShortTypeId},
 };
 
+// Version 1 sketches only had the dimensions and counters, version 2 added
+// the element type, heavy hitters and update mode.
+const COUNT_MIN_SKETCH_VERSION: u8 = 2;
+
 #[pg_schema]
 pub mod toolkit_experimental {
     use super::*;
@@ -20,22 +32,20 @@ pub mod toolkit_experimental {
         struct CountMinSketch<'input> {
             width: u32,
             depth: u32,
+            element_type: ShortTypeId,
+            collation: PgCollationId,
+            heavy_hitters: u64, // u64 to keep the counters aligned
             counters: [i64; self.width * self.depth],
+            candidates: DatumStore<'input>,
+            conservative: bool,
         }
     }
 
-    impl CountMinSketch<'_> {
-        fn new(width: u32, depth: u32, counters: Vec<i64>) -> Self {
-            let counters_arr = counters.try_into().unwrap();
-            unsafe {
-                flatten!(CountMinSketch {
-                    width,
-                    depth,
-                    counters: counters_arr,
-                })
-            }
-        }
+    impl CountMinSketchData<'_> {
+        pub const LAYOUT_VERSION: Option<u8> = Some(COUNT_MIN_SKETCH_VERSION);
+    }
 
+    impl CountMinSketch<'_> {
         pub fn to_internal_countminsketch(&self) -> CountMinSketchInternal {
             let depth: u64 = self.depth.into();
             let hashfuncs = (1..=depth).map(CountMinHashFn::with_key).collect();
@@ -61,13 +71,44 @@ pub mod toolkit_experimental {
             )
         }
 
-        pub fn from_internal_countminsketch(sketch: &mut CountMinSketchInternal) -> Self {
-            CountMinSketch::new(
-                sketch.width().try_into().unwrap(),
-                sketch.depth().try_into().unwrap(),
-                sketch.counters().iter().flatten().cloned().collect(),
+        pub(super) fn to_trans_state(&self) -> CountMinTransState {
+            CountMinTransState::from_parts(
+                self.to_internal_countminsketch(),
+                self.hasher(),
+                self.heavy_hitters as u32,
+                self.conservative,
+                self.candidates.iter(),
             )
         }
+
+        pub(super) fn hasher(&self) -> DatumHashBuilder {
+            unsafe { DatumHashBuilder::from_type_id(self.element_type.0, Some(self.collation.0)) }
+        }
+    }
+
+    impl From<&CountMinTransState> for CountMinSketch<'_> {
+        fn from(state: &CountMinTransState) -> Self {
+            let sketch = &state.sketch;
+            let hasher = state.candidates.hasher();
+            let candidates = state
+                .sorted_candidates()
+                .into_iter()
+                .map(|(value, _)| value)
+                .collect::<Vec<_>>();
+            build! {
+                CountMinSketch {
+                    version: COUNT_MIN_SKETCH_VERSION,
+                    width: sketch.width().try_into().unwrap(),
+                    depth: sketch.depth().try_into().unwrap(),
+                    element_type: ShortTypeId(hasher.type_id),
+                    collation: PgCollationId(hasher.collation),
+                    heavy_hitters: state.heavy_hitters.into(),
+                    counters: sketch.counters().iter().flatten().cloned().collect::<Vec<_>>().into(),
+                    candidates: DatumStore::from((hasher.type_id, candidates)),
+                    conservative: state.conservative,
+                }
+            }
+        }
     }
 
     ron_inout_funcs!(CountMinSketch);
@@ -75,60 +116,692 @@ pub mod toolkit_experimental {
 
 use toolkit_experimental::CountMinSketch;
 
-#[aggregate]
-impl toolkit_experimental::count_min_sketch {
-    type State = CountMinSketchInternal;
-
-    fn transition(
-        state: Option<State>,
-        #[sql_type("text")] value: Option<String>,
-        #[sql_type("float")] error: f64,
-        #[sql_type("float")] probability: f64,
-    ) -> Option<State> {
-        let value = match value {
-            None => return state,
-            Some(value) => value,
+/// Transition state for the count-min sketch aggregates.
+///
+/// When heavy-hitter tracking is enabled `candidates` holds up to
+/// `heavy_hitters` of the values with the largest estimated counts, each
+/// mapped to its hash and its estimate as of the last time it was checked.
+/// `conservative` sketches use conservative update when adding values.
+pub struct CountMinTransState {
+    sketch: CountMinSketchInternal,
+    candidates: PgAnyElementHashMap<(u64, i64)>,
+    heavy_hitters: u32,
+    conservative: bool,
+}
+
+impl CountMinTransState {
+    fn new(
+        sketch: CountMinSketchInternal,
+        hasher: DatumHashBuilder,
+        heavy_hitters: u32,
+        conservative: bool,
+    ) -> Self {
+        Self {
+            sketch,
+            candidates: PgAnyElementHashMap::with_hasher(hasher),
+            heavy_hitters,
+            conservative,
+        }
+    }
+
+    fn from_parts(
+        sketch: CountMinSketchInternal,
+        hasher: DatumHashBuilder,
+        heavy_hitters: u32,
+        conservative: bool,
+        candidates: impl Iterator<Item = Datum>,
+    ) -> Self {
+        let mut state = Self::new(sketch, hasher, heavy_hitters, conservative);
+        for value in candidates {
+            let hash = state.hash(value);
+            let estimate = state.sketch.estimate(hash);
+            state.insert_candidate(value, hash, estimate);
+        }
+        state
+    }
+
+    fn type_oid(&self) -> Oid {
+        self.candidates.typoid()
+    }
+
+    fn hash(&self, value: Datum) -> u64 {
+        let mut hasher = self.candidates.hasher().build_hasher();
+        hasher.write_usize(value.value());
+        hasher.finish()
+    }
+
+    fn add(&mut self, value: Datum) {
+        let hash = self.hash(value);
+        if self.conservative {
+            self.sketch.add_value_conservative(hash);
+        } else {
+            self.sketch.add_value(hash);
+        }
+        if self.heavy_hitters > 0 {
+            let estimate = self.sketch.estimate(hash);
+            self.offer_candidate(value, hash, estimate);
+        }
+    }
+
+    // The stored estimates only ever lag behind the sketch, so a value that
+    // doesn't beat the smallest stored estimate cannot beat the current one
+    // either, and we only need to refresh the candidates when it does.
+    fn offer_candidate(&mut self, value: Datum, hash: u64, estimate: i64) {
+        let typoid = self.type_oid();
+        if let Some(candidate) = self.candidates.get_mut(&(value, typoid).into()) {
+            candidate.1 = estimate;
+            return;
+        }
+
+        if self.candidates.len() < self.heavy_hitters as usize {
+            self.insert_candidate(value, hash, estimate);
+            return;
+        }
+
+        let smallest = |candidates: &PgAnyElementHashMap<(u64, i64)>| {
+            candidates
+                .iter()
+                .map(|(_, &candidate)| candidate)
+                .min_by_key(|&(_, estimate)| estimate)
         };
+        match smallest(&self.candidates) {
+            Some((_, min)) if min < estimate => (),
+            _ => return,
+        }
 
-        let mut state = match state {
-            None => CountMinSketchInternal::with_prob(error, probability),
-            Some(state) => state,
+        let sketch = &self.sketch;
+        for (hash, estimate) in self.candidates.values_mut() {
+            *estimate = sketch.estimate(*hash);
+        }
+        let (min_hash, min) = match smallest(&self.candidates) {
+            Some((min_hash, min)) if min < estimate => (min_hash, min),
+            _ => return,
         };
 
-        state.add_value(value);
-        Some(state)
+        let mut evicted = false;
+        self.candidates.retain(|_, &mut (hash, estimate)| {
+            let evict = !evicted && hash == min_hash && estimate == min;
+            evicted |= evict;
+            !evict
+        });
+        self.insert_candidate(value, hash, estimate);
     }
 
-    fn finally(state: Option<&mut State>) -> Option<CountMinSketch<'static>> {
-        state.map(CountMinSketch::from_internal_countminsketch)
+    // After the counters have been lowered, re-estimates the candidates and
+    // drops any whose count has gone entirely.
+    fn reestimate_candidates(&mut self) {
+        let sketch = &self.sketch;
+        self.candidates.retain(|_, (hash, estimate)| {
+            *estimate = sketch.estimate(*hash);
+            *estimate > 0
+        });
     }
 
-    const PARALLEL_SAFE: bool = true;
+    fn insert_candidate(&mut self, value: Datum, hash: u64, estimate: i64) {
+        let typoid = self.type_oid();
+        let value = unsafe { deep_copy_datum(value, typoid) };
+        self.candidates
+            .insert((value, typoid).into(), (hash, estimate));
+    }
 
-    fn serialize(state: &mut State) -> bytea {
-        crate::do_serialize!(state)
+    /// The heavy-hitter candidates along with their current estimated counts,
+    /// most frequent first.
+    fn sorted_candidates(&self) -> Vec<(Datum, i64)> {
+        let mut candidates: Vec<_> = self
+            .candidates
+            .iter()
+            .map(|(value, &(hash, _))| (value.datum(), self.sketch.estimate(hash)))
+            .collect();
+        candidates.sort_by_key(|&(_, estimate)| std::cmp::Reverse(estimate));
+        candidates
     }
 
-    fn deserialize(bytes: bytea) -> State {
-        crate::do_deserialize!(bytes, State)
+    fn combine(one: &Self, two: &Self) -> Self {
+        check_compatible((one.type_oid(), &one.sketch), (two.type_oid(), &two.sketch));
+
+        let mut sketch = one.sketch.clone();
+        sketch.combine(two.sketch.clone());
+        let heavy_hitters = one.heavy_hitters.max(two.heavy_hitters);
+        // counters updated conservatively stay that way after the merge
+        let conservative = one.conservative || two.conservative;
+        let mut result = Self::new(
+            sketch,
+            one.candidates.hasher().clone(),
+            heavy_hitters,
+            conservative,
+        );
+
+        for (value, &(hash, _)) in one.candidates.iter().chain(two.candidates.iter()) {
+            let estimate = result.sketch.estimate(hash);
+            result.offer_candidate(value.datum(), hash, estimate);
+        }
+        result
     }
+}
 
-    fn combine(state1: Option<&State>, state2: Option<&State>) -> Option<State> {
-        match (state1, state2) {
-            (None, None) => None,
-            (None, Some(only)) | (Some(only), None) => Some(only.clone()),
-            (Some(a), Some(b)) => {
-                let (mut a, b) = (a.clone(), b.clone());
-                a.combine(b);
-                Some(a)
+// Sketches can only be merged or compared if they count the same type, and
+// the same values map to the same buckets in both.
+fn check_compatible(
+    (type_a, sketch_a): (Oid, &CountMinSketchInternal),
+    (type_b, sketch_b): (Oid, &CountMinSketchInternal),
+) {
+    if type_a != type_b {
+        pgrx::error!("count-min sketches must have the same type")
+    }
+    if sketch_a.width() != sketch_b.width()
+        || sketch_a.depth() != sketch_b.depth()
+        || sketch_a.hash_keys() != sketch_b.hash_keys()
+    {
+        pgrx::error!("count-min sketches must have the same width, depth and hash keys")
+    }
+}
+
+impl Clone for CountMinTransState {
+    fn clone(&self) -> Self {
+        Self::from_parts(
+            self.sketch.clone(),
+            self.candidates.hasher().clone(),
+            self.heavy_hitters,
+            self.conservative,
+            self.candidates.iter().map(|(value, _)| value.datum()),
+        )
+    }
+}
+
+// The candidates need their type to be serialized, so we write them out
+// as a DatumStore alongside the rest of the state.
+impl Serialize for CountMinTransState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let candidates = self
+            .candidates
+            .iter()
+            .map(|(value, _)| value.datum())
+            .collect::<Vec<_>>();
+        let candidates = DatumStore::from((self.type_oid(), candidates));
+        (
+            &self.sketch,
+            self.candidates.hasher(),
+            self.heavy_hitters,
+            self.conservative,
+            candidates,
+        )
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CountMinTransState {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let (sketch, hasher, heavy_hitters, conservative, candidates): (
+            CountMinSketchInternal,
+            DatumHashBuilder,
+            u32,
+            bool,
+            DatumStore,
+        ) = Deserialize::deserialize(deserializer)?;
+        Ok(Self::from_parts(
+            sketch,
+            hasher,
+            heavy_hitters,
+            conservative,
+            candidates.iter(),
+        ))
+    }
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn count_min_sketch_trans(
+    state: Internal,
+    value: Option<AnyElement>,
+    error: f64,
+    probability: f64,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    count_min_sketch_trans_inner(
+        unsafe { state.to_inner() },
+        value,
+        error,
+        probability,
+        0,
+        false,
+        fcinfo,
+    )
+    .internal()
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn count_min_sketch_heavy_hitters_trans(
+    state: Internal,
+    value: Option<AnyElement>,
+    error: f64,
+    probability: f64,
+    heavy_hitters: i32,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    if heavy_hitters <= 0 {
+        pgrx::error!("count-min sketch requires a number of heavy hitters > 0")
+    }
+    count_min_sketch_trans_inner(
+        unsafe { state.to_inner() },
+        value,
+        error,
+        probability,
+        heavy_hitters as u32,
+        false,
+        fcinfo,
+    )
+    .internal()
+}
+
+/// A `heavy_hitters` of 0 disables heavy-hitter tracking.
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn count_min_sketch_conservative_trans(
+    state: Internal,
+    value: Option<AnyElement>,
+    error: f64,
+    probability: f64,
+    heavy_hitters: i32,
+    conservative: bool,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    if heavy_hitters < 0 {
+        pgrx::error!("count-min sketch requires a number of heavy hitters >= 0")
+    }
+    count_min_sketch_trans_inner(
+        unsafe { state.to_inner() },
+        value,
+        error,
+        probability,
+        heavy_hitters as u32,
+        conservative,
+        fcinfo,
+    )
+    .internal()
+}
+
+pub fn count_min_sketch_trans_inner(
+    state: Option<Inner<CountMinTransState>>,
+    value: Option<AnyElement>,
+    error: f64,
+    probability: f64,
+    heavy_hitters: u32,
+    conservative: bool,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<CountMinTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state = match state {
+                Some(state) => state,
+                None => {
+                    let hasher = DatumHashBuilder::from_type_id(value.oid(), get_collation(fcinfo));
+                    let sketch = CountMinSketchInternal::with_prob(error, probability);
+                    CountMinTransState::new(sketch, hasher, heavy_hitters, conservative).into()
+                }
+            };
+            state.add(value.datum());
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn count_min_sketch_rollup_trans<'a>(
+    state: Internal,
+    value: Option<CountMinSketch<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    count_min_sketch_rollup_trans_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
+}
+
+pub fn count_min_sketch_rollup_trans_inner(
+    state: Option<Inner<CountMinTransState>>,
+    value: Option<CountMinSketch>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<CountMinTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value.to_trans_state(),
+            };
+            match state {
+                None => Some(value.into()),
+                Some(state) => Some(CountMinTransState::combine(&state, &value).into()),
             }
-        }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn count_min_sketch_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        count_min_sketch_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+
+pub fn count_min_sketch_combine_inner(
+    state1: Option<Inner<CountMinTransState>>,
+    state2: Option<Inner<CountMinTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<CountMinTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(only)) | (Some(only), None) => Some(only.clone().into()),
+            (Some(a), Some(b)) => Some(CountMinTransState::combine(&a, &b).into()),
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict)]
+pub fn count_min_sketch_serialize(state: Internal) -> bytea {
+    let state: &CountMinTransState = unsafe { state.get().unwrap() };
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe)]
+pub fn count_min_sketch_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    let state: CountMinTransState = crate::do_deserialize!(bytes, CountMinTransState);
+    Inner::from(state).internal()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn count_min_sketch_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<CountMinSketch<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let state: Option<Inner<CountMinTransState>> = state.to_inner();
+            state.map(|state| CountMinSketch::from(&*state))
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.count_min_sketch(\n\
+        value AnyElement, error double precision, probability double precision\n\
+    ) (\n\
+        sfunc = toolkit_experimental.count_min_sketch_trans,\n\
+        stype = internal,\n\
+        finalfunc = count_min_sketch_final,\n\
+        combinefunc = count_min_sketch_combine,\n\
+        serialfunc = count_min_sketch_serialize,\n\
+        deserialfunc = count_min_sketch_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "count_min_sketch_agg",
+    requires = [
+        count_min_sketch_trans,
+        count_min_sketch_final,
+        count_min_sketch_combine,
+        count_min_sketch_serialize,
+        count_min_sketch_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.count_min_sketch(\n\
+        value AnyElement, error double precision, probability double precision, heavy_hitters integer\n\
+    ) (\n\
+        sfunc = toolkit_experimental.count_min_sketch_heavy_hitters_trans,\n\
+        stype = internal,\n\
+        finalfunc = count_min_sketch_final,\n\
+        combinefunc = count_min_sketch_combine,\n\
+        serialfunc = count_min_sketch_serialize,\n\
+        deserialfunc = count_min_sketch_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "count_min_sketch_heavy_hitters_agg",
+    requires = [
+        count_min_sketch_heavy_hitters_trans,
+        count_min_sketch_final,
+        count_min_sketch_combine,
+        count_min_sketch_serialize,
+        count_min_sketch_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.count_min_sketch(\n\
+        value AnyElement, error double precision, probability double precision, heavy_hitters integer, conservative boolean\n\
+    ) (\n\
+        sfunc = toolkit_experimental.count_min_sketch_conservative_trans,\n\
+        stype = internal,\n\
+        finalfunc = count_min_sketch_final,\n\
+        combinefunc = count_min_sketch_combine,\n\
+        serialfunc = count_min_sketch_serialize,\n\
+        deserialfunc = count_min_sketch_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "count_min_sketch_conservative_agg",
+    requires = [
+        count_min_sketch_conservative_trans,
+        count_min_sketch_final,
+        count_min_sketch_combine,
+        count_min_sketch_serialize,
+        count_min_sketch_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(\n\
+        sketch toolkit_experimental.CountMinSketch\n\
+    ) (\n\
+        sfunc = toolkit_experimental.count_min_sketch_rollup_trans,\n\
+        stype = internal,\n\
+        finalfunc = count_min_sketch_final,\n\
+        combinefunc = count_min_sketch_combine,\n\
+        serialfunc = count_min_sketch_serialize,\n\
+        deserialfunc = count_min_sketch_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "count_min_sketch_rollup",
+    requires = [
+        count_min_sketch_rollup_trans,
+        count_min_sketch_final,
+        count_min_sketch_combine,
+        count_min_sketch_serialize,
+        count_min_sketch_deserialize
+    ],
+);
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn approx_count<'a>(
+    item: Option<AnyElement>,
+    aggregate: Option<CountMinSketch<'a>>,
+) -> Option<i64> {
+    let (item, sketch) = match (item, aggregate) {
+        (Some(item), Some(sketch)) => (item, sketch),
+        _ => return None,
+    };
+    Some(estimate(&sketch, item.oid(), item.datum()))
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "approx_count"
+)]
+pub fn approx_count_text<'a>(
+    item: Option<String>,
+    aggregate: Option<CountMinSketch<'a>>,
+) -> Option<i64> {
+    let (item, sketch) = match (item, aggregate) {
+        (Some(item), Some(sketch)) => (item, sketch),
+        _ => return None,
+    };
+    Some(estimate(
+        &sketch,
+        pg_sys::TEXTOID,
+        item.into_datum().unwrap(),
+    ))
+}
+
+fn estimate(sketch: &CountMinSketch<'_>, typoid: Oid, item: Datum) -> i64 {
+    if typoid != sketch.element_type.0 {
+        pgrx::error!("mismatched types")
+    }
+    let mut hasher = sketch.hasher().build_hasher();
+    hasher.write_usize(item.value());
+    sketch
+        .to_internal_countminsketch()
+        .estimate(hasher.finish())
+}
+
+/// Estimates the inner product of the two sketches' count vectors, e.g. the
+/// size of an equi-join on the counted values. For sketches built with
+/// conservative update this is no longer guaranteed to be an overestimate.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn approx_inner_product<'a>(a: CountMinSketch<'a>, b: CountMinSketch<'a>) -> i64 {
+    let (sketch_a, sketch_b) = (
+        a.to_internal_countminsketch(),
+        b.to_internal_countminsketch(),
+    );
+    check_compatible((a.element_type.0, &sketch_a), (b.element_type.0, &sketch_b));
+    sketch_a.inner_product(&sketch_b)
+}
+
+/// Removes the counts in `b` from `a`, e.g. to drop an expired interval from
+/// a rollup. The result is only meaningful if everything counted in `b` was
+/// also counted in `a`. Any heavy hitters are those tracked by `a`.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn cms_subtract<'a>(a: CountMinSketch<'a>, b: CountMinSketch<'a>) -> CountMinSketch<'static> {
+    if a.conservative || b.conservative {
+        pgrx::error!("cannot subtract count-min sketches built with conservative update")
     }
+    let mut state = a.to_trans_state();
+    let other = b.to_internal_countminsketch();
+    check_compatible(
+        (state.type_oid(), &state.sketch),
+        (b.element_type.0, &other),
+    );
+    state.sketch.subtract(other);
+    state.reestimate_candidates();
+    CountMinSketch::from(&state)
 }
 
+/// Multiplies all the counts in the sketch by `factor`, rounding up, e.g. to
+/// exponentially decay older data before combining it with newer.
 #[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
-pub fn approx_count<'a>(item: String, aggregate: Option<CountMinSketch<'a>>) -> Option<i64> {
-    aggregate.map(|sketch| CountMinSketch::to_internal_countminsketch(&sketch).estimate(item))
+pub fn cms_scale<'a>(sketch: CountMinSketch<'a>, factor: f64) -> CountMinSketch<'static> {
+    if !(factor >= 0.0 && factor.is_finite()) {
+        pgrx::error!("count-min sketch scale factor must be a non-negative number")
+    }
+    let mut state = sketch.to_trans_state();
+    state.sketch.scale(factor);
+    state.reestimate_candidates();
+    CountMinSketch::from(&state)
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "width"
+)]
+pub fn count_min_sketch_width(sketch: CountMinSketch<'_>) -> i32 {
+    sketch.width as i32
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "depth"
+)]
+pub fn count_min_sketch_depth(sketch: CountMinSketch<'_>) -> i32 {
+    sketch.depth as i32
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "total"
+)]
+pub fn count_min_sketch_total(sketch: CountMinSketch<'_>) -> i64 {
+    sketch.to_internal_countminsketch().total()
+}
+
+/// The largest amount by which an `approx_count` is expected to exceed the
+/// true count, with probability `error_probability`.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "error_bound"
+)]
+pub fn count_min_sketch_error_bound(sketch: CountMinSketch<'_>) -> f64 {
+    let sketch = sketch.to_internal_countminsketch();
+    sketch.epsilon() * sketch.total() as f64
+}
+
+/// The probability that an `approx_count` exceeds the true count by more than
+/// `error_bound`.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "error_probability"
+)]
+pub fn count_min_sketch_error_probability(sketch: CountMinSketch<'_>) -> f64 {
+    sketch.to_internal_countminsketch().delta()
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "topn"
+)]
+pub fn count_min_sketch_topn(
+    sketch: CountMinSketch<'_>,
+    n: i32,
+    ty: Option<AnyElement>,
+) -> SetOfIterator<AnyElement> {
+    // If called with a NULL, assume type matches
+    if ty.is_some() && ty.unwrap().oid() != sketch.element_type.0 {
+        pgrx::error!("mismatched types")
+    }
+    if sketch.heavy_hitters == 0 {
+        pgrx::error!("count-min sketch was not built with heavy-hitter tracking")
+    }
+    if n > sketch.heavy_hitters as i32 {
+        pgrx::error!(
+            "requested N ({}) exceeds the number of heavy hitters tracked by the sketch ({})",
+            n,
+            sketch.heavy_hitters
+        )
+    }
+
+    let type_oid = sketch.element_type.0;
+    let candidates = sketch.to_trans_state().sorted_candidates();
+    SetOfIterator::new(candidates.into_iter().take(n.max(0) as usize).map_while(
+        move |(value, _)| unsafe { AnyElement::from_polymorphic_datum(value, false, type_oid) },
+    ))
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -246,23 +919,53 @@ mod tests {
                 .unwrap().first()
                 .get_one::<String>().unwrap();
 
-            let expected = "(\
-                version:1,\
+            let default_collation = ron::to_string(&PgCollationId(
+                crate::serialization::collations::DEFAULT_COLLATION_OID,
+            ))
+            .unwrap();
+            let expected = format!(
+                "(\
+                version:2,\
                 width:6,\
                 depth:5,\
+                element_type:TEXT,\
+                collation:{},\
+                heavy_hitters:0,\
                 counters:[\
-                    1,2,2,1,1,1,\
-                    0,0,2,3,1,2,\
-                    1,0,3,0,4,0,\
-                    1,3,2,0,1,1,\
-                    0,0,4,3,0,1\
-                    ]\
-                )";
-
-            assert_eq!(sketch, Some(expected.into()));
+                    1,2,1,2,0,2,\
+                    3,1,0,1,2,1,\
+                    1,2,0,2,1,2,\
+                    1,1,1,0,2,3,\
+                    1,2,1,2,1,1\
+                    ],\
+                candidates:[25],\
+                conservative:false\
+                )",
+                default_collation
+            );
+
+            assert_eq!(sketch, Some(expected));
         });
     }
 
+    #[pg_test(error = "unsupported CountMinSketch version 1, expected 2")]
+    fn test_countminsketch_old_version() {
+        // a version 1 sketch was the header followed by its width, depth and
+        // counters, here a single one
+        let mut old = vec![0, 0, 0, 0, 1, 0, 0, 0];
+        old.extend_from_slice(&1_u32.to_ne_bytes());
+        old.extend_from_slice(&1_u32.to_ne_bytes());
+        old.extend_from_slice(&0_i64.to_ne_bytes());
+        unsafe {
+            pgrx::set_varsize(old.as_mut_ptr().cast(), old.len() as i32);
+            CountMinSketch::from_polymorphic_datum(
+                pg_sys::Datum::from(old.as_ptr()),
+                false,
+                pg_sys::Oid::INVALID,
+            );
+        }
+    }
+
     #[pg_test]
     fn test_cms_null_input_yields_null_output() {
         Spi::connect(|mut client| {
@@ -294,4 +997,365 @@ mod tests {
             assert_eq!(output, None)
         })
     }
+
+    #[pg_test]
+    fn test_countminsketch_int() {
+        Spi::connect(|mut client| {
+            let (one, fifty_one, missing) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.approx_count(1, sketch), \
+                        toolkit_experimental.approx_count(51, sketch), \
+                        toolkit_experimental.approx_count(1000, sketch) \
+                    FROM (\
+                        SELECT toolkit_experimental.count_min_sketch(v, 0.01, 0.01) sketch \
+                        FROM (SELECT generate_series(1, 100) UNION ALL SELECT generate_series(1, 50)) u(v)\
+                    ) q",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_three::<i64, i64, i64>()
+                .unwrap();
+
+            let err_margin = 0.01 * 150.0;
+            for (approx_count, expected) in [(one, 2), (fifty_one, 1), (missing, 0)] {
+                let approx_count = approx_count.unwrap();
+                assert!(expected <= approx_count);
+                assert!((approx_count as f64) < err_margin + expected as f64);
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_countminsketch_rollup() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TABLE cms_rollup AS \
+                    SELECT v % 2 AS part, toolkit_experimental.count_min_sketch(v % 10, 0.01, 0.01, 3) sketch \
+                    FROM generate_series(1, 1000) v \
+                    GROUP BY v % 2",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let (rolled_up, direct) = client
+                .update(
+                    "SELECT \
+                        (SELECT toolkit_experimental.rollup(sketch)::text FROM cms_rollup), \
+                        (SELECT toolkit_experimental.count_min_sketch(v % 10, 0.01, 0.01, 3)::text \
+                         FROM generate_series(1, 1000) v)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<String, String>()
+                .unwrap();
+            let (rolled_up, direct) = (rolled_up.unwrap(), direct.unwrap());
+            // the candidate lists may pick different values out of the ties
+            let counters = |s: &str| s[..s.find("candidates").unwrap()].to_string();
+            assert_eq!(counters(&rolled_up), counters(&direct));
+
+            let count = client
+                .update(
+                    "SELECT toolkit_experimental.approx_count(3, toolkit_experimental.rollup(sketch)) \
+                    FROM cms_rollup",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<i64>()
+                .unwrap();
+            assert!((100..=110).contains(&count.unwrap()));
+        });
+    }
+
+    #[pg_test]
+    fn test_countminsketch_topn() {
+        Spi::connect(|mut client| {
+            // value v appears v times
+            client
+                .update(
+                    "CREATE TABLE cms_topn AS \
+                    SELECT v::text AS data FROM generate_series(1, 50) v, generate_series(1, v) i \
+                    ORDER BY random()",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let top: Vec<String> = client
+                .update(
+                    "SELECT toolkit_experimental.topn(\
+                        toolkit_experimental.count_min_sketch(data, 0.001, 0.01, 10), 5, NULL::text) \
+                    FROM cms_topn",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<String>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, ["50", "49", "48", "47", "46"]);
+
+            let top: Vec<String> = client
+                .update(
+                    "SELECT toolkit_experimental.topn(toolkit_experimental.rollup(sketch), 3, NULL::text) \
+                    FROM (\
+                        SELECT toolkit_experimental.count_min_sketch(data, 0.001, 0.01, 10) sketch \
+                        FROM cms_topn \
+                        GROUP BY length(data)\
+                    ) q",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<String>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, ["50", "49", "48"]);
+        });
+    }
+
+    #[pg_test(error = "count-min sketch was not built with heavy-hitter tracking")]
+    fn test_countminsketch_topn_without_heavy_hitters() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.topn(\
+                        toolkit_experimental.count_min_sketch(v, 0.01, 0.01), 5, NULL::int) \
+                    FROM generate_series(1, 100) v",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test(error = "count-min sketches must have the same type")]
+    fn test_countminsketch_rollup_mismatched_types() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.rollup(sketch) FROM (\
+                        SELECT toolkit_experimental.count_min_sketch(v, 0.01, 0.01) sketch \
+                        FROM generate_series(1, 10) v \
+                        UNION ALL \
+                        SELECT toolkit_experimental.count_min_sketch(v::text, 0.01, 0.01) \
+                        FROM generate_series(1, 10) v\
+                    ) q",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_countminsketch_conservative() {
+        Spi::connect(|mut client| {
+            // error 0.1 gives a sketch only 28 counters wide, so there are lots of collisions
+            let (standard, conservative) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.approx_count(1, toolkit_experimental.count_min_sketch(v, 0.1, 0.01)), \
+                        toolkit_experimental.approx_count(1, toolkit_experimental.count_min_sketch(v, 0.1, 0.01, 0, true)) \
+                    FROM (SELECT generate_series(1, 1000) UNION ALL SELECT 1) u(v)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            let (standard, conservative) = (standard.unwrap(), conservative.unwrap());
+            assert!(2 <= conservative);
+            assert!(conservative <= standard);
+
+            let text = client
+                .update(
+                    "SELECT toolkit_experimental.count_min_sketch(v, 0.1, 0.01, 0, true)::text \
+                    FROM generate_series(1, 10) v",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<String>()
+                .unwrap()
+                .unwrap();
+            assert!(text.ends_with("conservative:true)"), "{text}");
+        });
+    }
+
+    #[pg_test]
+    fn test_countminsketch_inner_product() {
+        Spi::connect(|mut client| {
+            // a has i copies of each i, b has two of each, so the join has sum(2i) rows
+            let (product, join_size) = client
+                .update(
+                    "WITH a AS (SELECT i FROM generate_series(1, 100) i, generate_series(1, i) j), \
+                        b AS (SELECT i FROM generate_series(1, 100) i, generate_series(1, 2) j) \
+                    SELECT \
+                        toolkit_experimental.approx_inner_product(\
+                            (SELECT toolkit_experimental.count_min_sketch(i, 0.001, 0.01) FROM a), \
+                            (SELECT toolkit_experimental.count_min_sketch(i, 0.001, 0.01) FROM b)), \
+                        (SELECT count(*) FROM a JOIN b USING (i))",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            let (product, join_size) = (product.unwrap(), join_size.unwrap());
+            assert_eq!(join_size, 10100);
+            // the error bound is error * |a| * |b|
+            assert!(join_size <= product);
+            assert!(product as f64 <= join_size as f64 + 0.001 * 5050.0 * 200.0);
+        });
+    }
+
+    #[pg_test(error = "count-min sketches must have the same width, depth and hash keys")]
+    fn test_countminsketch_inner_product_mismatched_dimensions() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.approx_inner_product(\
+                        (SELECT toolkit_experimental.count_min_sketch(v, 0.01, 0.01) FROM generate_series(1, 10) v), \
+                        (SELECT toolkit_experimental.count_min_sketch(v, 0.001, 0.01) FROM generate_series(1, 10) v))",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_countminsketch_subtract() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TABLE cms_hourly AS \
+                    SELECT hour, toolkit_experimental.count_min_sketch(v, 0.01, 0.01) AS sketch \
+                    FROM generate_series(1, 3) hour, generate_series(1, hour * 10) v \
+                    GROUP BY hour",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let (windowed, direct) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.cms_subtract(\
+                            (SELECT toolkit_experimental.rollup(sketch) FROM cms_hourly), \
+                            (SELECT sketch FROM cms_hourly WHERE hour = 1))::text, \
+                        (SELECT toolkit_experimental.rollup(sketch) FROM cms_hourly WHERE hour > 1)::text",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<String, String>()
+                .unwrap();
+            assert_eq!(windowed, direct);
+
+            let (total, count) = client
+                .update(
+                    "SELECT toolkit_experimental.total(s), toolkit_experimental.approx_count(5, s) \
+                    FROM (SELECT toolkit_experimental.cms_subtract(\
+                        (SELECT toolkit_experimental.rollup(sketch) FROM cms_hourly), \
+                        (SELECT sketch FROM cms_hourly WHERE hour = 3))) q(s)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            assert_eq!(total, Some(30));
+            assert_eq!(count, Some(2));
+
+            // 1 is tracked by the rollup but was only counted in the first sketch
+            let top: Vec<i32> = client
+                .update(
+                    "SELECT toolkit_experimental.topn(toolkit_experimental.cms_subtract(\
+                        (SELECT toolkit_experimental.count_min_sketch(v, 0.01, 0.01, 2) \
+                            FROM unnest(array[1, 1, 1, 1, 2, 2, 3]) v), \
+                        (SELECT toolkit_experimental.count_min_sketch(v, 0.01, 0.01, 2) \
+                            FROM unnest(array[1, 1, 1, 1]) v)), 2, NULL::int)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<i32>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, [2]);
+        });
+    }
+
+    #[pg_test(error = "cannot subtract count-min sketches built with conservative update")]
+    fn test_countminsketch_subtract_conservative() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.cms_subtract(s, s) \
+                    FROM (SELECT toolkit_experimental.count_min_sketch(v, 0.01, 0.01, 0, true) \
+                        FROM generate_series(1, 10) v) q(s)",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_countminsketch_scale() {
+        Spi::connect(|mut client| {
+            let (count, total) = client
+                .update(
+                    "SELECT toolkit_experimental.approx_count(1, s), toolkit_experimental.total(s) \
+                    FROM (SELECT toolkit_experimental.cms_scale(\
+                        toolkit_experimental.count_min_sketch(v % 2, 0.01, 0.01), 0.25) \
+                        FROM generate_series(1, 100) v) q(s)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            assert_eq!(count, Some(13));
+            assert!(matches!(total, Some(25..=26)));
+        });
+    }
+
+    #[pg_test]
+    fn test_countminsketch_metadata() {
+        Spi::connect(|mut client| {
+            let mut rows = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.width(s)::float8, \
+                        toolkit_experimental.depth(s)::float8, \
+                        toolkit_experimental.total(s)::float8, \
+                        toolkit_experimental.error_bound(s), \
+                        toolkit_experimental.error_probability(s) \
+                    FROM (SELECT toolkit_experimental.count_min_sketch(v, 0.01, 0.05) \
+                        FROM generate_series(1, 1000) v) q(s)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            let row = rows.next().unwrap();
+            let values: Vec<f64> = (1..=5).map(|i| row.get(i).unwrap().unwrap()).collect();
+            assert_eq!(&values[..3], &[272.0, 3.0, 1000.0]);
+            assert!(9.0 < values[3] && values[3] <= 10.0);
+            assert!(0.04 < values[4] && values[4] <= 0.05);
+        });
+    }
 }
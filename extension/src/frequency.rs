@@ -219,20 +219,22 @@ impl SpaceSavingTransState {
             pgrx::error!("mcv aggregate requires a skew factor > 1.0")
         }
 
-        let prob_eq_n = zeta_eq_n(skew, nval as u64);
-        let prob_lt_n = zeta_le_n(skew, nval as u64 - 1);
-
         SpaceSavingTransState {
             entries: vec![],
             indices: PgAnyElementHashMap::new(typ, collation),
             total_vals: 0,
             freq_param: skew,
-            max_size: nval - 1
-                + SpaceSavingTransState::max_size_for_freq(prob_eq_n / (1.0 - prob_lt_n)),
+            max_size: SpaceSavingTransState::max_size_for_mcv(skew, nval),
             topn: nval,
         }
     }
 
+    fn max_size_for_mcv(skew: f64, nval: u32) -> u32 {
+        let prob_eq_n = zeta_eq_n(skew, nval as u64);
+        let prob_lt_n = zeta_le_n(skew, nval as u64 - 1);
+        nval - 1 + SpaceSavingTransState::max_size_for_freq(prob_eq_n / (1.0 - prob_lt_n))
+    }
+
     fn ingest_aggregate_data(
         &mut self,
         val_count: u64,
@@ -280,17 +282,18 @@ impl SpaceSavingTransState {
         self.indices.typoid()
     }
 
-    fn add(&mut self, element: PgAnyElement) {
-        self.total_vals += 1;
+    // Counts `element` as having been seen `weight` times
+    fn add(&mut self, element: PgAnyElement, weight: u64) {
+        self.total_vals += weight;
         if let Some(idx) = self.indices.get(&element) {
             let idx = *idx;
-            self.entries[idx].count += 1;
+            self.entries[idx].count += weight;
             self.move_left(idx);
         } else if self.entries.len() < self.max_size as usize {
             let new_idx = self.entries.len();
             self.entries.push(SpaceSavingEntry {
                 value: element.deep_copy_datum(),
-                count: 1,
+                count: weight,
                 overcount: 0,
             });
 
@@ -299,6 +302,7 @@ impl SpaceSavingTransState {
                 (self.entries[new_idx].value, self.type_oid()).into(),
                 new_idx,
             );
+            self.move_left(new_idx);
         } else {
             let new_value = element.deep_copy_datum();
 
@@ -308,25 +312,29 @@ impl SpaceSavingTransState {
             self.indices.remove(&(entry.value, typoid).into());
             entry.value = new_value; // JOSH FIXME should we pfree() old value if by-ref?
             entry.overcount = entry.count;
-            entry.count += 1;
+            entry.count += weight;
             self.indices
                 .insert((new_value, typoid).into(), self.entries.len() - 1);
             self.move_left(self.entries.len() - 1);
         }
     }
 
-    // swap element i with an earlier element in the 'entries' vector to maintain decreasing order
+    // move element i to the front of the run of smaller elements before it to maintain decreasing order
     fn move_left(&mut self, i: usize) {
         let count = self.entries[i].count;
         let mut target = i;
         while target > 0 && self.entries[target - 1].count < count {
             target -= 1;
         }
-        if target != i {
-            self.entries.swap(i, target);
-
-            self.update_map_index(i);
-            self.update_map_index(target);
+        if target == i {
+            return;
+        }
+        // With weighted updates the element may move past several others, so
+        // shift them all right by one rather than swapping, which would break
+        // the order of the ones in between.
+        self.entries[target..=i].rotate_right(1);
+        for j in target..=i {
+            self.update_map_index(j);
         }
     }
 
@@ -786,17 +794,150 @@ pub fn freq_agg_text_trans(
     freq_agg_trans(state, freq, value, fcinfo)
 }
 
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn mcv_agg_weighted_trans(
+    state: Internal,
+    n: i32,
+    value: Option<AnyElement>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    space_saving_weighted_trans(
+        unsafe { state.to_inner() },
+        value,
+        weight,
+        fcinfo,
+        |typ, collation| {
+            SpaceSavingTransState::mcv_agg_from_type_id(DEFAULT_ZETA_SKEW, n as u32, typ, collation)
+        },
+    )
+    .internal()
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn mcv_agg_weighted_bigint_trans(
+    state: Internal,
+    n: i32,
+    value: Option<i64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    let value = match value {
+        None => None,
+        Some(val) => unsafe {
+            AnyElement::from_polymorphic_datum(pg_sys::Datum::from(val), false, pg_sys::INT8OID)
+        },
+    };
+    mcv_agg_weighted_trans(state, n, value, weight, fcinfo)
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn mcv_agg_weighted_text_trans(
+    state: Internal,
+    n: i32,
+    value: Option<crate::raw::text>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    let txt = value.map(|v| unsafe { pg_sys::pg_detoast_datum_copy(v.0.cast_mut_ptr()) });
+    let value = match txt {
+        None => None,
+        Some(val) => unsafe {
+            AnyElement::from_polymorphic_datum(pg_sys::Datum::from(val), false, pg_sys::TEXTOID)
+        },
+    };
+    mcv_agg_weighted_trans(state, n, value, weight, fcinfo)
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn freq_agg_weighted_trans(
+    state: Internal,
+    freq: f64,
+    value: Option<AnyElement>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    if freq <= 0. || freq >= 1.0 {
+        pgrx::error!("frequency aggregate requires a frequency in the range (0.0, 1.0)")
+    }
+
+    space_saving_weighted_trans(
+        unsafe { state.to_inner() },
+        value,
+        weight,
+        fcinfo,
+        |typ, collation| SpaceSavingTransState::freq_agg_from_type_id(freq, typ, collation),
+    )
+    .internal()
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn freq_agg_weighted_bigint_trans(
+    state: Internal,
+    freq: f64,
+    value: Option<i64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    let value = match value {
+        None => None,
+        Some(val) => unsafe {
+            AnyElement::from_polymorphic_datum(pg_sys::Datum::from(val), false, pg_sys::INT8OID)
+        },
+    };
+    freq_agg_weighted_trans(state, freq, value, weight, fcinfo)
+}
+
+#[pg_extern(schema = "toolkit_experimental", immutable, parallel_safe)]
+pub fn freq_agg_weighted_text_trans(
+    state: Internal,
+    freq: f64,
+    value: Option<crate::raw::text>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    let txt = value.map(|v| unsafe { pg_sys::pg_detoast_datum_copy(v.0.cast_mut_ptr()) });
+    let value = match txt {
+        None => None,
+        Some(val) => unsafe {
+            AnyElement::from_polymorphic_datum(pg_sys::Datum::from(val), false, pg_sys::TEXTOID)
+        },
+    };
+    freq_agg_weighted_trans(state, freq, value, weight, fcinfo)
+}
+
 pub fn space_saving_trans<F>(
     state: Option<Inner<SpaceSavingTransState>>,
     value: Option<AnyElement>,
     fcinfo: pg_sys::FunctionCallInfo,
     make_trans_state: F,
 ) -> Option<Inner<SpaceSavingTransState>>
+where
+    F: FnOnce(pg_sys::Oid, Option<pg_sys::Oid>) -> SpaceSavingTransState,
+{
+    space_saving_weighted_trans(state, value, Some(1), fcinfo, make_trans_state)
+}
+
+// Rows with a NULL or 0 weight are skipped, like rows with a NULL value
+pub fn space_saving_weighted_trans<F>(
+    state: Option<Inner<SpaceSavingTransState>>,
+    value: Option<AnyElement>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+    make_trans_state: F,
+) -> Option<Inner<SpaceSavingTransState>>
 where
     F: FnOnce(pg_sys::Oid, Option<pg_sys::Oid>) -> SpaceSavingTransState,
 {
     unsafe {
         in_aggregate_context(fcinfo, || {
+            let weight = match weight {
+                Some(weight) if weight < 0 => {
+                    pgrx::error!("frequency aggregate weights must not be negative")
+                }
+                None | Some(0) => return state,
+                Some(weight) => weight as u64,
+            };
             let value = match value {
                 None => return state,
                 Some(value) => value,
@@ -804,13 +945,25 @@ where
             let mut state = match state {
                 None => {
                     let typ = value.oid();
+                    // The row type of an anonymous record is only registered
+                    // for the current backend, so we couldn't read it back
+                    // once it has been stored. Composite types are fine, they
+                    // are hashed and compared like any other type.
+                    if typ == pg_sys::RECORDOID {
+                        pgrx::error!("frequency aggregates do not support anonymous records, cast the value to a composite type")
+                    }
+                    // records only have an extended hash function from PostgreSQL 14
+                    #[cfg(any(feature = "pg12", feature = "pg13"))]
+                    if pg_sys::type_is_rowtype(typ) {
+                        pgrx::error!("frequency aggregates only support composite types on PostgreSQL 14 and later")
+                    }
                     let collation = get_collation_or_default(fcinfo);
                     make_trans_state(typ, collation).into()
                 }
                 Some(state) => state,
             };
 
-            state.add(value.into());
+            state.add(value.into(), weight);
             Some(state)
         })
     }
@@ -1184,6 +1337,150 @@ extension_sql!(
     ],
 );
 
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.raw_freq_agg(\n\
+        frequency double precision, value AnyElement, weight INT8\n\
+    ) (\n\
+        sfunc = toolkit_experimental.freq_agg_weighted_trans,\n\
+        stype = internal,\n\
+        finalfunc = space_saving_final,\n\
+        combinefunc = space_saving_combine,\n\
+        serialfunc = space_saving_serialize,\n\
+        deserialfunc = space_saving_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "freq_agg_weighted",
+    requires = [
+        freq_agg_weighted_trans,
+        space_saving_final,
+        space_saving_combine,
+        space_saving_serialize,
+        space_saving_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.freq_agg(\n\
+        frequency double precision, value INT8, weight INT8\n\
+    ) (\n\
+        sfunc = toolkit_experimental.freq_agg_weighted_bigint_trans,\n\
+        stype = internal,\n\
+        finalfunc = space_saving_bigint_final,\n\
+        combinefunc = space_saving_combine,\n\
+        serialfunc = space_saving_serialize,\n\
+        deserialfunc = space_saving_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "freq_bigint_agg_weighted",
+    requires = [
+        freq_agg_weighted_bigint_trans,
+        space_saving_bigint_final,
+        space_saving_combine,
+        space_saving_serialize,
+        space_saving_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.freq_agg(\n\
+        frequency double precision, value TEXT, weight INT8\n\
+    ) (\n\
+        sfunc = toolkit_experimental.freq_agg_weighted_text_trans,\n\
+        stype = internal,\n\
+        finalfunc = space_saving_text_final,\n\
+        combinefunc = space_saving_combine,\n\
+        serialfunc = space_saving_serialize,\n\
+        deserialfunc = space_saving_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "freq_text_agg_weighted",
+    requires = [
+        freq_agg_weighted_text_trans,
+        space_saving_text_final,
+        space_saving_combine,
+        space_saving_serialize,
+        space_saving_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.raw_mcv_agg(\n\
+        count integer, value AnyElement, weight INT8\n\
+    ) (\n\
+        sfunc = toolkit_experimental.mcv_agg_weighted_trans,\n\
+        stype = internal,\n\
+        finalfunc = space_saving_final,\n\
+        combinefunc = space_saving_combine,\n\
+        serialfunc = space_saving_serialize,\n\
+        deserialfunc = space_saving_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "mcv_agg_weighted",
+    requires = [
+        mcv_agg_weighted_trans,
+        space_saving_final,
+        space_saving_combine,
+        space_saving_serialize,
+        space_saving_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.mcv_agg(\n\
+        count integer, value INT8, weight INT8\n\
+    ) (\n\
+        sfunc = toolkit_experimental.mcv_agg_weighted_bigint_trans,\n\
+        stype = internal,\n\
+        finalfunc = space_saving_bigint_final,\n\
+        combinefunc = space_saving_combine,\n\
+        serialfunc = space_saving_serialize,\n\
+        deserialfunc = space_saving_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "mcv_bigint_agg_weighted",
+    requires = [
+        mcv_agg_weighted_bigint_trans,
+        space_saving_bigint_final,
+        space_saving_combine,
+        space_saving_serialize,
+        space_saving_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.mcv_agg(\n\
+        count integer, value TEXT, weight INT8\n\
+    ) (\n\
+        sfunc = toolkit_experimental.mcv_agg_weighted_text_trans,\n\
+        stype = internal,\n\
+        finalfunc = space_saving_text_final,\n\
+        combinefunc = space_saving_combine,\n\
+        serialfunc = space_saving_serialize,\n\
+        deserialfunc = space_saving_deserialize,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "mcv_text_agg_weighted",
+    requires = [
+        mcv_agg_weighted_text_trans,
+        space_saving_text_final,
+        space_saving_combine,
+        space_saving_serialize,
+        space_saving_deserialize
+    ],
+);
+
 extension_sql!(
     "\n\
     CREATE AGGREGATE rollup(\n\
@@ -1259,7 +1556,7 @@ extension_sql!(
 #[pg_extern(immutable, parallel_safe, name = "into_values")]
 pub fn freq_iter<'a>(
     agg: SpaceSavingAggregate<'a>,
-    ty: AnyElement,
+    ty: Option<AnyElement>,
 ) -> TableIterator<
     'a,
     (
@@ -1269,7 +1566,8 @@ pub fn freq_iter<'a>(
     ),
 > {
     unsafe {
-        if ty.oid().as_u32() != agg.type_oid {
+        // If called with a NULL, assume type matches
+        if ty.is_some() && ty.unwrap().oid().as_u32() != agg.type_oid {
             pgrx::error!("mischatched types")
         }
         let counts = agg.counts.slice().iter().zip(agg.overcounts.slice().iter());
@@ -1635,6 +1933,213 @@ pub fn min_text_frequency(agg: SpaceSavingTextAggregate<'_>, value: text) -> f64
     }
 }
 
+// The largest count a value the aggregate isn't tracking could have. Until the
+// aggregate fills up every value seen is tracked, after that an untracked value
+// may have been evicted from the last entry.
+fn untracked_max_count(counts: &[u64], freq_param: f64, topn: u32) -> u64 {
+    let max_size = if topn == 0 {
+        SpaceSavingTransState::max_size_for_freq(freq_param)
+    } else {
+        SpaceSavingTransState::max_size_for_mcv(freq_param, topn)
+    };
+    if counts.len() < max_size as usize {
+        0
+    } else {
+        counts.last().copied().unwrap_or(0)
+    }
+}
+
+// The amount by which the count for the entry at `idx` may be overestimated,
+// or the most an untracked value could have been seen.
+fn count_error(
+    idx: Option<usize>,
+    counts: &[u64],
+    overcounts: &[u64],
+    freq_param: f64,
+    topn: u32,
+) -> u64 {
+    match idx {
+        Some(idx) => overcounts[idx],
+        None => untracked_max_count(counts, freq_param, topn),
+    }
+}
+
+// Indices of the entries among the first `n` whose guaranteed count is at least
+// the largest count any value outside of them could have, so that they must be
+// in the true top `n`.
+fn guaranteed_topn_indices(
+    n: i32,
+    counts: &[u64],
+    overcounts: &[u64],
+    freq_param: f64,
+    topn: u32,
+) -> Vec<usize> {
+    let n = n.max(0) as usize;
+    let threshold = match counts.get(n) {
+        Some(&count) => count,
+        None => untracked_max_count(counts, freq_param, topn),
+    };
+    (0..n.min(counts.len()))
+        .filter(|&i| counts[i] - overcounts[i] >= threshold)
+        .collect()
+}
+
+/// The most the frequency reported for `value` can differ from its true
+/// frequency, i.e. the difference between `max_frequency` and `min_frequency`
+/// for tracked values, or the highest frequency an untracked value could have.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn frequency_error(agg: SpaceSavingAggregate<'_>, value: AnyElement) -> f64 {
+    let value: PgAnyElement = value.into();
+    let idx = agg.datums.iter().position(|datum| {
+        value == (datum, unsafe { Oid::from_u32_unchecked(agg.type_oid) }).into()
+    });
+    let counts = agg.counts.slice();
+    let error = count_error(
+        idx,
+        counts,
+        agg.overcounts.slice(),
+        agg.freq_param,
+        agg.topn as u32,
+    );
+    error as f64 / agg.values_seen as f64
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "frequency_error"
+)]
+pub fn bigint_frequency_error(agg: SpaceSavingBigIntAggregate<'_>, value: i64) -> f64 {
+    let idx = agg.datums.iter().position(|datum| value == datum);
+    let counts = agg.counts.slice();
+    let error = count_error(
+        idx,
+        counts,
+        agg.overcounts.slice(),
+        agg.freq_param,
+        agg.topn,
+    );
+    error as f64 / agg.values_seen as f64
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "frequency_error"
+)]
+pub fn text_frequency_error(agg: SpaceSavingTextAggregate<'_>, value: text) -> f64 {
+    let value: PgAnyElement = (value.0, pg_sys::TEXTOID).into();
+    let idx = agg
+        .datums
+        .iter()
+        .position(|datum| value == (datum, pg_sys::TEXTOID).into());
+    let counts = agg.counts.slice();
+    let error = count_error(
+        idx,
+        counts,
+        agg.overcounts.slice(),
+        agg.freq_param,
+        agg.topn,
+    );
+    error as f64 / agg.values_seen as f64
+}
+
+/// Like `topn`, but only returns the values whose minimum count proves that
+/// they are among the `n` most frequent values.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn guaranteed_topn(
+    agg: SpaceSavingAggregate<'_>,
+    n: i32,
+    ty: Option<AnyElement>,
+) -> SetOfIterator<AnyElement> {
+    // If called with a NULL, assume type matches
+    if ty.is_some() && ty.unwrap().oid().as_u32() != agg.type_oid {
+        pgrx::error!("mischatched types")
+    }
+
+    let indices = guaranteed_topn_indices(
+        n,
+        agg.counts.slice(),
+        agg.overcounts.slice(),
+        agg.freq_param,
+        agg.topn as u32,
+    );
+    let datums: Vec<Datum> = agg.datums.iter().collect();
+    let type_oid = unsafe { Oid::from_u32_unchecked(agg.type_oid) };
+    SetOfIterator::new(indices.into_iter().map_while(move |i| unsafe {
+        AnyElement::from_polymorphic_datum(datums[i], false, type_oid)
+    }))
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "guaranteed_topn"
+)]
+pub fn guaranteed_topn_bigint(agg: SpaceSavingBigIntAggregate<'_>, n: i32) -> SetOfIterator<i64> {
+    let indices = guaranteed_topn_indices(
+        n,
+        agg.counts.slice(),
+        agg.overcounts.slice(),
+        agg.freq_param,
+        agg.topn,
+    );
+    let datums = agg.datums.slice();
+    let values: Vec<i64> = indices.into_iter().map(|i| datums[i]).collect();
+    SetOfIterator::new(values)
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "guaranteed_topn"
+)]
+pub fn guaranteed_topn_text(agg: SpaceSavingTextAggregate<'_>, n: i32) -> SetOfIterator<String> {
+    let indices = guaranteed_topn_indices(
+        n,
+        agg.counts.slice(),
+        agg.overcounts.slice(),
+        agg.freq_param,
+        agg.topn,
+    );
+    let datums: Vec<Datum> = agg.datums.iter().collect();
+    SetOfIterator::new(
+        indices
+            .into_iter()
+            .map(move |i| unsafe { varlena_to_string(datums[i].cast_mut_ptr()) }),
+    )
+}
+
+/// The total number of values the aggregate has seen.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn overall_count(agg: SpaceSavingAggregate<'_>) -> i64 {
+    agg.values_seen as i64
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "overall_count"
+)]
+pub fn overall_count_bigint(agg: SpaceSavingBigIntAggregate<'_>) -> i64 {
+    agg.values_seen as i64
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "overall_count"
+)]
+pub fn overall_count_text(agg: SpaceSavingTextAggregate<'_>) -> i64 {
+    agg.values_seen as i64
+}
+
 struct TopNIterator<Input, InputIterator: std::iter::Iterator<Item = Input>> {
     datums_iter: InputIterator,
     counts_iter: std::vec::IntoIter<u64>,
@@ -2284,6 +2789,339 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn test_frequency_error_bounds() {
+        Spi::connect(|mut client| {
+            setup_with_test_table(&mut client);
+
+            // freq_8 is full, so values evicted from it may have been seen up to 15 times
+            let (tracked, noisy, untracked) = client
+                .update(
+                    "SELECT frequency_error(agg, 19), frequency_error(agg, 10), frequency_error(agg, 3) \
+                    FROM aggs WHERE name = 'freq_8'",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_three::<f64, f64, f64>()
+                .unwrap();
+            assert_eq!(tracked.unwrap(), 0.);
+            assert_eq!(noisy.unwrap(), 14. / 210.);
+            assert_eq!(untracked.unwrap(), 15. / 210.);
+
+            // freq_2 tracks every value exactly
+            let (error, count) = client
+                .update(
+                    "SELECT frequency_error(agg, 3), overall_count(agg) FROM aggs WHERE name = 'freq_2'",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, i64>()
+                .unwrap();
+            assert_eq!(error.unwrap(), 0.);
+            assert_eq!(count.unwrap(), 210);
+
+            let top: Vec<i64> = client
+                .update(
+                    "SELECT guaranteed_topn(agg, 8) FROM aggs WHERE name = 'freq_8'",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<i64>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, vec![19, 18, 17, 16, 15]);
+            let top: Vec<i64> = client
+                .update(
+                    "SELECT guaranteed_topn(agg, 8) FROM aggs WHERE name = 'freq_2'",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<i64>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, vec![19, 18, 17, 16, 15, 14, 13, 12]);
+
+            // the generic and text aggregates
+            let top: Vec<f64> = client
+                .update(
+                    "SELECT guaranteed_topn(agg, 8, NULL::float8) \
+                    FROM (SELECT raw_freq_agg(0.08, s.data::float8) FROM (SELECT data FROM test ORDER BY time) s) q(agg)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<f64>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, vec![19., 18., 17., 16., 15.]);
+            let (error, count) = client
+                .update(
+                    "SELECT frequency_error(agg, 3::float8), overall_count(agg) \
+                    FROM (SELECT raw_freq_agg(0.08, s.data::float8) FROM (SELECT data FROM test ORDER BY time) s) q(agg)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, i64>()
+                .unwrap();
+            assert_eq!(error.unwrap(), 15. / 210.);
+            assert_eq!(count.unwrap(), 210);
+
+            let top: Vec<String> = client
+                .update(
+                    "SELECT guaranteed_topn(agg, 3) \
+                    FROM (SELECT freq_agg(0.08, s.data::text) FROM (SELECT data FROM test ORDER BY time) s) q(agg)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<String>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, vec!["19", "18", "17"]);
+            let (error, count) = client
+                .update(
+                    "SELECT frequency_error(agg, '10'::text), overall_count(agg) \
+                    FROM (SELECT freq_agg(0.08, s.data::text) FROM (SELECT data FROM test ORDER BY time) s) q(agg)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, i64>()
+                .unwrap();
+            assert_eq!(error.unwrap(), 14. / 210.);
+            assert_eq!(count.unwrap(), 210);
+        });
+    }
+
+    #[cfg(any(feature = "pg12", feature = "pg13"))]
+    #[pg_test(
+        error = "frequency aggregates only support composite types on PostgreSQL 14 and later"
+    )]
+    fn test_composite_keys_unsupported() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TYPE service_endpoint AS (service TEXT, endpoint TEXT)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "SELECT raw_mcv_agg(2, ('api', '/users')::service_endpoint)",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[cfg(not(any(feature = "pg12", feature = "pg13")))]
+    #[pg_test]
+    fn test_composite_keys() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TYPE service_endpoint AS (service TEXT, endpoint TEXT)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "CREATE TABLE requests AS \
+                    SELECT 'api'::text AS service, '/users'::text AS endpoint FROM generate_series(1, 5) \
+                    UNION ALL SELECT 'api', '/orders' FROM generate_series(1, 3) \
+                    UNION ALL SELECT 'web', '/users' FROM generate_series(1, 2)",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let top: Vec<String> = client
+                .update(
+                    "SELECT service || endpoint \
+                    FROM topn((SELECT raw_mcv_agg(2, (service, endpoint)::service_endpoint) FROM requests), NULL::service_endpoint)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| r.get::<String>(1).unwrap().unwrap())
+                .collect();
+            assert_eq!(top, vec!["api/users", "api/orders"]);
+
+            let values: Vec<(String, String, f64)> = client
+                .update(
+                    "SELECT (value).service, (value).endpoint, max_freq \
+                    FROM into_values(\
+                        (SELECT toolkit_experimental.raw_freq_agg(0.1, (service, endpoint)::service_endpoint) FROM requests), \
+                        NULL::service_endpoint)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| {
+                    (
+                        r.get::<String>(1).unwrap().unwrap(),
+                        r.get::<String>(2).unwrap().unwrap(),
+                        r.get::<f64>(3).unwrap().unwrap(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                values,
+                vec![
+                    ("api".to_string(), "/users".to_string(), 0.5),
+                    ("api".to_string(), "/orders".to_string(), 0.3),
+                    ("web".to_string(), "/users".to_string(), 0.2),
+                ]
+            );
+
+            // rolling up per-service aggregates, via their text representation
+            let (api_orders, web_orders) = client
+                .update(
+                    "SELECT max_frequency(agg, ('api', '/orders')::service_endpoint), \
+                        max_frequency(agg, ('web', '/orders')::service_endpoint) \
+                    FROM (SELECT rollup(agg::text::SpaceSavingAggregate) \
+                        FROM (SELECT raw_mcv_agg(2, (service, endpoint)::service_endpoint) \
+                            FROM requests GROUP BY service) s(agg)) q(agg)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, f64>()
+                .unwrap();
+            assert_eq!(api_orders.unwrap(), 0.3);
+            assert_eq!(web_orders.unwrap(), 0.);
+        });
+    }
+
+    #[pg_test(
+        error = "frequency aggregates do not support anonymous records, cast the value to a composite type"
+    )]
+    fn test_anonymous_record_keys() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT raw_mcv_agg(2, (v, v % 2)) FROM generate_series(1, 10) v",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_weighted_aggregates() {
+        Spi::connect(|mut client| {
+            client
+                .update("CREATE TABLE weighted (v BIGINT, w BIGINT)", None, None)
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO weighted VALUES (1, 100), (2, 50), (3, 10), (4, 7), (5, 0), (6, NULL)",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            // weights are equivalent to repeating the value, rows with no weight are ignored
+            let (weighted, repeated) = client
+                .update(
+                    "SELECT \
+                        (SELECT toolkit_experimental.freq_agg(0.05, v, w)::text FROM weighted), \
+                        (SELECT toolkit_experimental.freq_agg(0.05, v)::text FROM weighted, generate_series(1, w))",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<String, String>()
+                .unwrap();
+            assert_eq!(weighted, repeated);
+
+            let rollup = client
+                .update(
+                    "SELECT rollup(agg)::text \
+                    FROM (SELECT toolkit_experimental.freq_agg(0.05, v, w) FROM weighted GROUP BY v % 2) s(agg)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            assert_eq!(rollup.unwrap(), weighted.unwrap());
+
+            // a single heavy row pushes its value to the top
+            let (top, freq) = client
+                .update(
+                    "SELECT topn(agg, 1, NULL::float8), max_frequency(agg, 500::float8) \
+                    FROM (SELECT toolkit_experimental.raw_mcv_agg(3, v::float8, w) \
+                        FROM (SELECT v, 1 FROM generate_series(1, 100) v UNION ALL SELECT 500, 10000) data(v, w)) s(agg)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, f64>()
+                .unwrap();
+            assert_eq!(top.unwrap(), 500.);
+            assert_eq!(freq.unwrap(), 10001. / 10100.);
+        });
+    }
+
+    #[pg_test]
+    fn test_weighted_eviction_keeps_order() {
+        Spi::connect(|mut client| {
+            // 'd' replaces 'c' with a count of 2 + 4, which has to move past both 'b' and 'a'
+            let values: Vec<(String, f64, f64)> = client
+                .update(
+                    "SELECT value, min_freq, max_freq FROM into_values(\
+                        (SELECT toolkit_experimental.freq_agg(0.5, v, w ORDER BY i) \
+                        FROM (VALUES (1, 'a', 5), (2, 'b', 3), (3, 'c', 2), (4, 'd', 4)) data(i, v, w)))",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| {
+                    (
+                        r.get::<String>(1).unwrap().unwrap(),
+                        r.get::<f64>(2).unwrap().unwrap(),
+                        r.get::<f64>(3).unwrap().unwrap(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                values,
+                vec![
+                    ("d".to_string(), 4. / 14., 6. / 14.),
+                    ("a".to_string(), 5. / 14., 5. / 14.),
+                    ("b".to_string(), 3. / 14., 3. / 14.),
+                ]
+            );
+        });
+    }
+
+    #[pg_test(error = "frequency aggregate weights must not be negative")]
+    fn test_negative_weight() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.mcv_agg(2, v, -1) FROM generate_series(1, 10) v",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
     #[pg_test]
     fn test_rollups() {
         Spi::connect(|mut client| {
@@ -2,19 +2,24 @@ use pgrx::*;
 
 use serde::{Deserialize, Serialize};
 
-use counter_agg::{range::I64Range, GaugeSummaryBuilder, MetricSummary};
+use counter_agg::{
+    gauge::GaugeLevels, range::I64Range, window::MetricWindow, GaugeSummaryBuilder, MetricSummary,
+};
 use flat_serialize_macro::FlatSerializable;
 use stats_agg::stats2d::StatsSummary2D;
 use tspoint::TSPoint;
 
 use crate::{
     accessors::{
-        AccessorCorr, AccessorCounterZeroTime, AccessorDelta, AccessorExtrapolatedDelta,
-        AccessorExtrapolatedRate, AccessorIdeltaLeft, AccessorIdeltaRight, AccessorIntercept,
-        AccessorIrateLeft, AccessorIrateRight, AccessorNumChanges, AccessorNumElements,
-        AccessorRate, AccessorSlope, AccessorTimeDelta, AccessorWithBounds,
+        AccessorAverage, AccessorCorr, AccessorCounterZeroTime, AccessorDelta,
+        AccessorExtrapolatedDelta, AccessorExtrapolatedRate, AccessorIdeltaLeft,
+        AccessorIdeltaRight, AccessorIntegral, AccessorIntercept, AccessorIrateLeft,
+        AccessorIrateRight, AccessorMaxVal, AccessorMinVal, AccessorNumChanges,
+        AccessorNumElements, AccessorRate, AccessorSlope, AccessorTimeDelta, AccessorWithBounds,
     },
     aggregate_utils::in_aggregate_context,
+    counter_agg::{metric_window_inv_trans_inner, metric_window_trans_inner},
+    duration::DurationUnit,
     flatten,
     palloc::{Inner, Internal, InternalAsValue, ToInternal},
     pg_type,
@@ -38,6 +43,10 @@ pub struct FlatSummary {
     bounds: I64RangeWrapper,
 }
 
+// Version 1 summaries only had the FlatSummary, version 2 added the levels in
+// front of it.
+const GAUGE_SUMMARY_VERSION: u8 = 2;
+
 #[pg_schema]
 mod toolkit_experimental {
     use super::*;
@@ -45,6 +54,10 @@ mod toolkit_experimental {
     pg_type! {
         #[derive(Debug, PartialEq)]
         struct GaugeSummary {
+            // GaugeLevels
+            min: TSPoint,
+            max: TSPoint,
+            integral: f64,
             #[flat_serialize::flatten]
             summary: FlatSummary,
         }
@@ -59,6 +72,7 @@ mod toolkit_experimental {
             next: Option<GaugeSummary>,
         ) -> GaugeSummary<'static> {
             let this = MetricSummary::from(self.clone());
+            let this_levels = self.levels();
             let prev = prev.map(MetricSummary::from);
             let next = next.map(MetricSummary::from);
 
@@ -82,6 +96,17 @@ mod toolkit_experimental {
                     .expect("unable to interpolate upper bound")
             });
 
+            let mut levels = match prev {
+                Some(pt) => {
+                    let mut levels = GaugeLevels::new(&pt);
+                    levels
+                        .combine(&pt, &this.first, &this_levels)
+                        .expect("unable to add data to interpolation");
+                    levels
+                }
+                None => this_levels,
+            };
+
             let builder = prev.map(|pt| GaugeSummaryBuilder::new(&pt, None));
             let mut builder = builder.map_or_else(
                 || {
@@ -98,13 +123,40 @@ mod toolkit_experimental {
             );
 
             if let Some(next) = next {
+                levels
+                    .add_point(&this.last, &next)
+                    .expect("unable to add final interpolated point");
                 builder
                     .add_point(&next)
                     .expect("unable to add final interpolated point");
             }
 
-            builder.build().into()
+            GaugeSummary::new(builder.build(), levels)
         }
+
+        pub(super) fn new(summary: MetricSummary, levels: GaugeLevels) -> GaugeSummary<'static> {
+            unsafe {
+                flatten!(GaugeSummary {
+                    version: GAUGE_SUMMARY_VERSION,
+                    min: levels.min,
+                    max: levels.max,
+                    integral: levels.integral,
+                    summary: summary.into(),
+                })
+            }
+        }
+
+        pub(super) fn levels(&self) -> GaugeLevels {
+            GaugeLevels {
+                min: self.min,
+                max: self.max,
+                integral: self.integral,
+            }
+        }
+    }
+
+    impl GaugeSummaryData {
+        pub const LAYOUT_VERSION: Option<u8> = Some(GAUGE_SUMMARY_VERSION);
     }
 
     ron_inout_funcs!(GaugeSummary);
@@ -123,7 +175,7 @@ struct GaugeSummaryTransState {
     // We have a summary buffer here in order to deal with the fact that when the cmobine function gets called it
     // must first build up a buffer of InternalMetricSummaries, then sort them, then call the combine function in
     // the correct order.
-    summary_buffer: Vec<MetricSummary>,
+    summary_buffer: Vec<(MetricSummary, GaugeLevels)>,
 }
 
 impl GaugeSummaryTransState {
@@ -151,13 +203,15 @@ impl GaugeSummaryTransState {
                 .add_point(p)
                 .unwrap_or_else(|e| pgrx::error!("{}", e));
         }
+        let levels = GaugeLevels::from_points(self.point_buffer.iter())
+            .unwrap_or_else(|e| pgrx::error!("{}", e));
         self.point_buffer.clear();
         // TODO build method should check validity
         // check bounds only after we've combined all the points, so we aren't doing it all the time.
         if !summary.bounds_valid() {
             panic!("Metric bounds invalid")
         }
-        self.summary_buffer.push(summary.build());
+        self.summary_buffer.push((summary.build(), levels));
     }
 
     fn push_summary(&mut self, other: &Self) {
@@ -173,16 +227,22 @@ impl GaugeSummaryTransState {
         if self.summary_buffer.len() <= 1 {
             return;
         }
-        self.summary_buffer.sort_unstable_by_key(|s| s.first.ts);
+        self.summary_buffer
+            .sort_unstable_by_key(|(s, _)| s.first.ts);
         let mut sum_iter = self.summary_buffer.drain(..);
-        let first = sum_iter.next().expect("already handled empty case");
+        let (first, mut new_levels) = sum_iter.next().expect("already handled empty case");
+        let mut last = first.last;
         let mut new_summary = GaugeSummaryBuilder::from(first);
-        for sum in sum_iter {
+        for (sum, levels) in sum_iter {
+            new_levels
+                .combine(&last, &sum.first, &levels)
+                .unwrap_or_else(|e| pgrx::error!("{}", e));
             new_summary
                 .combine(&sum)
                 .unwrap_or_else(|e| pgrx::error!("{}", e));
+            last = sum.last;
         }
-        self.summary_buffer.push(new_summary.build());
+        self.summary_buffer.push((new_summary.build(), new_levels));
     }
 }
 
@@ -272,11 +332,13 @@ fn gauge_agg_summary_trans_inner(
             (state, None) => state,
             (None, Some(value)) => {
                 let mut state = GaugeSummaryTransState::new();
-                state.summary_buffer.push(value.into());
+                let levels = value.levels();
+                state.summary_buffer.push((value.into(), levels));
                 Some(state.into())
             }
             (Some(mut state), Some(value)) => {
-                state.summary_buffer.push(value.into());
+                let levels = value.levels();
+                state.summary_buffer.push((value.into(), levels));
                 Some(state)
             }
         })
@@ -344,18 +406,60 @@ fn gauge_agg_final_inner(
             debug_assert!(state.summary_buffer.len() <= 1);
             match state.summary_buffer.pop() {
                 None => None,
-                Some(st) => {
+                Some((st, levels)) => {
                     // there are some edge cases that this should prevent, but I'm not sure it's necessary, we do check the bounds in the functions that use them.
                     if !st.bounds_valid() {
                         panic!("Metric bounds invalid")
                     }
-                    Some(GaugeSummary::from(st))
+                    Some(GaugeSummary::new(st, levels))
                 }
             }
         })
     }
 }
 
+// moving-aggregate transition function for gauge_agg, see `counter_agg_moving_trans`
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn gauge_agg_moving_trans(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    metric_window_trans_inner(
+        unsafe { state.to_inner() },
+        ts,
+        val,
+        MetricWindow::new_gauge,
+        fcinfo,
+    )
+    .internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn gauge_agg_inv_trans(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    metric_window_inv_trans_inner(unsafe { state.to_inner() }, ts, val, fcinfo).internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn gauge_agg_moving_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<GaugeSummary<'static>> {
+    let state: Option<Inner<MetricWindow>> = unsafe { state.to_inner() };
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let state = state?;
+            Some(GaugeSummary::new(state.summary()?, state.levels()?))
+        })
+    }
+}
+
 extension_sql!(
     "\n\
     CREATE AGGREGATE toolkit_experimental.gauge_agg( ts timestamptz, value DOUBLE PRECISION, bounds tstzrange )\n\
@@ -389,6 +493,10 @@ extension_sql!(
         combinefunc = toolkit_experimental.gauge_agg_combine,\n\
         serialfunc = toolkit_experimental.gauge_summary_trans_serialize,\n\
         deserialfunc = toolkit_experimental.gauge_summary_trans_deserialize,\n\
+        msfunc = toolkit_experimental.gauge_agg_moving_trans,\n\
+        minvfunc = toolkit_experimental.gauge_agg_inv_trans,\n\
+        mstype = internal,\n\
+        mfinalfunc = toolkit_experimental.gauge_agg_moving_final,\n\
         parallel = restricted\n\
     );\n\
 ",
@@ -398,7 +506,10 @@ extension_sql!(
         gauge_agg_final,
         gauge_agg_combine,
         gauge_summary_trans_serialize,
-        gauge_summary_trans_deserialize
+        gauge_summary_trans_deserialize,
+        gauge_agg_moving_trans,
+        gauge_agg_inv_trans,
+        gauge_agg_moving_final
     ],
 );
 
@@ -513,9 +624,10 @@ fn arrow_with_bounds<'a>(
     sketch: GaugeSummary<'a>,
     accessor: AccessorWithBounds<'a>,
 ) -> GaugeSummary<'static> {
+    let levels = sketch.levels();
     let mut builder = GaugeSummaryBuilder::from(MetricSummary::from(sketch));
     builder.set_bounds(accessor.bounds());
-    builder.build().into()
+    GaugeSummary::new(builder.build(), levels)
 }
 
 #[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
@@ -523,9 +635,10 @@ fn with_bounds<'a>(summary: GaugeSummary<'a>, bounds: tstzrange) -> GaugeSummary
     // TODO dedup with previous by using apply_bounds
     unsafe {
         let ptr = bounds.0.cast_mut_ptr();
+        let levels = summary.levels();
         let mut builder = GaugeSummaryBuilder::from(MetricSummary::from(summary));
         builder.set_bounds(get_range(ptr));
-        builder.build().into()
+        GaugeSummary::new(builder.build(), levels)
     }
 }
 
@@ -650,38 +763,146 @@ fn gauge_zero_time<'a>(summary: GaugeSummary<'a>) -> Option<crate::raw::Timestam
     Some(((MetricSummary::from(summary).stats.x_intercept()? * 1_000_000.0) as i64).into())
 }
 
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+fn arrow_gauge_average<'a>(
+    sketch: GaugeSummary<'a>,
+    _accessor: AccessorAverage<'a>,
+) -> Option<f64> {
+    gauge_average(sketch)
+}
+
+// The time-weighted average of the linearly-interpolated gauge between its
+// first and last points, NULL for a single point.
+#[pg_extern(
+    name = "average",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn gauge_average<'a>(summary: GaugeSummary<'a>) -> Option<f64> {
+    let (first, last) = (summary.summary.first, summary.summary.last);
+    summary.levels().average(first.ts, last.ts)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+fn arrow_gauge_integral<'a>(sketch: GaugeSummary<'a>, accessor: AccessorIntegral<'a>) -> f64 {
+    gauge_integral(
+        sketch,
+        String::from_utf8_lossy(accessor.bytes.as_slice()).to_string(),
+    )
+}
+
+#[pg_extern(
+    name = "integral",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn gauge_integral<'a>(summary: GaugeSummary<'a>, unit: default!(String, "'second'")) -> f64 {
+    let unit = match DurationUnit::from_str(&unit) {
+        Some(unit) => unit,
+        None => pgrx::error!(
+            "Unrecognized duration unit: {}. Valid units are: usecond, msecond, second, minute, hour",
+            unit,
+        ),
+    };
+    DurationUnit::Microsec.convert_unit(summary.levels().integral, unit)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+fn arrow_gauge_min_val<'a>(sketch: GaugeSummary<'a>, _accessor: AccessorMinVal<'a>) -> f64 {
+    gauge_min_val(sketch)
+}
+
+#[pg_extern(
+    name = "min_val",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn gauge_min_val<'a>(summary: GaugeSummary<'a>) -> f64 {
+    summary.levels().min.val
+}
+
+#[pg_extern(
+    name = "min_time",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn gauge_min_time<'a>(summary: GaugeSummary<'a>) -> crate::raw::TimestampTz {
+    summary.levels().min.ts.into()
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+fn arrow_gauge_max_val<'a>(sketch: GaugeSummary<'a>, _accessor: AccessorMaxVal<'a>) -> f64 {
+    gauge_max_val(sketch)
+}
+
+#[pg_extern(
+    name = "max_val",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn gauge_max_val<'a>(summary: GaugeSummary<'a>) -> f64 {
+    summary.levels().max.val
+}
+
+#[pg_extern(
+    name = "max_time",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn gauge_max_time<'a>(summary: GaugeSummary<'a>) -> crate::raw::TimestampTz {
+    summary.levels().max.ts.into()
+}
+
 impl From<GaugeSummary<'_>> for MetricSummary {
     fn from(pg: GaugeSummary<'_>) -> Self {
+        Self::from(&pg.summary)
+    }
+}
+
+impl From<&FlatSummary> for MetricSummary {
+    fn from(flat: &FlatSummary) -> Self {
         Self {
-            first: pg.summary.first,
-            second: pg.summary.second,
-            penultimate: pg.summary.penultimate,
-            last: pg.summary.last,
-            reset_sum: pg.summary.reset_sum,
-            num_resets: pg.summary.num_resets,
-            num_changes: pg.summary.num_changes,
-            stats: pg.summary.stats,
-            bounds: pg.summary.bounds.to_i64range(),
+            first: flat.first,
+            second: flat.second,
+            penultimate: flat.penultimate,
+            last: flat.last,
+            reset_sum: flat.reset_sum,
+            num_resets: flat.num_resets,
+            num_changes: flat.num_changes,
+            stats: flat.stats,
+            bounds: flat.bounds.to_i64range(),
         }
     }
 }
 
-impl From<MetricSummary> for GaugeSummary<'_> {
+impl From<MetricSummary> for FlatSummary {
     fn from(internal: MetricSummary) -> Self {
-        unsafe {
-            flatten!(GaugeSummary {
-                summary: FlatSummary {
-                    stats: internal.stats,
-                    first: internal.first,
-                    second: internal.second,
-                    penultimate: internal.penultimate,
-                    last: internal.last,
-                    reset_sum: internal.reset_sum,
-                    num_resets: internal.num_resets,
-                    num_changes: internal.num_changes,
-                    bounds: I64RangeWrapper::from_i64range(internal.bounds)
-                }
-            })
+        Self {
+            stats: internal.stats,
+            first: internal.first,
+            second: internal.second,
+            penultimate: internal.penultimate,
+            last: internal.last,
+            reset_sum: internal.reset_sum,
+            num_resets: internal.num_resets,
+            num_changes: internal.num_changes,
+            bounds: I64RangeWrapper::from_i64range(internal.bounds),
         }
     }
 }
@@ -689,6 +910,7 @@ impl From<MetricSummary> for GaugeSummary<'_> {
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
+    use approx::assert_relative_eq;
     use pgrx_macros::pg_test;
 
     use crate::counter_agg::testing::*;
@@ -731,7 +953,10 @@ mod tests {
             client.update(stmt, None, None).unwrap();
 
             let expected = "(\
-                version:1,\
+                version:2,\
+                min:(ts:\"2020-01-01 00:00:00+00\",val:10),\
+                max:(ts:\"2020-01-01 00:02:00+00\",val:30),\
+                integral:9000000000,\
                 summary:(\
                     stats:(\
                         n:9,\
@@ -786,6 +1011,28 @@ mod tests {
         });
     }
 
+    #[pg_test(error = "unsupported GaugeSummary version 1, expected 2")]
+    fn gauge_agg_old_version() {
+        let pt = TSPoint { ts: 0, val: 10.0 };
+        let summary = GaugeSummary::new(
+            GaugeSummaryBuilder::new(&pt, None).build(),
+            GaugeLevels::new(&pt),
+        );
+        // a version 1 summary is the header followed by the FlatSummary,
+        // without the 40 bytes of levels in between
+        let bytes = summary.to_pg_bytes();
+        let mut old = [&bytes[..8], &bytes[48..]].concat();
+        old[4] = 1;
+        unsafe {
+            pgrx::set_varsize(old.as_mut_ptr().cast(), old.len() as i32);
+            GaugeSummary::from_polymorphic_datum(
+                pg_sys::Datum::from(old.as_ptr()),
+                false,
+                pg_sys::Oid::INVALID,
+            );
+        }
+    }
+
     #[pg_test]
     fn delta_after_gauge_decrease() {
         Spi::connect(|mut client| {
@@ -904,7 +1151,6 @@ mod tests {
         assert_eq!(p1.num_changes, p2.num_changes, "num_changes");
         assert_eq!(p1.num_resets, p2.num_resets, "num_resets");
         assert_eq!(p1.stats.n, p2.stats.n, "n");
-        use approx::assert_relative_eq;
         assert_relative_eq!(p1.stats.sx, p2.stats.sx);
         assert_relative_eq!(p1.stats.sx2, p2.stats.sx2);
         assert_relative_eq!(p1.stats.sy, p2.stats.sy);
@@ -955,10 +1201,114 @@ mod tests {
             let a = select_one!(client, stmt, GaugeSummary);
             let stmt = "WITH t as (SELECT date_trunc('minute', ts), gauge_agg(ts, val) as agg FROM test group by 1 ) SELECT rollup(agg) FROM t";
             let b = select_one!(client, stmt, GaugeSummary);
+            let (a_levels, b_levels) = (a.levels(), b.levels());
+            assert_eq!(a_levels.min, b_levels.min);
+            assert_eq!(a_levels.max, b_levels.max);
+            assert_relative_eq!(a_levels.integral, b_levels.integral);
             assert_close_enough(&a.into(), &b.into());
         });
     }
 
+    #[pg_test]
+    fn levels() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    "CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO test VALUES \
+                        ('2020-01-01 00:00:00+00', 10.0), \
+                        ('2020-01-01 00:01:00+00', 20.0), \
+                        ('2020-01-01 00:02:00+00', 30.0), \
+                        ('2020-01-01 00:03:00+00', 20.0), \
+                        ('2020-01-01 00:04:00+00', 30.0)",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            // (15 + 25 + 25 + 25) * 60s
+            for agg in [
+                "SELECT toolkit_experimental.gauge_agg(ts, val) FROM test",
+                "SELECT toolkit_experimental.rollup(agg) FROM (\
+                    SELECT toolkit_experimental.gauge_agg(ts, val) AS agg \
+                    FROM test GROUP BY ts < '2020-01-01 00:02:00+00') a",
+            ] {
+                let (integral, average, minutes) = client
+                    .update(
+                        &format!(
+                            "SELECT \
+                                toolkit_experimental.integral(agg), \
+                                toolkit_experimental.average(agg), \
+                                toolkit_experimental.integral(agg, 'minute') \
+                            FROM ({agg}) a(agg)"
+                        ),
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                    .first()
+                    .get_three::<f64, f64, f64>()
+                    .unwrap();
+                assert_relative_eq!(integral.unwrap(), 5400.0);
+                assert_relative_eq!(average.unwrap(), 22.5);
+                assert_relative_eq!(minutes.unwrap(), 90.0);
+
+                let (min, max) = client
+                    .update(
+                        &format!(
+                            "SELECT \
+                                toolkit_experimental.min_val(agg), \
+                                toolkit_experimental.max_val(agg) \
+                            FROM ({agg}) a(agg)"
+                        ),
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                    .first()
+                    .get_two::<f64, f64>()
+                    .unwrap();
+                assert_eq!(min, Some(10.0));
+                assert_eq!(max, Some(30.0));
+
+                // ties keep the earliest extreme
+                let (min_time, max_time) = client
+                    .update(
+                        &format!(
+                            "SELECT \
+                                toolkit_experimental.min_time(agg)::text, \
+                                toolkit_experimental.max_time(agg)::text \
+                            FROM ({agg}) a(agg)"
+                        ),
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                    .first()
+                    .get_two::<String, String>()
+                    .unwrap();
+                assert_eq!(min_time.as_deref(), Some("2020-01-01 00:00:00+00"));
+                assert_eq!(max_time.as_deref(), Some("2020-01-01 00:02:00+00"));
+            }
+
+            // a single point has no duration to average over
+            let average = select_one!(
+                client,
+                "SELECT toolkit_experimental.average(toolkit_experimental.gauge_agg(ts, val)) IS NULL \
+                FROM test WHERE val = 10.0",
+                bool
+            );
+            assert!(average);
+        });
+    }
+
     #[pg_test]
     fn gauge_agg_interpolation() {
         Spi::connect(|mut client| {
@@ -1098,6 +1448,69 @@ mod tests {
         });
     }
 
+    #[pg_test]
+    fn moving_aggregate() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    "CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO test \
+                    SELECT '2020-01-01 00:00:00+00'::timestamptz + i * '1 minute'::interval, v \
+                    FROM unnest(ARRAY[10, 20, 5, 15, 15, NULL, 3, 30, 40, 1, 2]::float8[]) \
+                        WITH ORDINALITY AS t(v, i)",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            // compare each windowed frame against aggregating the same rows directly
+            let stmt = "SELECT \
+                    toolkit_experimental.delta(toolkit_experimental.gauge_agg(ts, val) OVER w), \
+                    (SELECT toolkit_experimental.delta(toolkit_experimental.gauge_agg(ts, val)) \
+                        FROM (SELECT * FROM test t WHERE t.ts <= test.ts ORDER BY ts DESC LIMIT 3) f), \
+                    toolkit_experimental.slope(toolkit_experimental.gauge_agg(ts, val) OVER w), \
+                    (SELECT toolkit_experimental.slope(toolkit_experimental.gauge_agg(ts, val)) \
+                        FROM (SELECT * FROM test t WHERE t.ts <= test.ts ORDER BY ts DESC LIMIT 3) f), \
+                    toolkit_experimental.num_changes(toolkit_experimental.gauge_agg(ts, val) OVER w)::float8, \
+                    (SELECT toolkit_experimental.num_changes(toolkit_experimental.gauge_agg(ts, val))::float8 \
+                        FROM (SELECT * FROM test t WHERE t.ts <= test.ts ORDER BY ts DESC LIMIT 3) f), \
+                    toolkit_experimental.min_val(toolkit_experimental.gauge_agg(ts, val) OVER w), \
+                    (SELECT toolkit_experimental.min_val(toolkit_experimental.gauge_agg(ts, val)) \
+                        FROM (SELECT * FROM test t WHERE t.ts <= test.ts ORDER BY ts DESC LIMIT 3) f), \
+                    toolkit_experimental.max_val(toolkit_experimental.gauge_agg(ts, val) OVER w), \
+                    (SELECT toolkit_experimental.max_val(toolkit_experimental.gauge_agg(ts, val)) \
+                        FROM (SELECT * FROM test t WHERE t.ts <= test.ts ORDER BY ts DESC LIMIT 3) f), \
+                    toolkit_experimental.average(toolkit_experimental.gauge_agg(ts, val) OVER w), \
+                    (SELECT toolkit_experimental.average(toolkit_experimental.gauge_agg(ts, val)) \
+                        FROM (SELECT * FROM test t WHERE t.ts <= test.ts ORDER BY ts DESC LIMIT 3) f) \
+                FROM test \
+                WINDOW w AS (ORDER BY ts ROWS BETWEEN 2 PRECEDING AND CURRENT ROW) \
+                ORDER BY ts";
+            let mut rows = 0;
+            for row in client.update(stmt, None, None).unwrap() {
+                rows += 1;
+                for i in (1..=12).step_by(2) {
+                    let moving = row.get::<f64>(i).unwrap();
+                    let expected = row.get::<f64>(i + 1).unwrap();
+                    match (moving, expected) {
+                        (Some(moving), Some(expected)) => {
+                            assert!((moving - expected).abs() < 1e-9, "{moving} != {expected}")
+                        }
+                        (moving, expected) => assert_eq!(moving, expected),
+                    }
+                }
+            }
+            assert_eq!(rows, 11);
+        });
+    }
+
     #[pg_test]
     fn no_results_on_null_input() {
         Spi::connect(|mut client| {
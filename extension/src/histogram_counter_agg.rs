@@ -0,0 +1,633 @@
+use pgrx::{iter::TableIterator, *};
+
+use serde::{Deserialize, Serialize};
+
+use counter_agg::{
+    histogram::{bucket_quantile, Bucket},
+    range::I64Range,
+    CounterSummaryBuilder, MetricSummary,
+};
+use flat_serialize_macro::FlatSerializable;
+use stats_agg::stats2d::StatsSummary2D;
+use tspoint::TSPoint;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    build,
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_type,
+    range::{get_range, I64RangeWrapper},
+    raw::{bytea, tstzrange},
+    ron_inout_funcs,
+};
+
+// A MetricSummary for a single bucket, the bounds are shared by all the buckets
+// and stored once on the HistogramCounterSummary.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, FlatSerializable)]
+#[repr(C)]
+pub struct FlatBucketSummary {
+    le: f64,
+    stats: StatsSummary2D<f64>,
+    first: TSPoint,
+    second: TSPoint,
+    penultimate: TSPoint,
+    last: TSPoint,
+    reset_sum: f64,
+    num_resets: u64,
+    num_changes: u64,
+}
+
+#[pg_schema]
+mod toolkit_experimental {
+    use super::*;
+
+    pg_type! {
+        #[derive(Debug)]
+        struct HistogramCounterSummary<'input> {
+            num_buckets: u32,
+            internal_padding: [u8; 4],
+            buckets: [FlatBucketSummary; self.num_buckets],
+            #[flat_serialize::flatten]
+            bounds: I64RangeWrapper,
+        }
+    }
+
+    ron_inout_funcs!(HistogramCounterSummary);
+}
+
+use toolkit_experimental::*;
+
+impl<'input> HistogramCounterSummary<'input> {
+    // the per-bucket summaries in `le` order
+    fn bucket_summaries(&self) -> impl Iterator<Item = (f64, MetricSummary)> + '_ {
+        let bounds = self.bounds.to_i64range();
+        self.buckets.iter().map(move |b| {
+            (
+                b.le,
+                MetricSummary {
+                    first: b.first,
+                    second: b.second,
+                    penultimate: b.penultimate,
+                    last: b.last,
+                    reset_sum: b.reset_sum,
+                    num_resets: b.num_resets,
+                    num_changes: b.num_changes,
+                    stats: b.stats,
+                    bounds,
+                },
+            )
+        })
+    }
+
+    fn from_bucket_summaries(summaries: Vec<(f64, MetricSummary)>) -> Self {
+        let mut bounds: Option<I64Range> = None;
+        let buckets: Vec<_> = summaries
+            .into_iter()
+            .map(|(le, s)| {
+                bounds = match (bounds, s.bounds) {
+                    (Some(mut a), Some(b)) => {
+                        a.extend(&b);
+                        Some(a)
+                    }
+                    (a, b) => a.or(b),
+                };
+                FlatBucketSummary {
+                    le,
+                    stats: s.stats,
+                    first: s.first,
+                    second: s.second,
+                    penultimate: s.penultimate,
+                    last: s.last,
+                    reset_sum: s.reset_sum,
+                    num_resets: s.num_resets,
+                    num_changes: s.num_changes,
+                }
+            })
+            .collect();
+        build! {
+            HistogramCounterSummary {
+                num_buckets: buckets.len() as _,
+                internal_padding: [0; 4],
+                buckets: buckets.into(),
+                bounds: I64RangeWrapper::from_i64range(bounds),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct HistogramCounterTransState {
+    #[serde(skip)]
+    point_buffer: Vec<(f64, TSPoint)>,
+    #[serde(skip)]
+    bounds: Option<I64Range>, // stores bounds until we combine points, after which, the bounds are stored in each summary
+    // Summaries of each bucket, possibly several per bucket from different
+    // partials, which are combined bucket-by-bucket in time order.
+    summary_buffer: Vec<(f64, MetricSummary)>,
+}
+
+impl HistogramCounterTransState {
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer
+            .sort_unstable_by(|(le1, p1), (le2, p2)| le1.total_cmp(le2).then(p1.ts.cmp(&p2.ts)));
+        let bounds = self.bounds;
+        let mut points = self.point_buffer.drain(..).peekable();
+        while let Some((le, first)) = points.next() {
+            let mut summary = CounterSummaryBuilder::new(&first, bounds);
+            while let Some((_, p)) = points.next_if(|(next, _)| *next == le) {
+                summary
+                    .add_point(&p)
+                    .unwrap_or_else(|e| pgrx::error!("{}", e));
+            }
+            if !summary.bounds_valid() {
+                panic!("counter bounds invalid")
+            }
+            self.summary_buffer.push((le, summary.build()));
+        }
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+
+        self.summary_buffer
+            .sort_unstable_by(|(le1, s1), (le2, s2)| {
+                le1.total_cmp(le2).then(s1.first.ts.cmp(&s2.first.ts))
+            });
+        let mut combined = Vec::new();
+        let mut summaries = std::mem::take(&mut self.summary_buffer)
+            .into_iter()
+            .peekable();
+        while let Some((le, first)) = summaries.next() {
+            let mut summary = CounterSummaryBuilder::from(first);
+            while let Some((_, s)) = summaries.next_if(|(next, _)| *next == le) {
+                summary
+                    .combine(&s)
+                    .unwrap_or_else(|e| pgrx::error!("{}", e));
+            }
+            combined.push((le, summary.build()));
+        }
+        self.summary_buffer = combined;
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn histogram_counter_trans_serialize(state: Internal) -> bytea {
+    let state: &mut HistogramCounterTransState = unsafe { state.get_mut().unwrap() };
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn histogram_counter_trans_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    histogram_counter_trans_deserialize_inner(bytes).internal()
+}
+pub fn histogram_counter_trans_deserialize_inner(
+    bytes: bytea,
+) -> Inner<HistogramCounterTransState> {
+    let c: HistogramCounterTransState = crate::do_deserialize!(bytes, HistogramCounterTransState);
+    c.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn histogram_counter_agg_trans(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    le: Option<f64>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    histogram_counter_agg_trans_inner(unsafe { state.to_inner() }, ts, le, val, bounds, fcinfo)
+        .internal()
+}
+pub fn histogram_counter_agg_trans_inner(
+    state: Option<Inner<HistogramCounterTransState>>,
+    ts: Option<crate::raw::TimestampTz>,
+    le: Option<f64>,
+    val: Option<f64>,
+    bounds: Option<tstzrange>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<HistogramCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let (le, p) = match (ts, le, val) {
+                (Some(ts), Some(le), Some(val)) => (le, TSPoint { ts: ts.into(), val }),
+                _ => return state,
+            };
+            if le.is_nan() {
+                pgrx::error!("histogram bucket upper bounds must not be NaN")
+            }
+            let mut state = state.unwrap_or_else(|| {
+                let mut s = HistogramCounterTransState::default();
+                if let Some(r) = bounds {
+                    s.bounds = get_range(r.0.cast_mut_ptr());
+                }
+                s.into()
+            });
+            state.point_buffer.push((le, p));
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn histogram_counter_agg_trans_no_bounds(
+    state: Internal,
+    ts: Option<crate::raw::TimestampTz>,
+    le: Option<f64>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    histogram_counter_agg_trans_inner(unsafe { state.to_inner() }, ts, le, val, None, fcinfo)
+        .internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn histogram_counter_agg_summary_trans<'a>(
+    state: Internal,
+    value: Option<HistogramCounterSummary<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    histogram_counter_agg_summary_trans_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
+}
+pub fn histogram_counter_agg_summary_trans_inner(
+    state: Option<Inner<HistogramCounterTransState>>,
+    value: Option<HistogramCounterSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<HistogramCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state = state.unwrap_or_else(|| HistogramCounterTransState::default().into());
+            state.summary_buffer.extend(value.bucket_summaries());
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn histogram_counter_agg_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        histogram_counter_agg_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+pub fn histogram_counter_agg_combine_inner(
+    state1: Option<Inner<HistogramCounterTransState>>,
+    state2: Option<Inner<HistogramCounterTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<HistogramCounterTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state)) | (Some(state), None) => {
+                let mut s = state.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                s2.summary_buffer.append(&mut s1.summary_buffer);
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn histogram_counter_agg_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<HistogramCounterSummary<'static>> {
+    histogram_counter_agg_final_inner(unsafe { state.to_inner() }, fcinfo)
+}
+fn histogram_counter_agg_final_inner(
+    state: Option<Inner<HistogramCounterTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<HistogramCounterSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.combine_summaries();
+            if state.summary_buffer.is_empty() {
+                return None;
+            }
+            Some(HistogramCounterSummary::from_bucket_summaries(
+                state.summary_buffer,
+            ))
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.histogram_counter_agg( ts timestamptz, le DOUBLE PRECISION, value DOUBLE PRECISION, bounds tstzrange )\n\
+    (\n\
+        sfunc = toolkit_experimental.histogram_counter_agg_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.histogram_counter_agg_final,\n\
+        combinefunc = toolkit_experimental.histogram_counter_agg_combine,\n\
+        serialfunc = toolkit_experimental.histogram_counter_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.histogram_counter_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "histogram_counter_agg",
+    requires = [
+        histogram_counter_agg_trans,
+        histogram_counter_agg_final,
+        histogram_counter_agg_combine,
+        histogram_counter_trans_serialize,
+        histogram_counter_trans_deserialize
+    ],
+);
+
+// allow calling histogram_counter_agg without bounds provided.
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.histogram_counter_agg( ts timestamptz, le DOUBLE PRECISION, value DOUBLE PRECISION )\n\
+    (\n\
+        sfunc = toolkit_experimental.histogram_counter_agg_trans_no_bounds,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.histogram_counter_agg_final,\n\
+        combinefunc = toolkit_experimental.histogram_counter_agg_combine,\n\
+        serialfunc = toolkit_experimental.histogram_counter_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.histogram_counter_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "histogram_counter_agg2",
+    requires = [
+        histogram_counter_agg_trans_no_bounds,
+        histogram_counter_agg_final,
+        histogram_counter_agg_combine,
+        histogram_counter_trans_serialize,
+        histogram_counter_trans_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(hs toolkit_experimental.HistogramCounterSummary)\n\
+    (\n\
+        sfunc = toolkit_experimental.histogram_counter_agg_summary_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.histogram_counter_agg_final,\n\
+        combinefunc = toolkit_experimental.histogram_counter_agg_combine,\n\
+        serialfunc = toolkit_experimental.histogram_counter_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.histogram_counter_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "histogram_counter_rollup",
+    requires = [
+        histogram_counter_agg_summary_trans,
+        histogram_counter_agg_final,
+        histogram_counter_agg_combine,
+        histogram_counter_trans_serialize,
+        histogram_counter_trans_deserialize
+    ],
+);
+
+// With bounds we follow Prometheus and extrapolate each bucket's increase to
+// the edges of the range, without them we use the observed increase.
+fn bucket_increase(summary: &MetricSummary) -> Option<f64> {
+    if summary.bounds.is_none() {
+        return Some(summary.delta());
+    }
+    summary
+        .prometheus_delta()
+        .unwrap_or_else(|e| pgrx::error!("{}", e))
+}
+
+fn bucket_rate(summary: &MetricSummary) -> Option<f64> {
+    if summary.bounds.is_none() {
+        return summary.rate();
+    }
+    summary
+        .prometheus_rate()
+        .unwrap_or_else(|e| pgrx::error!("{}", e))
+}
+
+#[pg_extern(
+    name = "histogram_quantile",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn histogram_counter_quantile<'a>(summary: HistogramCounterSummary<'a>, quantile: f64) -> f64 {
+    // buckets without enough samples to have an increase are skipped, as
+    // Prometheus does for series missing from the range
+    let mut buckets: Vec<_> = summary
+        .bucket_summaries()
+        .filter_map(|(le, s)| {
+            Some(Bucket {
+                upper_bound: le,
+                count: bucket_increase(&s)?,
+            })
+        })
+        .collect();
+    bucket_quantile(quantile, &mut buckets)
+}
+
+#[pg_extern(
+    name = "histogram_rate",
+    strict,
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental"
+)]
+fn histogram_counter_rate<'a>(
+    summary: HistogramCounterSummary<'a>,
+) -> TableIterator<'static, (name!(le, f64), name!(rate, Option<f64>))> {
+    let rates: Vec<_> = summary
+        .bucket_summaries()
+        .map(|(le, s)| (le, bucket_rate(&s)))
+        .collect();
+    TableIterator::new(rates.into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use approx::assert_relative_eq;
+    use pgrx_macros::pg_test;
+
+    use super::*;
+
+    fn make_histogram_table(client: &mut pgrx::spi::SpiClient) {
+        client.update("SET timezone TO 'UTC'", None, None).unwrap();
+        client
+            .update(
+                "CREATE TABLE test(ts timestamptz, le DOUBLE PRECISION, value DOUBLE PRECISION)",
+                None,
+                None,
+            )
+            .unwrap();
+        // every bucket resets between the second and third scrape
+        client
+            .update(
+                "INSERT INTO test VALUES \
+                    ('2020-01-01 00:00:00+00', 0.1, 0), ('2020-01-01 00:00:00+00', 0.5, 0), \
+                    ('2020-01-01 00:00:00+00', 1, 0), ('2020-01-01 00:00:00+00', 'Infinity', 0), \
+                    ('2020-01-01 00:01:00+00', 0.1, 10), ('2020-01-01 00:01:00+00', 0.5, 30), \
+                    ('2020-01-01 00:01:00+00', 1, 40), ('2020-01-01 00:01:00+00', 'Infinity', 40), \
+                    ('2020-01-01 00:02:00+00', 0.1, 2), ('2020-01-01 00:02:00+00', 0.5, 4), \
+                    ('2020-01-01 00:02:00+00', 1, 6), ('2020-01-01 00:02:00+00', 'Infinity', 8)",
+                None,
+                None,
+            )
+            .unwrap();
+    }
+
+    #[pg_test]
+    fn test_histogram_quantile() {
+        Spi::connect(|mut client| {
+            make_histogram_table(&mut client);
+
+            // increases are 12, 34, 46 and 48, so the median falls in the (0.1, 0.5] bucket
+            let q = client
+                .update(
+                    "SELECT toolkit_experimental.histogram_quantile(\
+                        toolkit_experimental.histogram_counter_agg(ts, le, value), 0.5) \
+                    FROM test",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<f64>()
+                .unwrap()
+                .unwrap();
+            assert_relative_eq!(q, 0.1 + 0.4 * (12.0 / 22.0));
+
+            // the +Inf bucket falls back to the highest finite bound
+            let q = client
+                .update(
+                    "SELECT toolkit_experimental.histogram_quantile(\
+                        toolkit_experimental.histogram_counter_agg(ts, le, value), 0.99) \
+                    FROM test",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<f64>()
+                .unwrap()
+                .unwrap();
+            assert_relative_eq!(q, 1.0);
+
+            // rollup of per-minute partials gives the same answer
+            let (a, b) = client
+                .update(
+                    "WITH t AS (\
+                        SELECT toolkit_experimental.histogram_counter_agg(ts, le, value) AS agg \
+                        FROM test GROUP BY date_trunc('minute', ts)) \
+                    SELECT toolkit_experimental.histogram_quantile(toolkit_experimental.rollup(agg), 0.5), \
+                        (SELECT toolkit_experimental.histogram_quantile(\
+                            toolkit_experimental.histogram_counter_agg(ts, le, value), 0.5) FROM test) \
+                    FROM t",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, f64>()
+                .unwrap();
+            assert_relative_eq!(a.unwrap(), b.unwrap());
+
+            // without a +Inf bucket there is no quantile
+            let q = client
+                .update(
+                    "SELECT toolkit_experimental.histogram_quantile(\
+                        toolkit_experimental.histogram_counter_agg(ts, le, value), 0.5) \
+                    FROM test WHERE le < 'Infinity'",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<f64>()
+                .unwrap()
+                .unwrap();
+            assert!(q.is_nan());
+        });
+    }
+
+    #[pg_test]
+    fn test_histogram_rate() {
+        Spi::connect(|mut client| {
+            make_histogram_table(&mut client);
+
+            let rates: Vec<(f64, f64)> = client
+                .update(
+                    "SELECT le, rate FROM toolkit_experimental.histogram_rate(\
+                        (SELECT toolkit_experimental.histogram_counter_agg(ts, le, value) FROM test))",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| (r.get(1).unwrap().unwrap(), r.get(2).unwrap().unwrap()))
+                .collect();
+            assert_eq!(rates.len(), 4);
+            for ((le, rate), (expected_le, increase)) in rates.into_iter().zip([
+                (0.1, 12.0),
+                (0.5, 34.0),
+                (1.0, 46.0),
+                (f64::INFINITY, 48.0),
+            ]) {
+                assert_eq!(le, expected_le);
+                assert_relative_eq!(rate, increase / 120.0);
+            }
+
+            // with bounds each bucket matches the prometheus extrapolation of counter_agg
+            let rates: Vec<(f64, f64)> = client
+                .update(
+                    "WITH bounds AS (\
+                        SELECT '[2020-01-01 00:00:00+00, 2020-01-01 00:02:30.001+00)'::tstzrange AS b), \
+                    histogram AS (\
+                        SELECT r.le, r.rate FROM toolkit_experimental.histogram_rate(\
+                            (SELECT toolkit_experimental.histogram_counter_agg(ts, le, value, b) \
+                            FROM test, bounds)) r), \
+                    counters AS (\
+                        SELECT le, extrapolated_rate(counter_agg(ts, value, b), 'prometheus') AS rate \
+                        FROM test, bounds GROUP BY le) \
+                    SELECT histogram.rate, counters.rate \
+                    FROM histogram JOIN counters USING (le) ORDER BY le",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| (r.get(1).unwrap().unwrap(), r.get(2).unwrap().unwrap()))
+                .collect();
+            assert_eq!(rates.len(), 4);
+            for (histogram, counter) in rates {
+                assert_relative_eq!(histogram, counter);
+            }
+        });
+    }
+
+    #[pg_test(error = "histogram bucket upper bounds must not be NaN")]
+    fn test_histogram_nan_bucket() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.histogram_counter_agg(\
+                        '2020-01-01 00:00:00+00'::timestamptz, 'NaN', 1)",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+}
@@ -36,6 +36,11 @@ impl Hash for HashableDatum {
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct HyperLogLogTrans {
     logger: HLL<'static, HashableDatum, DatumHashBuilder>,
+    // whether hyperloglogs of different sizes were folded together, so the
+    // aggregate can say so once when it finishes; not serialized, to keep the
+    // format of the transition state
+    #[serde(skip)]
+    mixed_sizes: bool,
 }
 
 use crate::raw::AnyElement;
@@ -94,23 +99,14 @@ pub fn hyperloglog_trans_inner(
                 None => {
                     // TODO specialize hash function for bytea types?
                     //      ints? floats? uuids? other primitive types?
-                    let size: usize = size.try_into().unwrap();
-                    let b = size.checked_next_power_of_two().unwrap().trailing_zeros();
-
-                    if !(4..=18).contains(&b) {
-                        error!(
-                            "Invalid value for size {}. \
-                            Size must be between 16 and 262144, \
-                            though less than 1024 not recommended",
-                            size
-                        )
-                    }
+                    let b = precision_for_size(size);
 
                     let typ = arg_type;
                     let collation = get_collation(fc);
                     let hasher = DatumHashBuilder::from_type_id(typ, collation);
                     let trans = HyperLogLogTrans {
-                        logger: HLL::new(b as u8, hasher),
+                        logger: HLL::new(b, hasher),
+                        mixed_sizes: false,
                     };
                     trans.into()
                 }
@@ -122,6 +118,54 @@ pub fn hyperloglog_trans_inner(
     }
 }
 
+fn precision_for_size(size: i32) -> u8 {
+    let size: usize = size.try_into().unwrap();
+    let b = size.checked_next_power_of_two().unwrap().trailing_zeros();
+
+    if !(4..=18).contains(&b) {
+        error!(
+            "Invalid value for size {}. \
+            Size must be between 16 and 262144, \
+            though less than 1024 not recommended",
+            size
+        )
+    }
+    b as u8
+}
+
+// Merge `other` into `logger`, folding whichever has the higher precision
+// down to the lower one if they differ. Returns whether they differed.
+fn merge_logs(
+    logger: &mut HLL<'_, HashableDatum, DatumHashBuilder>,
+    mut other: HLL<'_, HashableDatum, DatumHashBuilder>,
+) -> bool {
+    let folded = fold_to_common_precision(logger, &mut other);
+    logger.merge_in(&other);
+    folded
+}
+
+fn fold_to_common_precision(
+    a: &mut HLL<'_, HashableDatum, DatumHashBuilder>,
+    b: &mut HLL<'_, HashableDatum, DatumHashBuilder>,
+) -> bool {
+    if a.precision() == b.precision() {
+        return false;
+    }
+    let min = a.precision().min(b.precision());
+    a.fold_to_precision(min);
+    b.fold_to_precision(min);
+    true
+}
+
+fn notice_mixed_sizes(state: &HyperLogLogTrans) {
+    if state.mixed_sizes {
+        notice!(
+            "combined hyperloglogs of different sizes, result has size {}",
+            1u64 << state.logger.precision()
+        );
+    }
+}
+
 #[pg_extern(immutable, parallel_safe)]
 pub fn hyperloglog_combine(
     state1: Internal,
@@ -142,8 +186,14 @@ pub fn hyperloglog_combine_inner(
             (Some(state1), None) => Some(state1.clone().into()),
             (Some(state1), Some(state2)) => {
                 let mut logger = state1.logger.clone();
-                logger.merge_in(&state2.logger);
-                Some(HyperLogLogTrans { logger }.into())
+                let folded = merge_logs(&mut logger, state2.logger.clone());
+                Some(
+                    HyperLogLogTrans {
+                        logger,
+                        mixed_sizes: state1.mixed_sizes || state2.mixed_sizes || folded,
+                    }
+                    .into(),
+                )
             }
         })
     }
@@ -154,6 +204,8 @@ use crate::raw::bytea;
 #[pg_extern(immutable, parallel_safe, strict)]
 pub fn hyperloglog_serialize(state: Internal) -> bytea {
     let state: &mut HyperLogLogTrans = unsafe { state.get_mut().unwrap() };
+    // the flag doesn't survive serialization, so a partial aggregate reports it here
+    notice_mixed_sizes(state);
     state.logger.merge_all();
     crate::do_serialize!(state)
 }
@@ -222,6 +274,7 @@ fn hyperloglog_final_inner(
                 Some(state) => state,
             };
 
+            notice_mixed_sizes(&state);
             flatten_log(&mut state.logger).into()
         })
     }
@@ -299,6 +352,7 @@ pub fn hyperloglog_union_inner(
                 None => {
                     let state = HyperLogLogTrans {
                         logger: unflatten_log(other).into_owned(),
+                        mixed_sizes: false,
                     };
                     return Some(state.into());
                 }
@@ -308,7 +362,7 @@ pub fn hyperloglog_union_inner(
                 error!("mismatched types")
             }
             // TODO error on mismatched collation?
-            state.logger.merge_in(&other);
+            state.mixed_sizes |= merge_logs(&mut state.logger, other);
             Some(state)
         })
     }
@@ -388,6 +442,89 @@ pub fn hyperloglog_error<'a>(hyperloglog: HyperLogLog<'a>) -> f64 {
     hyperloglogplusplus::error_for_precision(precision)
 }
 
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn hyperloglog_resize<'a>(hyperloglog: HyperLogLog<'a>, new_size: i32) -> HyperLogLog<'static> {
+    let precision = precision_for_size(new_size);
+    let mut log = unflatten_log(hyperloglog);
+    if precision > log.precision() {
+        error!(
+            "cannot resize a hyperloglog of size {} to the larger size {}",
+            1u64 << log.precision(),
+            1u64 << precision
+        )
+    }
+    log.fold_to_precision(precision);
+    flatten_log(&mut log)
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "intersection_estimate"
+)]
+pub fn hyperloglog_intersection_estimate<'a>(a: HyperLogLog<'a>, b: HyperLogLog<'a>) -> i64 {
+    overlap_counts(a, b).intersection() as i64
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "jaccard"
+)]
+pub fn hyperloglog_jaccard<'a>(a: HyperLogLog<'a>, b: HyperLogLog<'a>) -> f64 {
+    let counts = overlap_counts(a, b);
+    if counts.union == 0 {
+        return 0.0;
+    }
+    counts.intersection() as f64 / counts.union as f64
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "distinct_count_difference"
+)]
+pub fn hyperloglog_distinct_count_difference<'a>(a: HyperLogLog<'a>, b: HyperLogLog<'a>) -> i64 {
+    // |A \ B| = |A ∪ B| - |B|
+    let counts = overlap_counts(a, b);
+    counts.union.saturating_sub(counts.b) as i64
+}
+
+struct OverlapCounts {
+    a: u64,
+    b: u64,
+    union: u64,
+}
+
+impl OverlapCounts {
+    // inclusion-exclusion: |A ∩ B| = |A| + |B| - |A ∪ B|
+    // the estimates are independent so this can go negative for
+    // (nearly) disjoint inputs, clamp it at 0
+    fn intersection(&self) -> u64 {
+        (self.a + self.b).saturating_sub(self.union)
+    }
+}
+
+fn overlap_counts(a: HyperLogLog, b: HyperLogLog) -> OverlapCounts {
+    let mut a = unflatten_log(a);
+    let mut b = unflatten_log(b);
+    if a.buildhasher.type_id != b.buildhasher.type_id {
+        error!("cannot compare hyperloglogs of different types")
+    }
+    // as with rollup, different sizes are compared at the smaller one
+    fold_to_common_precision(&mut a, &mut b);
+    let mut union = a.into_owned();
+    union.merge_in(&b);
+    OverlapCounts {
+        a: a.immutable_estimate_count(),
+        b: b.immutable_estimate_count(),
+        union: union.estimate_count(),
+    }
+}
+
 impl HyperLogLog<'_> {
     pub fn build_from(
         size: i32,
@@ -640,6 +777,7 @@ mod tests {
             );
             let mut control = HyperLogLogTrans {
                 logger: HLL::new(6, hasher),
+                mixed_sizes: false,
             };
             control.logger.add(&HashableDatum(
                 rust_str_to_text_p("first").into_datum().unwrap(),
@@ -1022,6 +1160,195 @@ mod tests {
         })
     }
 
+    #[pg_test]
+    fn test_hll_overlap() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TABLE hll_overlap AS SELECT \
+                        (SELECT hyperloglog(1024, v::text) FROM generate_series(1, 100) v) a, \
+                        (SELECT hyperloglog(1024, v::text) FROM generate_series(51, 150) v) b, \
+                        (SELECT hyperloglog(1024, v::text) FROM generate_series(1001, 1100) v) c",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let (intersection, jaccard, difference) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.intersection_estimate(a, b), \
+                        toolkit_experimental.jaccard(a, b), \
+                        toolkit_experimental.distinct_count_difference(a, b) \
+                    FROM hll_overlap",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_three::<i64, f64, i64>()
+                .unwrap();
+            let intersection = intersection.unwrap();
+            let jaccard = jaccard.unwrap();
+            let difference = difference.unwrap();
+            assert!((45..=55).contains(&intersection), "{intersection}");
+            assert!((0.3..=0.37).contains(&jaccard), "{jaccard}");
+            assert!((45..=55).contains(&difference), "{difference}");
+
+            let (self_intersection, self_jaccard, self_difference) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.intersection_estimate(a, a), \
+                        toolkit_experimental.jaccard(a, a), \
+                        toolkit_experimental.distinct_count_difference(a, a) \
+                    FROM hll_overlap",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_three::<i64, f64, i64>()
+                .unwrap();
+            let count = client
+                .update("SELECT distinct_count(a) FROM hll_overlap", None, None)
+                .unwrap()
+                .first()
+                .get_one::<i64>()
+                .unwrap();
+            assert_eq!(self_intersection, count);
+            assert_eq!(self_jaccard, Some(1.0));
+            assert_eq!(self_difference, Some(0));
+
+            let (disjoint_intersection, disjoint_difference) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.intersection_estimate(a, c), \
+                        toolkit_experimental.distinct_count_difference(a, c) \
+                    FROM hll_overlap",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            assert!(disjoint_intersection.unwrap() <= 5);
+            assert!((95..=105).contains(&disjoint_difference.unwrap()));
+        });
+    }
+
+    #[pg_test]
+    fn test_hll_overlap_mismatched_size() {
+        Spi::connect(|mut client| {
+            let (jaccard, difference) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.jaccard(a, b), \
+                        toolkit_experimental.distinct_count_difference(a, b) \
+                    FROM \
+                        (SELECT hyperloglog(1024, v) FROM generate_series(1, 100) v) q1(a), \
+                        (SELECT hyperloglog(2048, v) FROM generate_series(1, 100) v) q2(b)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, i64>()
+                .unwrap();
+            assert_eq!(jaccard, Some(1.0));
+            assert_eq!(difference, Some(0));
+        });
+    }
+
+    #[pg_test(error = "cannot compare hyperloglogs of different types")]
+    fn test_hll_overlap_mismatched_type() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.intersection_estimate(\
+                        (SELECT hyperloglog(1024, v) FROM generate_series(1, 100) v), \
+                        (SELECT hyperloglog(1024, v::text) FROM generate_series(1, 100) v))",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_hll_resize() {
+        Spi::connect(|mut client| {
+            let (resized, direct) = client
+                .update(
+                    "SELECT \
+                        toolkit_experimental.hyperloglog_resize(hyperloglog(32768, v), 1024)::text, \
+                        hyperloglog(1024, v)::text \
+                    FROM generate_series(1, 100000) v",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<String, String>()
+                .unwrap();
+            assert_eq!(resized.unwrap(), direct.unwrap());
+
+            let (resized, direct) = client
+                .update(
+                    "SELECT \
+                        distinct_count(toolkit_experimental.hyperloglog_resize(hyperloglog(4096, v::text), 64)), \
+                        distinct_count(hyperloglog(64, v::text)) \
+                    FROM generate_series(1, 100) v",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<i64, i64>()
+                .unwrap();
+            assert_eq!(resized, direct);
+        });
+    }
+
+    #[pg_test(error = "cannot resize a hyperloglog of size 1024 to the larger size 4096")]
+    fn test_hll_resize_larger() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.hyperloglog_resize(hyperloglog(1024, v), 4096) \
+                    FROM generate_series(1, 100) v",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_hll_rollup_mixed_sizes() {
+        Spi::connect(|mut client| {
+            let (rollup, direct) = client
+                .update(
+                    "SELECT \
+                        (SELECT rollup(logs)::text FROM (\
+                            (SELECT hyperloglog(32768, v) logs FROM generate_series(1, 50000) v) \
+                            UNION ALL \
+                            (SELECT hyperloglog(4096, v) FROM generate_series(25000, 75000) v) \
+                            UNION ALL \
+                            (SELECT hyperloglog(32768, v) FROM generate_series(70000, 70100) v)\
+                        ) q), \
+                        (SELECT hyperloglog(4096, v)::text FROM generate_series(1, 75000) v)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<String, String>()
+                .unwrap();
+            assert_eq!(rollup.unwrap(), direct.unwrap());
+        });
+    }
+
     #[pg_test]
     fn test_hll_null_rollup() {
         Spi::connect(|mut client| {
@@ -23,6 +23,7 @@ pub mod countminsketch;
 pub mod frequency;
 pub mod gauge_agg;
 pub mod heartbeat_agg;
+pub mod histogram_counter_agg;
 pub mod hyperloglog;
 pub mod lttb;
 pub mod nmost;
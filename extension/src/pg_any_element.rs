@@ -27,6 +27,10 @@ impl PgAnyElement {
     pub fn deep_copy_datum(&self) -> Datum {
         unsafe { deep_copy_datum(self.datum, self.typoid) }
     }
+
+    pub fn datum(&self) -> Datum {
+        self.datum
+    }
 }
 
 impl PartialEq for PgAnyElement {
@@ -134,4 +138,13 @@ impl<V> PgAnyElementHashMap<V> {
     pub fn remove(&mut self, k: &PgAnyElement) -> Option<V> {
         self.0.remove(k)
     }
+    pub fn iter(&self) -> impl Iterator<Item = (&PgAnyElement, &V)> {
+        self.0.iter()
+    }
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.0.values_mut()
+    }
+    pub fn retain(&mut self, f: impl FnMut(&PgAnyElement, &mut V) -> bool) {
+        self.0.retain(f)
+    }
 }
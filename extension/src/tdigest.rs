@@ -4,6 +4,7 @@ use pgrx::*;
 
 use crate::{
     accessors::{
+        toolkit_experimental::{AccessorApproxCdf, AccessorIqr, AccessorTrimmedMean},
         AccessorApproxPercentile, AccessorApproxPercentileRank, AccessorMaxVal, AccessorMean,
         AccessorMinVal, AccessorNumVals,
     },
@@ -416,11 +417,116 @@ pub fn tdigest_mean<'a>(digest: TDigest<'a>) -> f64 {
     }
 }
 
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_tdigest_trimmed_mean<'a>(
+    sketch: TDigest<'a>,
+    accessor: AccessorTrimmedMean<'a>,
+) -> f64 {
+    tdigest_trimmed_mean(sketch, accessor.low, accessor.high)
+}
+
+// Approximate the mean of the values between the `low` and `high` quantiles (0.0-1.0).
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "trimmed_mean"
+)]
+pub fn tdigest_trimmed_mean<'a>(digest: TDigest<'a>, low: f64, high: f64) -> f64 {
+    if !(0.0 <= low && low < high && high <= 1.0) {
+        pgrx::error!("trimmed_mean requires 0 <= low < high <= 1")
+    }
+    digest
+        .to_internal_tdigest()
+        .estimate_trimmed_mean(low, high)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_tdigest_iqr<'a>(sketch: TDigest<'a>, _accessor: AccessorIqr<'a>) -> f64 {
+    tdigest_iqr(sketch)
+}
+
+// Approximate interquartile range, the difference between the 75th and 25th percentiles.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "iqr"
+)]
+pub fn tdigest_iqr<'a>(digest: TDigest<'a>) -> f64 {
+    let digest = digest.to_internal_tdigest();
+    digest.estimate_quantile(0.75) - digest.estimate_quantile(0.25)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_tdigest_approx_cdf<'a>(
+    sketch: TDigest<'a>,
+    accessor: AccessorApproxCdf<'a>,
+) -> Vec<f64> {
+    tdigest_approx_cdf(sketch, accessor.values.as_slice().to_vec())
+}
+
+// Approximate the quantile of each of the given values.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "approx_cdf"
+)]
+pub fn tdigest_approx_cdf<'a>(digest: TDigest<'a>, values: Vec<f64>) -> Vec<f64> {
+    let digest = digest.to_internal_tdigest();
+    values
+        .into_iter()
+        .map(|value| digest.estimate_quantile_at_value(value))
+        .collect()
+}
+
+// The Kolmogorov-Smirnov statistic between the distributions of two digests,
+// the largest difference between their cumulative distributions (0.0-1.0).
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "ks_distance"
+)]
+pub fn tdigest_ks_distance<'a, 'b>(a: TDigest<'a>, b: TDigest<'b>) -> Option<f64> {
+    let (a, b) = nonempty_digests(&a, &b)?;
+    Some(a.ks_distance(&b))
+}
+
+// The Wasserstein (earth mover's) distance between the distributions of two
+// digests, expressed in the units of the digested values.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "wasserstein_distance"
+)]
+pub fn tdigest_wasserstein_distance<'a, 'b>(a: TDigest<'a>, b: TDigest<'b>) -> Option<f64> {
+    let (a, b) = nonempty_digests(&a, &b)?;
+    Some(a.wasserstein_distance(&b))
+}
+
+// None if either digest is empty, as an empty digest has no distribution
+fn nonempty_digests(
+    a: &TDigest<'_>,
+    b: &TDigest<'_>,
+) -> Option<(InternalTDigest, InternalTDigest)> {
+    if a.count == 0 || b.count == 0 {
+        return None;
+    }
+    Some((a.to_internal_tdigest(), b.to_internal_tdigest()))
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
     use super::*;
 
+    use crate::uddsketch::tests::{check_distribution_accessors, check_distribution_distances};
     use pgrx_macros::pg_test;
 
     // Assert equality between two floats, within some fixed error range.
@@ -611,6 +717,38 @@ mod tests {
         assert_eq!(t.format_for_postgres(), si.to_string());
     }
 
+    #[pg_test]
+    fn test_tdigest_distribution_accessors() {
+        Spi::connect(|mut client| {
+            check_distribution_accessors(&mut client, "tdigest(100, value)");
+        });
+    }
+
+    #[pg_test(error = "trimmed_mean requires 0 <= low < high <= 1")]
+    fn test_tdigest_trimmed_mean_invalid_bounds() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.trimmed_mean(tdigest(100, value), 0.9, 0.1) \
+                    FROM generate_series(1, 100) value",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_tdigest_distribution_distances() {
+        Spi::connect(|mut client| {
+            check_distribution_distances(
+                &mut client,
+                "tdigest(100, value)",
+                "'(version:1,buckets:0,max_buckets:100,count:0,sum:0,min:0,max:0,centroids:[])'::tdigest",
+            );
+        });
+    }
+
     #[pg_test]
     fn test_tdigest_io() {
         Spi::connect(|mut client| {
@@ -4,6 +4,7 @@ mod delta;
 mod expansion;
 mod fill_to;
 mod filter;
+mod indicators;
 mod lambda;
 mod map;
 mod sort;
@@ -17,6 +18,7 @@ use super::*;
 use crate::{flatten, pg_type, ron_inout_funcs};
 
 use fill_to::{fill_to, FillToMethod};
+use indicators::MacdLine;
 
 use delta::timevector_delta;
 use sort::sort_timevector;
@@ -76,6 +78,25 @@ pub mod toolkit_experimental {
                 interval: i64,
                 fill_method: FillToMethod,
             },
+            MovingAverage: 12 {
+                window: u64,
+            },
+            Ema: 13 {
+                alpha: f64,
+            },
+            Rsi: 14 {
+                period: u64,
+            },
+            Bollinger: 15 {
+                period: u64,
+                k: f64,
+            },
+            Macd: 16 {
+                fast: u64,
+                slow: u64,
+                signal: u64,
+                line: MacdLine,
+            },
         }
     }
 
@@ -143,6 +164,26 @@ pub fn execute_pipeline_element<'s>(
         Element::FilterLambda { lambda } => filter::apply_lambda_to(timevector, lambda),
         Element::Arithmetic { function, rhs } => arithmetic::apply(timevector, *function, *rhs),
         Element::FillTo { .. } => fill_to(timevector, element),
+        Element::MovingAverage { window } => {
+            indicators::moving_average(&timevector, *window as usize)
+        }
+        Element::Ema { alpha } => indicators::ema(&timevector, *alpha),
+        Element::Rsi { period } => indicators::rsi(&timevector, *period as usize),
+        Element::Bollinger { period, k } => {
+            indicators::bollinger(&timevector, *period as usize, *k)
+        }
+        Element::Macd {
+            fast,
+            slow,
+            signal,
+            line,
+        } => indicators::macd(
+            &timevector,
+            *fast as usize,
+            *slow as usize,
+            *signal as usize,
+            *line,
+        ),
     }
 }
 
@@ -0,0 +1,493 @@
+use pgrx::*;
+
+use flat_serialize_macro::FlatSerializable;
+
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+// Technical indicators over the values of a sorted timevector. Windows and
+// periods count points, not time, so the input is expected to be regular,
+// e.g. the output of a time_bucket or fill_to. Indicators that need a full
+// window before they are defined drop the points preceding it, much like
+// delta drops the first point.
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, FlatSerializable)]
+#[repr(u64)]
+pub enum MacdLine {
+    Macd,
+    Signal,
+    Histogram,
+}
+
+// TODO is (immutable, parallel_safe) correct?
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "moving_average",
+    schema = "toolkit_experimental"
+)]
+pub fn moving_average_pipeline_element(
+    window: i32,
+) -> toolkit_experimental::UnstableTimevectorPipeline<'static> {
+    if window < 1 {
+        panic!("moving_average window must be at least 1")
+    }
+    Element::MovingAverage {
+        window: window as u64,
+    }
+    .flatten()
+}
+
+// TODO is (immutable, parallel_safe) correct?
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "ema",
+    schema = "toolkit_experimental"
+)]
+pub fn ema_pipeline_element(
+    alpha: f64,
+) -> toolkit_experimental::UnstableTimevectorPipeline<'static> {
+    if !(alpha > 0.0 && alpha <= 1.0) {
+        panic!("ema alpha must be greater than 0 and at most 1")
+    }
+    Element::Ema { alpha }.flatten()
+}
+
+// TODO is (immutable, parallel_safe) correct?
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "rsi",
+    schema = "toolkit_experimental"
+)]
+pub fn rsi_pipeline_element(
+    period: i32,
+) -> toolkit_experimental::UnstableTimevectorPipeline<'static> {
+    if period < 1 {
+        panic!("rsi period must be at least 1")
+    }
+    Element::Rsi {
+        period: period as u64,
+    }
+    .flatten()
+}
+
+// `k` is the number of standard deviations from the moving average, positive
+// for the upper band, negative for the lower one and 0 for the middle
+// TODO is (immutable, parallel_safe) correct?
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "bollinger",
+    schema = "toolkit_experimental"
+)]
+pub fn bollinger_pipeline_element(
+    period: i32,
+    k: f64,
+) -> toolkit_experimental::UnstableTimevectorPipeline<'static> {
+    if period < 1 {
+        panic!("bollinger period must be at least 1")
+    }
+    Element::Bollinger {
+        period: period as u64,
+        k,
+    }
+    .flatten()
+}
+
+// TODO is (immutable, parallel_safe) correct?
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "macd",
+    schema = "toolkit_experimental"
+)]
+pub fn macd_pipeline_element(
+    fast: i32,
+    slow: i32,
+    signal: i32,
+    line: default!(String, "'macd'"),
+) -> toolkit_experimental::UnstableTimevectorPipeline<'static> {
+    if fast < 1 || slow < 1 || signal < 1 {
+        panic!("macd periods must be at least 1")
+    }
+    let line = match line.to_lowercase().as_str() {
+        "macd" => MacdLine::Macd,
+        "signal" => MacdLine::Signal,
+        "histogram" => MacdLine::Histogram,
+        _ => panic!("Invalid macd line, expected one of 'macd', 'signal' or 'histogram'"),
+    };
+    Element::Macd {
+        fast: fast as u64,
+        slow: slow as u64,
+        signal: signal as u64,
+        line,
+    }
+    .flatten()
+}
+
+pub fn moving_average<'s>(
+    series: &Timevector_TSTZ_F64<'s>,
+    window: usize,
+) -> Timevector_TSTZ_F64<'s> {
+    let points = points_of(series, "moving_average");
+    bands(series, &points, window, 0.0)
+}
+
+pub fn ema<'s>(series: &Timevector_TSTZ_F64<'s>, alpha: f64) -> Timevector_TSTZ_F64<'s> {
+    let points = points_of(series, "ema");
+    let values = ema_values(points.iter().map(|p| p.val), alpha);
+    with_values(series, &points, values)
+}
+
+// Wilder's RSI: the first average gain and loss are the simple means over the
+// first `period` changes, later ones are smoothed with alpha = 1 / period
+pub fn rsi<'s>(series: &Timevector_TSTZ_F64<'s>, period: usize) -> Timevector_TSTZ_F64<'s> {
+    let points = points_of(series, "rsi");
+    if points.len() <= period {
+        return with_values(series, &[], vec![]);
+    }
+
+    let changes: Vec<f64> = points.windows(2).map(|w| w[1].val - w[0].val).collect();
+    let (gains, losses) = changes[..period]
+        .iter()
+        .fold((0.0, 0.0), |(g, l), &c| (g + c.max(0.0), l + (-c).max(0.0)));
+    let (mut avg_gain, mut avg_loss) = (gains / period as f64, losses / period as f64);
+    let mut values = vec![relative_strength(avg_gain, avg_loss)];
+    for &change in &changes[period..] {
+        avg_gain = (avg_gain * (period - 1) as f64 + change.max(0.0)) / period as f64;
+        avg_loss = (avg_loss * (period - 1) as f64 + (-change).max(0.0)) / period as f64;
+        values.push(relative_strength(avg_gain, avg_loss));
+    }
+    with_values(series, &points[period..], values)
+}
+
+fn relative_strength(avg_gain: f64, avg_loss: f64) -> f64 {
+    match (avg_gain, avg_loss) {
+        // a flat series is neither overbought nor oversold
+        (g, l) if g == 0.0 && l == 0.0 => 50.0,
+        (_, l) if l == 0.0 => 100.0,
+        (g, l) => 100.0 - 100.0 / (1.0 + g / l),
+    }
+}
+
+// the moving average plus `k` population standard deviations over the window
+pub fn bollinger<'s>(
+    series: &Timevector_TSTZ_F64<'s>,
+    period: usize,
+    k: f64,
+) -> Timevector_TSTZ_F64<'s> {
+    let points = points_of(series, "bollinger");
+    bands(series, &points, period, k)
+}
+
+fn bands<'s>(
+    series: &Timevector_TSTZ_F64<'s>,
+    points: &[TSPoint],
+    period: usize,
+    k: f64,
+) -> Timevector_TSTZ_F64<'s> {
+    if points.len() < period {
+        return with_values(series, &[], vec![]);
+    }
+
+    let values = points
+        .windows(period)
+        .map(|window| {
+            let mean = window.iter().map(|p| p.val).sum::<f64>() / period as f64;
+            if k == 0.0 {
+                return mean;
+            }
+            let variance =
+                window.iter().map(|p| (p.val - mean).powi(2)).sum::<f64>() / period as f64;
+            mean + k * variance.sqrt()
+        })
+        .collect();
+    with_values(series, &points[period - 1..], values)
+}
+
+// the EMAs use alpha = 2 / (period + 1) and are seeded with the first value,
+// so there is no warm-up period and every point gets a value
+pub fn macd<'s>(
+    series: &Timevector_TSTZ_F64<'s>,
+    fast: usize,
+    slow: usize,
+    signal: usize,
+    line: MacdLine,
+) -> Timevector_TSTZ_F64<'s> {
+    let points = points_of(series, "macd");
+    let alpha = |period: usize| 2.0 / (period + 1) as f64;
+    let fast = ema_values(points.iter().map(|p| p.val), alpha(fast));
+    let slow = ema_values(points.iter().map(|p| p.val), alpha(slow));
+    let macd: Vec<f64> = fast.iter().zip(&slow).map(|(f, s)| f - s).collect();
+    let values = match line {
+        MacdLine::Macd => macd,
+        MacdLine::Signal => ema_values(macd.into_iter(), alpha(signal)),
+        MacdLine::Histogram => {
+            let signal = ema_values(macd.iter().copied(), alpha(signal));
+            macd.iter().zip(signal).map(|(m, s)| m - s).collect()
+        }
+    };
+    with_values(series, &points, values)
+}
+
+fn ema_values(values: impl Iterator<Item = f64>, alpha: f64) -> Vec<f64> {
+    let mut prev: Option<f64> = None;
+    values
+        .map(|val| {
+            let ema = match prev {
+                None => val,
+                Some(prev) => alpha * val + (1.0 - alpha) * prev,
+            };
+            prev = Some(ema);
+            ema
+        })
+        .collect()
+}
+
+fn points_of(series: &Timevector_TSTZ_F64<'_>, indicator: &str) -> Vec<TSPoint> {
+    if !series.is_sorted() {
+        panic!("can only compute {} for sorted timevector", indicator);
+    }
+    if series.has_nulls() {
+        panic!(
+            "Unable to compute {} over timevector containing nulls",
+            indicator
+        );
+    }
+    series.iter().collect()
+}
+
+// the timevector of `values` at the times of `points`
+fn with_values<'s>(
+    series: &Timevector_TSTZ_F64<'s>,
+    points: &[TSPoint],
+    values: Vec<f64>,
+) -> Timevector_TSTZ_F64<'s> {
+    let points: Vec<TSPoint> = points
+        .iter()
+        .zip(values)
+        .map(|(p, val)| TSPoint { ts: p.ts, val })
+        .collect();
+    let nulls_len = (points.len() + 7) / 8;
+
+    build!(Timevector_TSTZ_F64 {
+        num_points: points.len() as u32,
+        flags: series.flags,
+        internal_padding: [0; 3],
+        points: points.into(),
+        null_val: std::vec::from_elem(0_u8, nulls_len).into(),
+    })
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use approx::assert_relative_eq;
+    use pgrx::*;
+    use pgrx_macros::pg_test;
+
+    fn setup(client: &mut pgrx::spi::SpiClient) {
+        client.update("SET timezone TO 'UTC'", None, None).unwrap();
+        // using the search path trick for this test b/c the operator is
+        // difficult to spot otherwise.
+        let sp = client
+            .update(
+                "SELECT format(' %s, toolkit_experimental',current_setting('search_path'))",
+                None,
+                None,
+            )
+            .unwrap()
+            .first()
+            .get_one::<String>()
+            .unwrap()
+            .unwrap();
+        client
+            .update(&format!("SET LOCAL search_path TO {}", sp), None, None)
+            .unwrap();
+
+        client
+            .update(
+                "CREATE TABLE series(time timestamptz, value double precision)",
+                None,
+                None,
+            )
+            .unwrap();
+        client
+            .update(
+                "INSERT INTO series \
+                VALUES \
+                ('2020-01-01 UTC'::TIMESTAMPTZ, 10.0), \
+                ('2020-01-02 UTC'::TIMESTAMPTZ, 12.0), \
+                ('2020-01-03 UTC'::TIMESTAMPTZ, 11.0), \
+                ('2020-01-04 UTC'::TIMESTAMPTZ, 13.0), \
+                ('2020-01-05 UTC'::TIMESTAMPTZ, 15.0), \
+                ('2020-01-06 UTC'::TIMESTAMPTZ, 14.0)",
+                None,
+                None,
+            )
+            .unwrap();
+    }
+
+    // the (day of month, value) pairs of the pipeline's output
+    fn run(client: &mut pgrx::spi::SpiClient, pipeline: &str) -> Vec<(i32, f64)> {
+        client
+            .update(
+                &format!(
+                    "SELECT extract(day FROM time)::int, value FROM unnest(( \
+                        SELECT timevector(time, value) -> sort() -> {pipeline} FROM series))"
+                ),
+                None,
+                None,
+            )
+            .unwrap()
+            .map(|row| {
+                (
+                    row.get::<i32>(1).unwrap().unwrap(),
+                    row.get::<f64>(2).unwrap().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn assert_values(actual: Vec<(i32, f64)>, expected: &[(i32, f64)]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for ((day, val), (expected_day, expected_val)) in actual.into_iter().zip(expected) {
+            assert_eq!(day, *expected_day);
+            assert_relative_eq!(val, *expected_val);
+        }
+    }
+
+    #[pg_test]
+    fn test_pipeline_moving_average_and_ema() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            assert_values(
+                run(&mut client, "moving_average(3)"),
+                &[(3, 11.0), (4, 12.0), (5, 13.0), (6, 14.0)],
+            );
+            assert_values(
+                run(&mut client, "ema(0.5)"),
+                &[
+                    (1, 10.0),
+                    (2, 11.0),
+                    (3, 11.0),
+                    (4, 12.0),
+                    (5, 13.5),
+                    (6, 13.75),
+                ],
+            );
+            // a window longer than the series leaves nothing
+            assert_values(run(&mut client, "moving_average(7)"), &[]);
+        });
+    }
+
+    #[pg_test]
+    fn test_pipeline_rsi() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            // changes are +2, -1, +2, +2, -1
+            assert_values(
+                run(&mut client, "rsi(2)"),
+                &[
+                    (3, 100.0 - 100.0 / (1.0 + 1.0 / 0.5)),
+                    (4, 100.0 - 100.0 / (1.0 + 1.5 / 0.25)),
+                    (5, 100.0 - 100.0 / (1.0 + 1.75 / 0.125)),
+                    (6, 100.0 - 100.0 / (1.0 + 0.875 / 0.5625)),
+                ],
+            );
+            // with only gains so far the RSI is 100
+            assert_values(run(&mut client, "rsi(1)")[..1].to_vec(), &[(2, 100.0)]);
+        });
+    }
+
+    #[pg_test]
+    fn test_pipeline_bollinger() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            let band = |mean: f64, variance: f64, k: f64| mean + k * f64::sqrt(variance);
+            for k in [2.0, -2.0] {
+                assert_values(
+                    run(&mut client, &format!("bollinger(3, {k})")),
+                    &[
+                        (3, band(11.0, 2.0 / 3.0, k)),
+                        (4, band(12.0, 2.0 / 3.0, k)),
+                        (5, band(13.0, 8.0 / 3.0, k)),
+                        (6, band(14.0, 2.0 / 3.0, k)),
+                    ],
+                );
+            }
+            assert_values(
+                run(&mut client, "bollinger(3, 0)"),
+                &run(&mut client, "moving_average(3)"),
+            );
+        });
+    }
+
+    #[pg_test(error = "can only compute moving_average for sorted timevector")]
+    fn test_pipeline_moving_average_unsorted() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+            client
+                .update(
+                    "SELECT timevector(time, value) -> moving_average(3) \
+                    FROM (SELECT * FROM series ORDER BY time DESC) s",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_pipeline_macd() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            // a fast period of 1 is the value itself, a slow one of 3 is ema(0.5)
+            let days = |values: [f64; 6]| (1..).zip(values).collect::<Vec<_>>();
+            assert_values(
+                run(&mut client, "macd(1, 3, 3)"),
+                &days([0.0, 1.0, 0.0, 1.0, 1.5, 0.25]),
+            );
+            assert_values(
+                run(&mut client, "macd(1, 3, 3, 'signal')"),
+                &days([0.0, 0.5, 0.25, 0.625, 1.0625, 0.65625]),
+            );
+            assert_values(
+                run(&mut client, "macd(1, 3, 3, 'histogram')"),
+                &days([0.0, 0.5, -0.25, 0.375, 0.4375, -0.40625]),
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_pipeline_indicator_output() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            let val = client
+                .update(
+                    "SELECT (ema(0.5) -> macd(12, 26, 9, 'histogram'))::TEXT",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            assert_eq!(
+                val.unwrap(),
+                "(version:1,num_elements:2,elements:[\
+                    Ema(alpha:0.5),\
+                    Macd(fast:12,slow:26,signal:9,line:Histogram)\
+                ])"
+            );
+        });
+    }
+}
@@ -24,6 +24,9 @@ use time_weighted_average::{
 use crate::raw::bytea;
 
 mod accessors;
+mod gaps;
+mod keyed;
+mod stats;
 
 use accessors::{TimeWeightInterpolatedAverageAccessor, TimeWeightInterpolatedIntegralAccessor};
 
@@ -170,6 +173,26 @@ pub fn time_weight_trans_deserialize_inner(bytes: bytea) -> Inner<TimeWeightTran
     t.into()
 }
 
+fn parse_method(method: &str) -> TimeWeightMethod {
+    // TODO technically not portable to ASCII-compatible charsets
+    match method.trim().to_lowercase().as_str() {
+        "linear" | "trapezoidal" => TimeWeightMethod::Linear,
+        "locf" => TimeWeightMethod::LOCF,
+        "nocb" => TimeWeightMethod::NOCB,
+        _ => panic!("unknown method"),
+    }
+}
+
+// NOCB is only accepted by the toolkit_experimental aggregates until it's stabilized
+fn parse_stable_method(method: &str) -> TimeWeightMethod {
+    match parse_method(method) {
+        TimeWeightMethod::NOCB => {
+            pgrx::error!("the NOCB method is only available in toolkit_experimental aggregates")
+        }
+        method => method,
+    }
+}
+
 // these are technically parallel_safe (as in they can be called in a parallel context) even though the aggregate itself is parallel restricted.
 #[pg_extern(immutable, parallel_safe)]
 pub fn time_weight_trans(
@@ -201,12 +224,7 @@ pub fn time_weight_trans_inner(
                 None => {
                     let mut s = TimeWeightTransState {
                         point_buffer: vec![],
-                        // TODO technically not portable to ASCII-compatible charsets
-                        method: match method.trim().to_lowercase().as_str() {
-                            "linear" | "trapezoidal" => TimeWeightMethod::Linear,
-                            "locf" => TimeWeightMethod::LOCF,
-                            _ => panic!("unknown method"),
-                        },
+                        method: parse_stable_method(&method),
                         summary_buffer: vec![],
                     };
                     s.push_point(p);
@@ -714,6 +732,20 @@ mod tests {
         });
     }
 
+    #[pg_test(error = "the NOCB method is only available in toolkit_experimental aggregates")]
+    fn test_time_weight_nocb_experimental_only() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT average(time_weight('NOCB', ts, val)) \
+                    FROM (VALUES ('2020-01-01 00:00:00+00'::timestamptz, 10.0::float8)) v(ts, val)",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
     #[pg_test]
     fn test_time_weight_io() {
         Spi::connect(|mut client| {
@@ -0,0 +1,577 @@
+use pgrx::*;
+use serde::{Deserialize, Serialize};
+
+use time_weighted_average::{
+    TimeWeightError, TimeWeightGapSummary as TimeWeightGapSummaryInternal, TimeWeightMethod,
+    TimeWeightSummary as TimeWeightSummaryInternal,
+};
+use tspoint::TSPoint;
+
+use crate::{
+    accessors::{AccessorAverage, AccessorIntegral},
+    aggregate_utils::in_aggregate_context,
+    datum_utils::interval_to_ms,
+    duration::DurationUnit,
+    flatten,
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_type,
+    raw::{bytea, Interval, TimestampTz},
+    ron_inout_funcs,
+};
+
+use super::parse_method;
+
+#[pg_schema]
+mod toolkit_experimental {
+    use super::*;
+
+    // A TimeWeightSummary that leaves gaps longer than max_gap out of both the
+    // weighted sum and the duration, `excluded` being the total length of those.
+    pg_type! {
+        #[derive(Debug)]
+        struct TimeWeightGapSummary {
+            first: TSPoint,
+            last: TSPoint,
+            weighted_sum: f64,
+            max_gap: i64,
+            excluded: i64,
+            method: TimeWeightMethod,
+        }
+    }
+    ron_inout_funcs!(TimeWeightGapSummary);
+}
+
+use toolkit_experimental::*;
+
+impl<'input> TimeWeightGapSummary<'input> {
+    fn internal(&self) -> TimeWeightGapSummaryInternal {
+        TimeWeightGapSummaryInternal {
+            summary: TimeWeightSummaryInternal {
+                method: self.method,
+                first: self.first,
+                last: self.last,
+                w_sum: self.weighted_sum,
+            },
+            max_gap: self.max_gap,
+            excluded: self.excluded,
+        }
+    }
+
+    fn from_internal(s: TimeWeightGapSummaryInternal) -> TimeWeightGapSummary<'static> {
+        unsafe {
+            flatten!(TimeWeightGapSummary {
+                first: s.summary.first,
+                last: s.summary.last,
+                weighted_sum: s.summary.w_sum,
+                max_gap: s.max_gap,
+                excluded: s.excluded,
+                method: s.summary.method,
+            })
+        }
+    }
+
+    fn interpolate(
+        &self,
+        interval_start: i64,
+        interval_len: i64,
+        prev: Option<TimeWeightGapSummary>,
+        next: Option<TimeWeightGapSummary>,
+    ) -> TimeWeightGapSummary<'static> {
+        assert!(
+            interval_start <= self.first.ts,
+            "Interval start ({}) must be at or before first timestamp ({})",
+            interval_start,
+            self.first.ts
+        );
+        let end = interval_start + interval_len;
+        assert!(
+            end > self.last.ts,
+            "Interval end ({}) must be after last timestamp ({})",
+            end,
+            self.last.ts
+        );
+        let start_prev = match prev {
+            Some(prev) if interval_start < self.first.ts => Some((interval_start, prev.last)),
+            _ => None,
+        };
+        let end_next = match (self.method, next) {
+            (_, Some(next)) => Some((end, Some(next.first))),
+            (TimeWeightMethod::LOCF, None) => Some((end, None)),
+            _ => None,
+        };
+        let summary = self
+            .internal()
+            .with_bounds(start_prev, end_next)
+            .expect("unable to interpolate interval");
+        Self::from_internal(summary)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TimeWeightGapTransState {
+    #[serde(skip)]
+    point_buffer: Vec<TSPoint>,
+    method: TimeWeightMethod,
+    max_gap: i64,
+    summary_buffer: Vec<TimeWeightGapSummaryInternal>,
+}
+
+impl TimeWeightGapTransState {
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer.sort_unstable_by_key(|p| p.ts);
+        self.summary_buffer.push(
+            TimeWeightGapSummaryInternal::new_from_sorted_iter(
+                &self.point_buffer,
+                self.method,
+                self.max_gap,
+            )
+            .unwrap(),
+        );
+        self.point_buffer.clear();
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+        if self.summary_buffer.len() <= 1 {
+            return;
+        }
+        self.summary_buffer
+            .sort_unstable_by_key(|s| s.summary.first.ts);
+        self.summary_buffer =
+            vec![TimeWeightGapSummaryInternal::combine_sorted_iter(&self.summary_buffer).unwrap()];
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn time_weight_gap_trans_serialize(state: Internal) -> bytea {
+    let mut state: Inner<TimeWeightGapTransState> = unsafe { state.to_inner().unwrap() };
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_gap_trans_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    time_weight_gap_trans_deserialize_inner(bytes).internal()
+}
+pub fn time_weight_gap_trans_deserialize_inner(bytes: bytea) -> Inner<TimeWeightGapTransState> {
+    let t: TimeWeightGapTransState = crate::do_deserialize!(bytes, TimeWeightGapTransState);
+    t.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_gap_trans(
+    state: Internal,
+    method: String,
+    ts: Option<TimestampTz>,
+    val: Option<f64>,
+    max_gap: Option<Interval>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        time_weight_gap_trans_inner(state.to_inner(), method, ts, val, max_gap, fcinfo).internal()
+    }
+}
+
+pub fn time_weight_gap_trans_inner(
+    state: Option<Inner<TimeWeightGapTransState>>,
+    method: String,
+    ts: Option<TimestampTz>,
+    val: Option<f64>,
+    max_gap: Option<Interval>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<TimeWeightGapTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (Some(ts), Some(val)) => TSPoint { ts: ts.into(), val },
+                _ => return state,
+            };
+
+            match state {
+                None => {
+                    // a NULL max_gap never excludes anything
+                    let max_gap = match max_gap {
+                        None => i64::MAX,
+                        Some(max_gap) => interval_to_ms(&p.ts.into(), &max_gap),
+                    };
+                    if max_gap <= 0 {
+                        pgrx::error!("max_gap must be a positive interval")
+                    }
+                    let s = TimeWeightGapTransState {
+                        point_buffer: vec![p],
+                        method: parse_method(&method),
+                        max_gap,
+                        summary_buffer: vec![],
+                    };
+                    Some(s.into())
+                }
+                Some(mut s) => {
+                    s.point_buffer.push(p);
+                    Some(s)
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_gap_summary_trans<'a>(
+    state: Internal,
+    next: Option<TimeWeightGapSummary<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    time_weight_gap_summary_trans_inner(unsafe { state.to_inner() }, next, fcinfo).internal()
+}
+
+pub fn time_weight_gap_summary_trans_inner(
+    state: Option<Inner<TimeWeightGapTransState>>,
+    next: Option<TimeWeightGapSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<TimeWeightGapTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state, next) {
+            (state, None) => state,
+            (None, Some(next)) => Some(
+                TimeWeightGapTransState {
+                    point_buffer: vec![],
+                    method: next.method,
+                    max_gap: next.max_gap,
+                    summary_buffer: vec![next.internal()],
+                }
+                .into(),
+            ),
+            (Some(mut state), Some(next)) => {
+                state.summary_buffer.push(next.internal());
+                Some(state)
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_gap_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        time_weight_gap_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+
+pub fn time_weight_gap_combine_inner(
+    state1: Option<Inner<TimeWeightGapTransState>>,
+    state2: Option<Inner<TimeWeightGapTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<TimeWeightGapTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state)) | (Some(state), None) => {
+                let mut s = state.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                s2.summary_buffer.append(&mut s1.summary_buffer);
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn time_weight_gap_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimeWeightGapSummary<'static>> {
+    time_weight_gap_final_inner(unsafe { state.to_inner() }, fcinfo)
+}
+
+fn time_weight_gap_final_inner(
+    state: Option<Inner<TimeWeightGapTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimeWeightGapSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.combine_summaries();
+            debug_assert!(state.summary_buffer.len() <= 1);
+            state
+                .summary_buffer
+                .pop()
+                .map(TimeWeightGapSummary::from_internal)
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.time_weight(method text, ts timestamptz, value DOUBLE PRECISION, max_gap interval)\n\
+    (\n\
+        sfunc = toolkit_experimental.time_weight_gap_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.time_weight_gap_final,\n\
+        combinefunc = toolkit_experimental.time_weight_gap_combine,\n\
+        serialfunc = toolkit_experimental.time_weight_gap_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.time_weight_gap_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n\
+\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(tws toolkit_experimental.TimeWeightGapSummary)\n\
+    (\n\
+        sfunc = toolkit_experimental.time_weight_gap_summary_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.time_weight_gap_final,\n\
+        combinefunc = toolkit_experimental.time_weight_gap_combine,\n\
+        serialfunc = toolkit_experimental.time_weight_gap_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.time_weight_gap_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n\
+",
+    name = "time_weight_gap_agg",
+    requires = [
+        time_weight_gap_trans,
+        time_weight_gap_final,
+        time_weight_gap_combine,
+        time_weight_gap_trans_serialize,
+        time_weight_gap_trans_deserialize,
+        time_weight_gap_summary_trans
+    ],
+);
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_time_weight_gap_average<'a>(
+    tws: Option<TimeWeightGapSummary<'a>>,
+    _accessor: AccessorAverage<'a>,
+) -> Option<f64> {
+    time_weight_gap_average(tws)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_time_weight_gap_integral<'a>(
+    tws: Option<TimeWeightGapSummary<'a>>,
+    accessor: AccessorIntegral<'a>,
+) -> Option<f64> {
+    time_weight_gap_integral(
+        tws,
+        String::from_utf8_lossy(accessor.bytes.as_slice()).to_string(),
+    )
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "average",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_gap_average<'a>(tws: Option<TimeWeightGapSummary<'a>>) -> Option<f64> {
+    match tws?.internal().time_weighted_average() {
+        Ok(a) => Some(a),
+        // as with time_weight, a summary without any duration outside of gaps has no average
+        Err(TimeWeightError::ZeroDuration) => None,
+        Err(e) => Err(e).unwrap(),
+    }
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "integral",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_gap_integral<'a>(
+    tws: Option<TimeWeightGapSummary<'a>>,
+    unit: default!(String, "'second'"),
+) -> Option<f64> {
+    let unit = match DurationUnit::from_str(&unit) {
+        Some(unit) => unit,
+        None => pgrx::error!(
+            "Unrecognized duration unit: {}. Valid units are: usecond, msecond, second, minute, hour",
+            unit,
+        ),
+    };
+    let integral_microsecs = tws?.internal().time_weighted_integral();
+    Some(DurationUnit::Microsec.convert_unit(integral_microsecs, unit))
+}
+
+fn interpolate<'a>(
+    tws: Option<TimeWeightGapSummary>,
+    start: TimestampTz,
+    duration: Interval,
+    prev: Option<TimeWeightGapSummary>,
+    next: Option<TimeWeightGapSummary>,
+) -> Option<TimeWeightGapSummary<'a>> {
+    let tws = tws?;
+    let interval = interval_to_ms(&start, &duration);
+    Some(tws.interpolate(start.into(), interval, prev, next))
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "interpolated_average",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_gap_interpolated_average<'a>(
+    tws: Option<TimeWeightGapSummary<'a>>,
+    start: TimestampTz,
+    duration: Interval,
+    prev: default!(Option<TimeWeightGapSummary<'a>>, "NULL"),
+    next: default!(Option<TimeWeightGapSummary<'a>>, "NULL"),
+) -> Option<f64> {
+    let target = interpolate(tws, start, duration, prev, next);
+    time_weight_gap_average(target)
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "interpolated_integral",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_gap_interpolated_integral<'a>(
+    tws: Option<TimeWeightGapSummary<'a>>,
+    start: TimestampTz,
+    interval: Interval,
+    prev: default!(Option<TimeWeightGapSummary<'a>>, "NULL"),
+    next: default!(Option<TimeWeightGapSummary<'a>>, "NULL"),
+    unit: default!(String, "'second'"),
+) -> Option<f64> {
+    let target = interpolate(tws, start, interval, prev, next);
+    time_weight_gap_integral(target, unit)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx_macros::pg_test;
+
+    macro_rules! select_one {
+        ($client:expr, $stmt:expr, $type:ty) => {
+            $client
+                .update($stmt, None, None)
+                .unwrap()
+                .first()
+                .get_one::<$type>()
+                .unwrap()
+                .unwrap()
+        };
+    }
+
+    fn setup(client: &mut pgrx::spi::SpiClient) {
+        client.update("SET timezone TO 'UTC'", None, None).unwrap();
+        client
+            .update(
+                "CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)",
+                None,
+                None,
+            )
+            .unwrap();
+        // the sensor is offline between 00:10 and 06:10
+        client
+            .update(
+                "INSERT INTO test VALUES \
+                    ('2020-01-01 00:00:00+00', 10.0), \
+                    ('2020-01-01 00:10:00+00', 20.0), \
+                    ('2020-01-01 06:10:00+00', 30.0), \
+                    ('2020-01-01 06:20:00+00', 40.0)",
+                None,
+                None,
+            )
+            .unwrap();
+    }
+
+    #[pg_test]
+    fn test_time_weight_max_gap() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            let agg = |method: &str| {
+                format!(
+                    "SELECT toolkit_experimental.time_weight('{method}', ts, val, '1 hour') FROM test"
+                )
+            };
+            let stmt = format!("SELECT toolkit_experimental.average(({}))", agg("Linear"));
+            assert_eq!(select_one!(client, &*stmt, f64), 25.0);
+            let stmt = format!(
+                "SELECT toolkit_experimental.integral(({}), 'minutes')",
+                agg("Linear")
+            );
+            assert_eq!(select_one!(client, &*stmt, f64), 15.0 * 10.0 + 35.0 * 10.0);
+            let stmt = format!("SELECT toolkit_experimental.average(({}))", agg("LOCF"));
+            assert_eq!(select_one!(client, &*stmt, f64), 20.0);
+            let stmt = format!("SELECT toolkit_experimental.average(({}))", agg("NOCB"));
+            assert_eq!(select_one!(client, &*stmt, f64), 30.0);
+
+            // a max_gap longer than any gap is the same as plain time_weight
+            let stmt = "SELECT toolkit_experimental.average(\
+                    toolkit_experimental.time_weight('Linear', ts, val, '7 hours')) \
+                = average(time_weight('Linear', ts, val)) FROM test";
+            assert!(select_one!(client, stmt, bool));
+            let stmt = "SELECT toolkit_experimental.average(\
+                    toolkit_experimental.time_weight('LOCF', ts, val, NULL)) \
+                = average(time_weight('LOCF', ts, val)) FROM test";
+            assert!(select_one!(client, stmt, bool));
+
+            // a rollup over the gap gets the same answer
+            let stmt =
+                "SELECT toolkit_experimental.average(toolkit_experimental.rollup(tws)) FROM (\
+                    SELECT toolkit_experimental.time_weight('Linear', ts, val, '1 hour') AS tws \
+                    FROM test GROUP BY date_trunc('hour', ts)) s";
+            assert_eq!(select_one!(client, stmt, f64), 25.0);
+
+            let stmt = format!("SELECT ({}) -> average()", agg("NOCB"));
+            assert_eq!(select_one!(client, &*stmt, f64), 30.0);
+        });
+    }
+
+    #[pg_test]
+    fn test_time_weight_max_gap_interpolation() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+
+            // the 06:00 bucket doesn't extend back across the gap to its previous bucket,
+            // so only 06:10-06:20 counts
+            let stmt = "SELECT toolkit_experimental.interpolated_average(\
+                    tws, bucket, '1 hour', LAG(tws) OVER (ORDER BY bucket), NULL) \
+                FROM (\
+                    SELECT date_trunc('hour', ts) AS bucket, \
+                        toolkit_experimental.time_weight('Linear', ts, val, '1 hour') AS tws \
+                    FROM test GROUP BY 1) s \
+                ORDER BY bucket DESC LIMIT 1";
+            assert_eq!(select_one!(client, stmt, f64), 35.0);
+
+            // with LOCF the last value is carried to the end of the bucket, as long as that
+            // isn't further than max_gap
+            let stmt = "SELECT toolkit_experimental.interpolated_integral(\
+                    toolkit_experimental.time_weight('LOCF', ts, val, '1 hour'), \
+                    '2020-01-01 06:10:00+00', '30 minutes', NULL, NULL, 'minutes') \
+                FROM test WHERE ts > '2020-01-01 06:00:00+00'";
+            assert_eq!(select_one!(client, stmt, f64), 30.0 * 10.0 + 40.0 * 20.0);
+        });
+    }
+
+    #[pg_test(error = "max_gap must be a positive interval")]
+    fn test_time_weight_bad_max_gap() {
+        Spi::connect(|mut client| {
+            setup(&mut client);
+            client
+                .update(
+                    "SELECT toolkit_experimental.time_weight('Linear', ts, val, '0 seconds') FROM test",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+
+use pgrx::*;
+
+use serde::{Deserialize, Serialize};
+
+use flat_serialize_macro::FlatSerializable;
+use time_weighted_average::{TimeWeightMethod, TimeWeightSummary as TimeWeightSummaryInternal};
+use tspoint::TSPoint;
+
+use crate::{
+    aggregate_utils::in_aggregate_context,
+    build,
+    datum_utils::interval_to_ms,
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_type,
+    raw::{bytea, Interval, TimestampTz},
+    ron_inout_funcs,
+};
+
+use super::parse_method;
+
+// A TimeWeightSummary for a single series, whose key is stored as the
+// `[key_start, key_end)` range of the KeyedTimeWeightSummary's keys.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, FlatSerializable)]
+#[repr(C)]
+pub struct FlatTimeWeightSeries {
+    key_start: u32,
+    key_end: u32,
+    first: TSPoint,
+    last: TSPoint,
+    weighted_sum: f64,
+}
+
+#[pg_schema]
+mod toolkit_experimental {
+    use super::*;
+
+    pg_type! {
+        #[derive(Debug)]
+        struct KeyedTimeWeightSummary<'input> {
+            num_series: u32,
+            method: TimeWeightMethod,
+            internal_padding: [u8; 3],
+            series: [FlatTimeWeightSeries; self.num_series],
+            keys_len: u64,
+            keys: [u8; self.keys_len],
+        }
+    }
+
+    ron_inout_funcs!(KeyedTimeWeightSummary);
+}
+
+use toolkit_experimental::*;
+
+impl<'input> KeyedTimeWeightSummary<'input> {
+    // the per-series summaries in key order
+    fn series_summaries(&self) -> impl Iterator<Item = (&str, TimeWeightSummaryInternal)> + '_ {
+        let keys = std::str::from_utf8(self.keys.as_slice()).expect("invalid series keys");
+        let method = self.method;
+        self.series.iter().map(move |s| {
+            (
+                &keys[s.key_start as usize..s.key_end as usize],
+                TimeWeightSummaryInternal {
+                    method,
+                    first: s.first,
+                    last: s.last,
+                    w_sum: s.weighted_sum,
+                },
+            )
+        })
+    }
+
+    fn from_series_summaries(
+        method: TimeWeightMethod,
+        summaries: Vec<(String, TimeWeightSummaryInternal)>,
+    ) -> Self {
+        let mut keys = String::new();
+        let series: Vec<_> = summaries
+            .into_iter()
+            .map(|(key, s)| {
+                let key_start = keys.len() as u32;
+                keys.push_str(&key);
+                FlatTimeWeightSeries {
+                    key_start,
+                    key_end: keys.len() as u32,
+                    first: s.first,
+                    last: s.last,
+                    weighted_sum: s.w_sum,
+                }
+            })
+            .collect();
+        build! {
+            KeyedTimeWeightSummary {
+                num_series: series.len() as _,
+                method,
+                internal_padding: [0; 3],
+                series: series.into(),
+                keys_len: keys.len() as _,
+                keys: keys.into_bytes().into(),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyedTimeWeightTransState {
+    #[serde(skip)]
+    point_buffer: Vec<(String, TSPoint)>,
+    method: TimeWeightMethod,
+    // Summaries of each series, possibly several per series from different
+    // partials, which are combined series-by-series in time order.
+    summary_buffer: Vec<(String, TimeWeightSummaryInternal)>,
+}
+
+impl KeyedTimeWeightTransState {
+    fn new(method: TimeWeightMethod) -> Self {
+        Self {
+            point_buffer: vec![],
+            method,
+            summary_buffer: vec![],
+        }
+    }
+
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer
+            .sort_unstable_by(|(k1, p1), (k2, p2)| k1.cmp(k2).then(p1.ts.cmp(&p2.ts)));
+        let mut points = self.point_buffer.drain(..).peekable();
+        while let Some((key, first)) = points.next() {
+            let mut summary = TimeWeightSummaryInternal::new(first, self.method);
+            while let Some((_, p)) = points.next_if(|(next, _)| *next == key) {
+                summary.accum(p).unwrap();
+            }
+            self.summary_buffer.push((key, summary));
+        }
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+
+        self.summary_buffer
+            .sort_unstable_by(|(k1, s1), (k2, s2)| k1.cmp(k2).then(s1.first.ts.cmp(&s2.first.ts)));
+        let mut combined = Vec::new();
+        let mut summaries = std::mem::take(&mut self.summary_buffer)
+            .into_iter()
+            .peekable();
+        while let Some((key, mut summary)) = summaries.next() {
+            while let Some((_, s)) = summaries.next_if(|(next, _)| *next == key) {
+                summary = summary.combine(&s).unwrap();
+            }
+            combined.push((key, summary));
+        }
+        self.summary_buffer = combined;
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn keyed_time_weight_trans_serialize(state: Internal) -> bytea {
+    let state: &mut KeyedTimeWeightTransState = unsafe { state.get_mut().unwrap() };
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_time_weight_trans_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    keyed_time_weight_trans_deserialize_inner(bytes).internal()
+}
+pub fn keyed_time_weight_trans_deserialize_inner(bytes: bytea) -> Inner<KeyedTimeWeightTransState> {
+    let t: KeyedTimeWeightTransState = crate::do_deserialize!(bytes, KeyedTimeWeightTransState);
+    t.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_time_weight_trans(
+    state: Internal,
+    key: Option<String>,
+    method: String,
+    ts: Option<TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    keyed_time_weight_trans_inner(unsafe { state.to_inner() }, key, method, ts, val, fcinfo)
+        .internal()
+}
+pub fn keyed_time_weight_trans_inner(
+    state: Option<Inner<KeyedTimeWeightTransState>>,
+    key: Option<String>,
+    method: String,
+    ts: Option<TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<KeyedTimeWeightTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let (key, p) = match (key, ts, val) {
+                (Some(key), Some(ts), Some(val)) => (key, TSPoint { ts: ts.into(), val }),
+                _ => return state,
+            };
+            let mut state = state
+                .unwrap_or_else(|| KeyedTimeWeightTransState::new(parse_method(&method)).into());
+            state.point_buffer.push((key, p));
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_time_weight_summary_trans<'a>(
+    state: Internal,
+    value: Option<KeyedTimeWeightSummary<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    keyed_time_weight_summary_trans_inner(unsafe { state.to_inner() }, value, fcinfo).internal()
+}
+pub fn keyed_time_weight_summary_trans_inner(
+    state: Option<Inner<KeyedTimeWeightTransState>>,
+    value: Option<KeyedTimeWeightSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<KeyedTimeWeightTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state =
+                state.unwrap_or_else(|| KeyedTimeWeightTransState::new(value.method).into());
+            state.summary_buffer.extend(
+                value
+                    .series_summaries()
+                    .map(|(key, s)| (key.to_string(), s)),
+            );
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn keyed_time_weight_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        keyed_time_weight_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+pub fn keyed_time_weight_combine_inner(
+    state1: Option<Inner<KeyedTimeWeightTransState>>,
+    state2: Option<Inner<KeyedTimeWeightTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<KeyedTimeWeightTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state)) | (Some(state), None) => {
+                let mut s = state.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                s2.summary_buffer.append(&mut s1.summary_buffer);
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn keyed_time_weight_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<KeyedTimeWeightSummary<'static>> {
+    keyed_time_weight_final_inner(unsafe { state.to_inner() }, fcinfo)
+}
+fn keyed_time_weight_final_inner(
+    state: Option<Inner<KeyedTimeWeightTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<KeyedTimeWeightSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.combine_summaries();
+            if state.summary_buffer.is_empty() {
+                return None;
+            }
+            Some(KeyedTimeWeightSummary::from_series_summaries(
+                state.method,
+                state.summary_buffer,
+            ))
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.time_weight_by( series_key TEXT, method TEXT, ts timestamptz, value DOUBLE PRECISION )\n\
+    (\n\
+        sfunc = toolkit_experimental.keyed_time_weight_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.keyed_time_weight_final,\n\
+        combinefunc = toolkit_experimental.keyed_time_weight_combine,\n\
+        serialfunc = toolkit_experimental.keyed_time_weight_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.keyed_time_weight_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "keyed_time_weight_agg",
+    requires = [
+        keyed_time_weight_trans,
+        keyed_time_weight_final,
+        keyed_time_weight_combine,
+        keyed_time_weight_trans_serialize,
+        keyed_time_weight_trans_deserialize
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(ktws toolkit_experimental.KeyedTimeWeightSummary)\n\
+    (\n\
+        sfunc = toolkit_experimental.keyed_time_weight_summary_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.keyed_time_weight_final,\n\
+        combinefunc = toolkit_experimental.keyed_time_weight_combine,\n\
+        serialfunc = toolkit_experimental.keyed_time_weight_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.keyed_time_weight_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n",
+    name = "keyed_time_weight_rollup",
+    requires = [
+        keyed_time_weight_summary_trans,
+        keyed_time_weight_final,
+        keyed_time_weight_combine,
+        keyed_time_weight_trans_serialize,
+        keyed_time_weight_trans_deserialize
+    ],
+);
+
+// The integral of every series over the total time covered by the series,
+// after each is extended to the interval as interpolated_average would, using
+// the same series in `prev` and `next`. This is the average over all
+// series-time rather than an average of per-series averages, so a series
+// covering only part of the interval only counts for that part.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn fleet_average<'a>(
+    summary: Option<KeyedTimeWeightSummary<'a>>,
+    start: TimestampTz,
+    duration: Interval,
+    prev: default!(Option<KeyedTimeWeightSummary<'a>>, "NULL"),
+    next: default!(Option<KeyedTimeWeightSummary<'a>>, "NULL"),
+) -> Option<f64> {
+    let summary = summary?;
+    let interval_start: i64 = start.into();
+    let interval_end = interval_start + interval_to_ms(&start, &duration);
+    let prev: HashMap<_, _> = prev
+        .as_ref()
+        .map(|p| p.series_summaries().collect())
+        .unwrap_or_default();
+    let next: HashMap<_, _> = next
+        .as_ref()
+        .map(|n| n.series_summaries().collect())
+        .unwrap_or_default();
+
+    let mut weighted_sum = 0.0;
+    let mut covered = 0;
+    for (key, s) in summary.series_summaries() {
+        if s.first.ts < interval_start || s.last.ts > interval_end {
+            pgrx::error!("series {} has points outside of the interval", key)
+        }
+        let start_prev = match prev.get(key) {
+            Some(prev) if interval_start < s.first.ts => Some((interval_start, prev.last)),
+            _ => None,
+        };
+        let end_next = match (s.method, next.get(key)) {
+            (_, Some(next)) => Some((interval_end, Some(next.first))),
+            (TimeWeightMethod::LOCF, None) => Some((interval_end, None)),
+            _ => None,
+        };
+        let s = s
+            .with_bounds(start_prev, end_next)
+            .unwrap_or_else(|e| pgrx::error!("unable to interpolate series {}: {:?}", key, e));
+        weighted_sum += s.time_weighted_integral();
+        covered += s.last.ts - s.first.ts;
+    }
+    if covered == 0 {
+        return None;
+    }
+    Some(weighted_sum / covered as f64)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use approx::assert_relative_eq;
+    use pgrx_macros::pg_test;
+
+    fn make_series_table(client: &mut pgrx::spi::SpiClient) {
+        client.update("SET timezone TO 'UTC'", None, None).unwrap();
+        client
+            .update(
+                "CREATE TABLE test(series TEXT, ts timestamptz, val DOUBLE PRECISION)",
+                None,
+                None,
+            )
+            .unwrap();
+        // b starts reporting half way through the first hour
+        client
+            .update(
+                "INSERT INTO test VALUES \
+                    ('a', '2020-01-01 00:00:00+00', 10), \
+                    ('a', '2020-01-01 00:30:00+00', 20), \
+                    ('a', '2020-01-01 01:30:00+00', 40), \
+                    ('b', '2020-01-01 00:30:00+00', 0), \
+                    ('b', '2020-01-01 01:00:00+00', 60)",
+                None,
+                None,
+            )
+            .unwrap();
+    }
+
+    fn select_f64s(client: &mut pgrx::spi::SpiClient, stmt: &str) -> Vec<Option<f64>> {
+        client
+            .update(stmt, None, None)
+            .unwrap()
+            .map(|row| row.get::<f64>(1).unwrap())
+            .collect()
+    }
+
+    #[pg_test]
+    fn test_fleet_average() {
+        Spi::connect(|mut client| {
+            make_series_table(&mut client);
+
+            for agg in [
+                "SELECT toolkit_experimental.time_weight_by(series, 'LOCF', ts, val) \
+                    FROM test WHERE ts < '2020-01-01 01:00:00+00'",
+                "SELECT toolkit_experimental.rollup(agg) FROM (\
+                    SELECT toolkit_experimental.time_weight_by(series, 'LOCF', ts, val) AS agg \
+                    FROM test WHERE ts < '2020-01-01 01:00:00+00' GROUP BY ts) a",
+            ] {
+                // a: 10 for 30 minutes then 20 for 30, b: 0 for its last 30 minutes,
+                // where averaging the averages would give 7.5
+                let avg = select_f64s(
+                    &mut client,
+                    &format!(
+                        "SELECT toolkit_experimental.fleet_average(\
+                            agg, '2020-01-01 00:00:00+00', '1 hour') \
+                        FROM ({agg}) a(agg)"
+                    ),
+                );
+                assert_eq!(avg, vec![Some(900.0 / 90.0)]);
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_fleet_average_interpolated() {
+        Spi::connect(|mut client| {
+            make_series_table(&mut client);
+
+            let avgs = select_f64s(
+                &mut client,
+                "SELECT toolkit_experimental.fleet_average(\
+                    agg, bucket, '1 hour', \
+                    LAG(agg) OVER (ORDER BY bucket), \
+                    LEAD(agg) OVER (ORDER BY bucket)) \
+                FROM (\
+                    SELECT date_trunc('hour', ts) AS bucket, \
+                        toolkit_experimental.time_weight_by(series, 'Linear', ts, val) AS agg \
+                    FROM test GROUP BY 1) s \
+                ORDER BY bucket",
+            );
+            assert_eq!(avgs.len(), 2);
+            // a: 10 -> 20 -> 30 at 01:00, b: 0 -> 60 over its 30 minutes
+            assert_relative_eq!(
+                avgs[0].unwrap(),
+                (15.0 * 30.0 + 25.0 * 30.0 + 30.0 * 30.0) / 90.0
+            );
+            // a: 30 -> 40 over its last 30 minutes, b's single point covers no time
+            assert_relative_eq!(avgs[1].unwrap(), 35.0);
+        });
+    }
+
+    #[pg_test(error = "series a has points outside of the interval")]
+    fn test_fleet_average_outside_interval() {
+        Spi::connect(|mut client| {
+            make_series_table(&mut client);
+            select_f64s(
+                &mut client,
+                "SELECT toolkit_experimental.fleet_average(\
+                    toolkit_experimental.time_weight_by(series, 'LOCF', ts, val), \
+                    '2020-01-01 00:00:00+00', '1 hour') \
+                FROM test",
+            );
+        });
+    }
+}
@@ -0,0 +1,489 @@
+use pgrx::*;
+use serde::{Deserialize, Serialize};
+
+use time_weighted_average::{stats::TimeWeightStats, TimeWeightError, TimeWeightMethod};
+use tspoint::TSPoint;
+
+use crate::{
+    accessors::AccessorAverage,
+    aggregate_utils::in_aggregate_context,
+    build,
+    duration::DurationUnit,
+    palloc::{Inner, Internal, InternalAsValue, ToInternal},
+    pg_type,
+    raw::{bytea, TimestampTz},
+    ron_inout_funcs,
+};
+
+use super::parse_method;
+
+#[pg_schema]
+mod toolkit_experimental {
+    use super::*;
+
+    // A TimeWeightSummary that also keeps the integral of the squared value and
+    // every point, for the time-weighted variance and the time spent above or
+    // below a threshold.
+    pg_type! {
+        #[derive(Debug)]
+        struct TimeWeightStatsSummary<'input> {
+            weighted_sum: f64,
+            weighted_sum_sq: f64,
+            num_points: u32,
+            method: TimeWeightMethod,
+            internal_padding: [u8; 3],
+            points: [TSPoint; self.num_points],
+        }
+    }
+    ron_inout_funcs!(TimeWeightStatsSummary);
+}
+
+use toolkit_experimental::*;
+
+impl<'input> TimeWeightStatsSummary<'input> {
+    fn to_stats(&self) -> TimeWeightStats {
+        TimeWeightStats::from_parts(
+            self.points.iter().collect(),
+            self.method,
+            self.weighted_sum,
+            self.weighted_sum_sq,
+        )
+    }
+
+    fn from_stats(stats: TimeWeightStats) -> TimeWeightStatsSummary<'static> {
+        build! {
+            TimeWeightStatsSummary {
+                weighted_sum: stats.summary.w_sum,
+                weighted_sum_sq: stats.w_sum_sq,
+                num_points: stats.points().len() as _,
+                method: stats.summary.method,
+                internal_padding: [0; 3],
+                points: stats.points().to_vec().into(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TimeWeightStatsTransState {
+    #[serde(skip)]
+    point_buffer: Vec<TSPoint>,
+    method: TimeWeightMethod,
+    summary_buffer: Vec<TimeWeightStats>,
+}
+
+impl TimeWeightStatsTransState {
+    fn combine_points(&mut self) {
+        if self.point_buffer.is_empty() {
+            return;
+        }
+        self.point_buffer.sort_unstable_by_key(|p| p.ts);
+        self.summary_buffer
+            .push(TimeWeightStats::new_from_sorted_iter(&self.point_buffer, self.method).unwrap());
+        self.point_buffer.clear();
+    }
+
+    fn combine_summaries(&mut self) {
+        self.combine_points();
+        if self.summary_buffer.len() <= 1 {
+            return;
+        }
+        self.summary_buffer
+            .sort_unstable_by_key(|s| s.summary.first.ts);
+        self.summary_buffer =
+            vec![TimeWeightStats::combine_sorted_iter(&self.summary_buffer).unwrap()];
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, strict, schema = "toolkit_experimental")]
+pub fn time_weight_stats_trans_serialize(state: Internal) -> bytea {
+    let mut state: Inner<TimeWeightStatsTransState> = unsafe { state.to_inner().unwrap() };
+    state.combine_summaries();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern(strict, immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_stats_trans_deserialize(bytes: bytea, _internal: Internal) -> Option<Internal> {
+    time_weight_stats_trans_deserialize_inner(bytes).internal()
+}
+pub fn time_weight_stats_trans_deserialize_inner(bytes: bytea) -> Inner<TimeWeightStatsTransState> {
+    let t: TimeWeightStatsTransState = crate::do_deserialize!(bytes, TimeWeightStatsTransState);
+    t.into()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_stats_trans(
+    state: Internal,
+    method: String,
+    ts: Option<TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe { time_weight_stats_trans_inner(state.to_inner(), method, ts, val, fcinfo).internal() }
+}
+
+pub fn time_weight_stats_trans_inner(
+    state: Option<Inner<TimeWeightStatsTransState>>,
+    method: String,
+    ts: Option<TimestampTz>,
+    val: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<TimeWeightStatsTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let p = match (ts, val) {
+                (Some(ts), Some(val)) => TSPoint { ts: ts.into(), val },
+                _ => return state,
+            };
+
+            match state {
+                None => {
+                    let s = TimeWeightStatsTransState {
+                        point_buffer: vec![p],
+                        method: parse_method(&method),
+                        summary_buffer: vec![],
+                    };
+                    Some(s.into())
+                }
+                Some(mut s) => {
+                    s.point_buffer.push(p);
+                    Some(s)
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_stats_summary_trans<'a>(
+    state: Internal,
+    next: Option<TimeWeightStatsSummary<'a>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    time_weight_stats_summary_trans_inner(unsafe { state.to_inner() }, next, fcinfo).internal()
+}
+
+pub fn time_weight_stats_summary_trans_inner(
+    state: Option<Inner<TimeWeightStatsTransState>>,
+    next: Option<TimeWeightStatsSummary>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<TimeWeightStatsTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state, next) {
+            (state, None) => state,
+            (None, Some(next)) => Some(
+                TimeWeightStatsTransState {
+                    point_buffer: vec![],
+                    method: next.method,
+                    summary_buffer: vec![next.to_stats()],
+                }
+                .into(),
+            ),
+            (Some(mut state), Some(next)) => {
+                state.summary_buffer.push(next.to_stats());
+                Some(state)
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn time_weight_stats_combine(
+    state1: Internal,
+    state2: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    unsafe {
+        time_weight_stats_combine_inner(state1.to_inner(), state2.to_inner(), fcinfo).internal()
+    }
+}
+
+pub fn time_weight_stats_combine_inner(
+    state1: Option<Inner<TimeWeightStatsTransState>>,
+    state2: Option<Inner<TimeWeightStatsTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<TimeWeightStatsTransState>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state1, state2) {
+            (None, None) => None,
+            (None, Some(state)) | (Some(state), None) => {
+                let mut s = state.clone();
+                s.combine_points();
+                Some(s.into())
+            }
+            (Some(state1), Some(state2)) => {
+                let mut s1 = state1.clone();
+                s1.combine_points();
+                let mut s2 = state2.clone();
+                s2.combine_points();
+                s2.summary_buffer.append(&mut s1.summary_buffer);
+                Some(s2.into())
+            }
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+fn time_weight_stats_final(
+    state: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimeWeightStatsSummary<'static>> {
+    time_weight_stats_final_inner(unsafe { state.to_inner() }, fcinfo)
+}
+
+fn time_weight_stats_final_inner(
+    state: Option<Inner<TimeWeightStatsTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimeWeightStatsSummary<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?.clone();
+            state.combine_summaries();
+            debug_assert!(state.summary_buffer.len() <= 1);
+            state
+                .summary_buffer
+                .pop()
+                .map(TimeWeightStatsSummary::from_stats)
+        })
+    }
+}
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.time_weight_stats(method text, ts timestamptz, value DOUBLE PRECISION)\n\
+    (\n\
+        sfunc = toolkit_experimental.time_weight_stats_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.time_weight_stats_final,\n\
+        combinefunc = toolkit_experimental.time_weight_stats_combine,\n\
+        serialfunc = toolkit_experimental.time_weight_stats_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.time_weight_stats_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n\
+\n\
+    CREATE AGGREGATE toolkit_experimental.rollup(tws toolkit_experimental.TimeWeightStatsSummary)\n\
+    (\n\
+        sfunc = toolkit_experimental.time_weight_stats_summary_trans,\n\
+        stype = internal,\n\
+        finalfunc = toolkit_experimental.time_weight_stats_final,\n\
+        combinefunc = toolkit_experimental.time_weight_stats_combine,\n\
+        serialfunc = toolkit_experimental.time_weight_stats_trans_serialize,\n\
+        deserialfunc = toolkit_experimental.time_weight_stats_trans_deserialize,\n\
+        parallel = restricted\n\
+    );\n\
+",
+    name = "time_weight_stats_agg",
+    requires = [
+        time_weight_stats_trans,
+        time_weight_stats_final,
+        time_weight_stats_combine,
+        time_weight_stats_trans_serialize,
+        time_weight_stats_trans_deserialize,
+        time_weight_stats_summary_trans
+    ],
+);
+
+// without bounds the statistics of a single value are undefined, return null
+// as average() does rather than throwing an error
+fn zero_duration_as_null(result: Result<f64, TimeWeightError>) -> Option<f64> {
+    match result {
+        Ok(v) => Some(v),
+        Err(TimeWeightError::ZeroDuration) => None,
+        Err(e) => Err(e).unwrap(),
+    }
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_time_weight_stats_average<'a>(
+    tws: Option<TimeWeightStatsSummary<'a>>,
+    _accessor: AccessorAverage<'a>,
+) -> Option<f64> {
+    time_weight_stats_average(tws)
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "average",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_stats_average<'a>(tws: Option<TimeWeightStatsSummary<'a>>) -> Option<f64> {
+    zero_duration_as_null(tws?.to_stats().time_weighted_average())
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "time_weighted_variance",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_stats_variance<'a>(tws: Option<TimeWeightStatsSummary<'a>>) -> Option<f64> {
+    zero_duration_as_null(tws?.to_stats().time_weighted_variance())
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "time_weighted_stddev",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_stats_stddev<'a>(tws: Option<TimeWeightStatsSummary<'a>>) -> Option<f64> {
+    zero_duration_as_null(tws?.to_stats().time_weighted_stddev())
+}
+
+fn parse_unit(unit: &str) -> DurationUnit {
+    match DurationUnit::from_str(unit) {
+        Some(unit) => unit,
+        None => pgrx::error!(
+            "Unrecognized duration unit: {}. Valid units are: usecond, msecond, second, minute, hour",
+            unit,
+        ),
+    }
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "duration_above",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_stats_duration_above<'a>(
+    tws: Option<TimeWeightStatsSummary<'a>>,
+    threshold: f64,
+    unit: default!(String, "'second'"),
+) -> Option<f64> {
+    let unit = parse_unit(&unit);
+    let duration = tws?.to_stats().duration_above(threshold);
+    Some(DurationUnit::Microsec.convert_unit(duration, unit))
+}
+
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    name = "duration_below",
+    schema = "toolkit_experimental"
+)]
+pub fn time_weight_stats_duration_below<'a>(
+    tws: Option<TimeWeightStatsSummary<'a>>,
+    threshold: f64,
+    unit: default!(String, "'second'"),
+) -> Option<f64> {
+    let unit = parse_unit(&unit);
+    let duration = tws?.to_stats().duration_below(threshold);
+    Some(DurationUnit::Microsec.convert_unit(duration, unit))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx_macros::pg_test;
+
+    macro_rules! select_one {
+        ($client:expr, $stmt:expr, $type:ty) => {
+            $client
+                .update($stmt, None, None)
+                .unwrap()
+                .first()
+                .get_one::<$type>()
+                .unwrap()
+                .unwrap()
+        };
+    }
+
+    #[pg_test]
+    fn test_time_weight_stats() {
+        Spi::connect(|mut client| {
+            client.update("SET timezone TO 'UTC'", None, None).unwrap();
+            client
+                .update(
+                    "CREATE TABLE test(ts timestamptz, val DOUBLE PRECISION)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO test VALUES \
+                        ('2020-01-01 00:00:00+00', 10.0), \
+                        ('2020-01-01 00:10:00+00', 30.0), \
+                        ('2020-01-01 00:20:00+00', 30.0), \
+                        ('2020-01-01 00:40:00+00', -10.0)",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let agg = |method: &str| {
+                format!(
+                    "SELECT toolkit_experimental.time_weight_stats('{method}', ts, val) FROM test"
+                )
+            };
+
+            // LOCF: 10 for 10 minutes, 30 for 30
+            let mean: f64 = (10.0 * 10.0 + 30.0 * 30.0) / 40.0;
+            let variance = (100.0 * 10.0 + 900.0 * 30.0) / 40.0 - mean * mean;
+            let stmt = format!("SELECT toolkit_experimental.average(({}))", agg("LOCF"));
+            assert_eq!(select_one!(client, &*stmt, f64), mean);
+            let stmt = format!(
+                "SELECT toolkit_experimental.time_weighted_variance(({}))",
+                agg("LOCF")
+            );
+            assert!((select_one!(client, &*stmt, f64) - variance).abs() < 1e-9);
+            let stmt = format!(
+                "SELECT toolkit_experimental.time_weighted_stddev(({}))",
+                agg("LOCF")
+            );
+            assert!((select_one!(client, &*stmt, f64) - variance.sqrt()).abs() < 1e-9);
+
+            // the average agrees with time_weight
+            let stmt = format!(
+                "SELECT toolkit_experimental.average(({})) = (SELECT average(time_weight('Linear', ts, val)) FROM test)",
+                agg("Linear")
+            );
+            assert!(select_one!(client, &*stmt, bool));
+
+            let stmt = format!(
+                "SELECT toolkit_experimental.duration_above(({}), 20, 'minutes')",
+                agg("LOCF")
+            );
+            assert_eq!(select_one!(client, &*stmt, f64), 30.0);
+            let stmt = format!(
+                "SELECT toolkit_experimental.duration_below(({}), 20)",
+                agg("LOCF")
+            );
+            assert_eq!(select_one!(client, &*stmt, f64), 600.0);
+            // linear crosses 20 at 00:05 and 00:25
+            let stmt = format!(
+                "SELECT toolkit_experimental.duration_above(({}), 20, 'minutes')",
+                agg("Linear")
+            );
+            assert_eq!(select_one!(client, &*stmt, f64), 20.0);
+            let stmt = format!(
+                "SELECT toolkit_experimental.duration_below(({}), 20, 'minutes')",
+                agg("NOCB")
+            );
+            assert_eq!(select_one!(client, &*stmt, f64), 20.0);
+
+            // rollup over partials gets the same answers
+            let rollup = "SELECT toolkit_experimental.rollup(tws) FROM (\
+                    SELECT toolkit_experimental.time_weight_stats('Linear', ts, val) AS tws \
+                    FROM test GROUP BY date_trunc('minute', ts)) s";
+            let stmt = format!(
+                "SELECT toolkit_experimental.time_weighted_variance(({rollup})) \
+                    - toolkit_experimental.time_weighted_variance(({}))",
+                agg("Linear")
+            );
+            assert!(select_one!(client, &*stmt, f64).abs() < 1e-9);
+            let stmt =
+                format!("SELECT toolkit_experimental.duration_above(({rollup}), 20, 'minutes')");
+            assert_eq!(select_one!(client, &*stmt, f64), 20.0);
+
+            // a single point has no variance
+            let stmt = "SELECT toolkit_experimental.time_weighted_variance(\
+                    toolkit_experimental.time_weight_stats('LOCF', ts, val)) IS NULL \
+                FROM test WHERE val = 10";
+            assert!(select_one!(client, stmt, bool));
+        });
+    }
+}
@@ -11,6 +11,14 @@ impl PartialEq for CachedDatum<'_> {
     }
 }
 
+/// The layout version a pg_type's stored values must have to be read. Types
+/// whose layout has changed shadow this with an inherent `LAYOUT_VERSION`, so
+/// that values in an older layout are rejected before they're parsed as the
+/// current one; other types accept whatever version was written.
+pub trait LayoutVersion {
+    const LAYOUT_VERSION: Option<u8> = None;
+}
+
 #[macro_export]
 macro_rules! pg_type {
     // base case, all fields are collected into $vals
@@ -133,6 +141,8 @@ macro_rules! pg_type_impl {
                 }
             }
 
+            impl<$lifetemplate> $crate::type_builder::LayoutVersion for [<$name Data>] $(<$inlife>)? {}
+
             impl<$lifetemplate> [<$name Data>] $(<$inlife>)? {
                 #[allow(clippy::missing_safety_doc)]
                 pub unsafe fn flatten<'any>(&self) -> $name<'any> {
@@ -182,6 +192,9 @@ macro_rules! pg_type_impl {
                     Self: Sized,
                 {
                     use flat_serialize::FlatSerializable as _;
+                    // unused by types that shadow the version
+                    #[allow(unused_imports)]
+                    use $crate::type_builder::LayoutVersion as _;
                     if is_null {
                         return None;
                     }
@@ -193,6 +206,17 @@ macro_rules! pg_type_impl {
                     }
                     let data_len = pgrx::varsize_any(ptr);
                     let bytes = std::slice::from_raw_parts(ptr as *mut u8, data_len);
+                    if let Some(expected) = [<$name Data>]::LAYOUT_VERSION {
+                        // the version follows the 4-byte varlena header
+                        let version = bytes.get(4).copied().unwrap_or(0);
+                        if version != expected {
+                            error!(
+                                concat!("unsupported ", stringify!($name), " version {}, expected {}"),
+                                version,
+                                expected,
+                            )
+                        }
+                    }
                     let (data, _) = match [<$name Data>]::try_ref(bytes) {
                         Ok(wrapped) => wrapped,
                         Err(e) => error!(concat!("invalid ", stringify!($name), " {:?}, got len {}"), e, bytes.len()),
@@ -276,12 +300,13 @@ macro_rules! ron_inout_funcs {
 
 #[macro_export]
 macro_rules! flatten {
-    ($typ:ident { $($field:ident$(: $value:expr)?),* $(,)? }) => {
+    // types whose layout has changed give the version explicitly
+    ($typ:ident { version: $version:expr, $($field:ident$(: $value:expr)?),* $(,)? }) => {
         {
             let data = ::paste::paste! {
                 [<$typ Data>] {
                     header: 0,
-                    version: 1,
+                    version: $version,
                     padding: [0; 3],
                     $(
                         $field$(: $value)?
@@ -290,17 +315,21 @@ macro_rules! flatten {
             };
             data.flatten()
         }
-    }
+    };
+    ($typ:ident { $($field:ident$(: $value:expr)?),* $(,)? }) => {
+        $crate::flatten!($typ { version: 1, $($field$(: $value)?),* })
+    };
 }
 
 #[macro_export]
 macro_rules! build {
-    ($typ:ident { $($field:ident$(: $value:expr)?),* $(,)? }) => {
+    // types whose layout has changed give the version explicitly
+    ($typ:ident { version: $version:expr, $($field:ident$(: $value:expr)?),* $(,)? }) => {
         {
             <$typ>::from(::paste::paste! {
                 [<$typ Data>] {
                     header: 0,
-                    version: 1,
+                    version: $version,
                     padding: [0; 3],
                     $(
                         $field$(: $value)?
@@ -308,7 +337,10 @@ macro_rules! build {
                 }
             })
         }
-    }
+    };
+    ($typ:ident { $($field:ident$(: $value:expr)?),* $(,)? }) => {
+        $crate::build!($typ { version: 1, $($field$(: $value)?),* })
+    };
 }
 
 #[repr(u8)]
@@ -368,7 +400,10 @@ macro_rules! do_serialize {
 }
 #[macro_export]
 macro_rules! do_deserialize {
-    ($bytes: expr, $t: ty) => {{
+    ($bytes: expr, $t: ty) => {
+        $crate::do_deserialize!($bytes, $t, version: 1)
+    };
+    ($bytes: expr, $t: ty, version: $version: expr) => {{
         use $crate::type_builder::SerializationType;
 
         let state: $t = unsafe {
@@ -381,7 +416,7 @@ macro_rules! do_deserialize {
             if bytes.len() < 1 {
                 pgrx::error!("deserialization error, no bytes")
             }
-            if bytes[0] != 1 {
+            if bytes[0] != $version {
                 pgrx::error!(
                     "deserialization error, invalid serialization version {}",
                     bytes[0]
@@ -399,3 +434,15 @@ macro_rules! do_deserialize {
         state.into()
     }};
 }
+
+// the version `do_serialize!` wrote, for states whose format has changed
+pub fn serialized_version(bytes: crate::raw::bytea) -> u8 {
+    unsafe {
+        let input: pgrx::pg_sys::Datum = bytes.into();
+        let detoasted = pgrx::pg_sys::pg_detoast_datum_packed(input.cast_mut_ptr());
+        if pgrx::varsize_any_exhdr(detoasted) < 1 {
+            pgrx::error!("deserialization error, no bytes")
+        }
+        *(pgrx::vardata_any(detoasted) as *const u8)
+    }
+}
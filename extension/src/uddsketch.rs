@@ -1,4 +1,4 @@
-use pgrx::*;
+use pgrx::{iter::TableIterator, *};
 
 use encodings::{delta, prefix_varint};
 
@@ -6,6 +6,7 @@ use uddsketch::{SketchHashKey, UDDSketch as UddSketchInternal};
 
 use crate::{
     accessors::{
+        toolkit_experimental::{AccessorApproxCdf, AccessorIqr, AccessorTrimmedMean},
         AccessorApproxPercentile, AccessorApproxPercentileRank, AccessorError, AccessorMean,
         AccessorNumVals, AccessorPercentileArray,
     },
@@ -74,6 +75,159 @@ pub fn percentile_agg_trans_inner(
     let default_max_error = PERCENTILE_AGG_DEFAULT_ERROR;
     uddsketch_trans_inner(state, default_size as _, default_max_error, value, fcinfo)
 }
+
+// PG function for adding weighted values to a sketch, e.g. from pre-aggregated
+// histogram rows. Null values and weights are ignored.
+// Unlike `uddsketch_trans` this always returns a state, as it's also used as the
+// moving-aggregate transition function and the inverse function can't accept a
+// NULL state.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn uddsketch_weighted_trans(
+    state: Internal,
+    size: i32,
+    max_error: f64,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    uddsketch_weighted_trans_inner(
+        unsafe { state.to_inner() },
+        size,
+        max_error,
+        value,
+        weight,
+        fcinfo,
+    )
+    .internal()
+}
+
+pub fn uddsketch_weighted_trans_inner(
+    state: Option<Inner<UddSketchInternal>>,
+    size: i32,
+    max_error: f64,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<UddSketchInternal>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = match state {
+                None => UddSketchInternal::new(size as u64, max_error).into(),
+                Some(state) => state,
+            };
+            if let (Some(value), Some(weight)) = (value, weight) {
+                state.add_weighted_value(value, weight_to_count(weight));
+            }
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn uddsketch_weighted_inv_trans(
+    state: Internal,
+    _size: i32,
+    _max_error: f64,
+    value: Option<f64>,
+    weight: Option<i64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    uddsketch_inv_trans_inner(
+        unsafe { state.to_inner() },
+        value,
+        weight.unwrap_or(0),
+        fcinfo,
+    )
+    .internal()
+}
+
+// moving-aggregate transition function for uddsketch, see `uddsketch_weighted_trans`
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn uddsketch_moving_trans(
+    state: Internal,
+    size: i32,
+    max_error: f64,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    uddsketch_weighted_trans_inner(
+        unsafe { state.to_inner() },
+        size,
+        max_error,
+        value,
+        Some(1),
+        fcinfo,
+    )
+    .internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn uddsketch_inv_trans(
+    state: Internal,
+    _size: i32,
+    _max_error: f64,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    uddsketch_inv_trans_inner(unsafe { state.to_inner() }, value, 1, fcinfo).internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn percentile_agg_moving_trans(
+    state: Internal,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    uddsketch_weighted_trans_inner(
+        unsafe { state.to_inner() },
+        PERCENTILE_AGG_DEFAULT_SIZE as _,
+        PERCENTILE_AGG_DEFAULT_ERROR,
+        value,
+        Some(1),
+        fcinfo,
+    )
+    .internal()
+}
+
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn percentile_agg_inv_trans(
+    state: Internal,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal> {
+    uddsketch_inv_trans_inner(unsafe { state.to_inner() }, value, 1, fcinfo).internal()
+}
+
+pub fn uddsketch_inv_trans_inner(
+    state: Option<Inner<UddSketchInternal>>,
+    value: Option<f64>,
+    weight: i64,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Inner<UddSketchInternal>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || match (state, value) {
+            (None, _) => panic!("Inverse function should never be called with NULL state"),
+            (Some(state), None) => Some(state),
+            (Some(mut state), Some(value)) => {
+                // Returning NULL tells postgres to recompute the window from
+                // scratch, which is the correct fallback if the value somehow
+                // isn't where we expect it to be.
+                if !state.remove_weighted_value(value, weight_to_count(weight)) {
+                    return None;
+                }
+                Some(state)
+            }
+        })
+    }
+}
+
+fn weight_to_count(weight: i64) -> u64 {
+    if weight < 0 {
+        pgrx::error!("uddsketch weights must not be negative")
+    }
+    weight as u64
+}
+
 // PG function for merging sketches.
 #[pg_extern(immutable, parallel_safe)]
 pub fn uddsketch_combine(
@@ -381,6 +535,12 @@ fn uddsketch_final_inner(
                 Some(state) => state,
             };
 
+            // the moving-aggregate and weighted transition functions can
+            // produce empty sketches, treat them the same as no input at all
+            if state.count() == 0 {
+                return None;
+            }
+
             UddSketch::from_internal(&state).into()
         })
     }
@@ -505,6 +665,98 @@ extension_sql!(
     ],
 );
 
+// Experimental versions of the above aggregates which also provide a moving
+// aggregate mode, so that they can be efficiently used with sliding window
+// frames. Note that removing values cannot undo compactions, so the error of a
+// moving sketch never decreases.
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.uddsketch(\n\
+        size integer, max_error DOUBLE PRECISION, value DOUBLE PRECISION\n\
+    ) (\n\
+        sfunc = uddsketch_trans,\n\
+        stype = internal,\n\
+        finalfunc = uddsketch_final,\n\
+        combinefunc = uddsketch_combine,\n\
+        serialfunc = uddsketch_serialize,\n\
+        deserialfunc = uddsketch_deserialize,\n\
+        msfunc = toolkit_experimental.uddsketch_moving_trans,\n\
+        minvfunc = toolkit_experimental.uddsketch_inv_trans,\n\
+        mstype = internal,\n\
+        mfinalfunc = uddsketch_final,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "udd_moving_agg",
+    requires = [
+        uddsketch_trans,
+        uddsketch_final,
+        uddsketch_combine,
+        uddsketch_serialize,
+        uddsketch_deserialize,
+        uddsketch_moving_trans,
+        uddsketch_inv_trans
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.percentile_agg(value DOUBLE PRECISION)\n\
+    (\n\
+        sfunc = percentile_agg_trans,\n\
+        stype = internal,\n\
+        finalfunc = uddsketch_final,\n\
+        combinefunc = uddsketch_combine,\n\
+        serialfunc = uddsketch_serialize,\n\
+        deserialfunc = uddsketch_deserialize,\n\
+        msfunc = toolkit_experimental.percentile_agg_moving_trans,\n\
+        minvfunc = toolkit_experimental.percentile_agg_inv_trans,\n\
+        mstype = internal,\n\
+        mfinalfunc = uddsketch_final,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "percentile_moving_agg",
+    requires = [
+        percentile_agg_trans,
+        uddsketch_final,
+        uddsketch_combine,
+        uddsketch_serialize,
+        uddsketch_deserialize,
+        percentile_agg_moving_trans,
+        percentile_agg_inv_trans
+    ],
+);
+
+extension_sql!(
+    "\n\
+    CREATE AGGREGATE toolkit_experimental.uddsketch(\n\
+        size integer, max_error DOUBLE PRECISION, value DOUBLE PRECISION, weight BIGINT\n\
+    ) (\n\
+        sfunc = toolkit_experimental.uddsketch_weighted_trans,\n\
+        stype = internal,\n\
+        finalfunc = uddsketch_final,\n\
+        combinefunc = uddsketch_combine,\n\
+        serialfunc = uddsketch_serialize,\n\
+        deserialfunc = uddsketch_deserialize,\n\
+        msfunc = toolkit_experimental.uddsketch_weighted_trans,\n\
+        minvfunc = toolkit_experimental.uddsketch_weighted_inv_trans,\n\
+        mstype = internal,\n\
+        mfinalfunc = uddsketch_final,\n\
+        parallel = safe\n\
+    );\n\
+",
+    name = "udd_weighted_agg",
+    requires = [
+        uddsketch_weighted_trans,
+        uddsketch_final,
+        uddsketch_combine,
+        uddsketch_serialize,
+        uddsketch_deserialize,
+        uddsketch_weighted_inv_trans
+    ],
+);
+
 #[pg_extern(immutable, parallel_safe)]
 pub fn uddsketch_compound_trans<'a>(
     state: Internal,
@@ -677,9 +929,189 @@ pub fn uddsketch_error<'a>(sketch: UddSketch<'a>) -> f64 {
     sketch.alpha
 }
 
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_uddsketch_trimmed_mean<'a>(
+    sketch: UddSketch<'a>,
+    accessor: AccessorTrimmedMean<'a>,
+) -> f64 {
+    uddsketch_trimmed_mean(sketch, accessor.low, accessor.high)
+}
+
+// Approximate the mean of the values between the `low` and `high` percentiles (0.0-1.0).
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "trimmed_mean"
+)]
+pub fn uddsketch_trimmed_mean<'a>(sketch: UddSketch<'a>, low: f64, high: f64) -> f64 {
+    if !(0.0 <= low && low < high && high <= 1.0) {
+        pgrx::error!("trimmed_mean requires 0 <= low < high <= 1")
+    }
+    uddsketch::estimate_trimmed_mean(
+        low,
+        high,
+        sketch.alpha,
+        uddsketch::gamma(sketch.alpha),
+        sketch.count,
+        sketch.keys().zip(sketch.counts()),
+    )
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_uddsketch_iqr<'a>(sketch: UddSketch<'a>, _accessor: AccessorIqr<'a>) -> f64 {
+    uddsketch_iqr(sketch)
+}
+
+// Approximate interquartile range, the difference between the 75th and 25th percentiles.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "iqr"
+)]
+pub fn uddsketch_iqr<'a>(sketch: UddSketch<'a>) -> f64 {
+    let quartiles = approx_percentile_slice(&[0.25, 0.75], sketch);
+    quartiles[1] - quartiles[0]
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(->)]
+pub fn arrow_uddsketch_approx_cdf<'a>(
+    sketch: UddSketch<'a>,
+    accessor: AccessorApproxCdf<'a>,
+) -> Vec<f64> {
+    approx_cdf_slice(accessor.values.as_slice(), sketch)
+}
+
+// Approximate the percentile rank of each of the given values.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "approx_cdf"
+)]
+pub fn uddsketch_approx_cdf<'a>(sketch: UddSketch<'a>, values: Vec<f64>) -> Vec<f64> {
+    approx_cdf_slice(&values, sketch)
+}
+
+fn approx_cdf_slice<'a, 'b>(
+    values: impl IntoIterator<Item = &'b f64>,
+    sketch: UddSketch<'a>,
+) -> Vec<f64> {
+    let gamma = uddsketch::gamma(sketch.alpha);
+    values
+        .into_iter()
+        .map(|value| {
+            uddsketch::estimate_quantile_at_value(
+                *value,
+                gamma,
+                sketch.count,
+                sketch.keys().zip(sketch.counts()),
+            )
+        })
+        .collect()
+}
+
+// The Kolmogorov-Smirnov statistic between the distributions of two sketches,
+// the largest difference between their cumulative distributions (0.0-1.0).
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "ks_distance"
+)]
+pub fn uddsketch_ks_distance<'a, 'b>(a: UddSketch<'a>, b: UddSketch<'b>) -> Option<f64> {
+    let (a, b) = comparable_sketches(&a, &b)?;
+    Some(a.ks_distance(&b))
+}
+
+// The Wasserstein (earth mover's) distance between the distributions of two
+// sketches, expressed in the units of the sketched values.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "wasserstein_distance"
+)]
+pub fn uddsketch_wasserstein_distance<'a, 'b>(a: UddSketch<'a>, b: UddSketch<'b>) -> Option<f64> {
+    let (a, b) = comparable_sketches(&a, &b)?;
+    Some(a.wasserstein_distance(&b))
+}
+
+// None if either sketch is empty, as an empty sketch has no distribution
+fn comparable_sketches(
+    a: &UddSketch<'_>,
+    b: &UddSketch<'_>,
+) -> Option<(UddSketchInternal, UddSketchInternal)> {
+    let (a, b) = (a.to_uddsketch(), b.to_uddsketch());
+    if !a.has_same_initial_error(&b) {
+        pgrx::error!("cannot compare uddsketches created with different max_error")
+    }
+    if a.count() == 0 || b.count() == 0 {
+        return None;
+    }
+    Some((a, b))
+}
+
+// The buckets of the sketch in increasing order, as the range of values each
+// bucket covers along with the number of values it holds.
+#[pg_extern(
+    immutable,
+    parallel_safe,
+    schema = "toolkit_experimental",
+    name = "buckets"
+)]
+pub fn uddsketch_buckets<'a>(
+    sketch: UddSketch<'a>,
+) -> TableIterator<
+    'static,
+    (
+        name!(lower_bound, f64),
+        name!(upper_bound, f64),
+        name!(count, i64),
+    ),
+> {
+    let gamma = uddsketch::gamma(sketch.alpha);
+    let buckets: Vec<_> = sketch
+        .keys()
+        .zip(sketch.counts())
+        .map(|(key, count)| {
+            let (lower, upper) = uddsketch::bucket_bounds(gamma, key);
+            (lower, upper, count as i64)
+        })
+        .collect();
+    TableIterator::new(buckets.into_iter())
+}
+
+// Re-bin the sketch into a cumulative histogram with the given upper bounds,
+// i.e. the approximate number of values less than or equal to each bound.
+// Each count is exact for some bound within the sketch's error of the given one.
+// A NaN bound bounds nothing and gets a NULL count.
+#[pg_extern(immutable, parallel_safe, schema = "toolkit_experimental")]
+pub fn cumulative_histogram<'a>(sketch: UddSketch<'a>, bounds: Vec<f64>) -> Vec<Option<i64>> {
+    let gamma = uddsketch::gamma(sketch.alpha);
+    bounds
+        .iter()
+        .map(|bound| {
+            if bound.is_nan() {
+                return None;
+            }
+            Some(uddsketch::estimate_count_at_or_below(
+                *bound,
+                sketch.alpha,
+                gamma,
+                sketch.keys().zip(sketch.counts()),
+            ) as i64)
+        })
+        .collect()
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     use pgrx_macros::pg_test;
@@ -1286,6 +1718,293 @@ mod tests {
         }
     }
 
+    #[pg_test]
+    fn test_moving_aggregate() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TABLE moving_test (i INTEGER, value DOUBLE PRECISION)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO moving_test \
+                    SELECT i, CASE WHEN i % 7 = 0 THEN NULL ELSE (i * 37) % 101 END \
+                    FROM generate_series(1, 300) i",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            // The stable aggregates have no inverse transition function, so
+            // they recompute each frame from scratch. Since the windows are too
+            // small to cause compactions the results should be identical.
+            for (moving, control) in [
+                (
+                    "toolkit_experimental.percentile_agg(value)",
+                    "percentile_agg(value)",
+                ),
+                (
+                    "toolkit_experimental.uddsketch(50, 0.01, value)",
+                    "uddsketch(50, 0.01, value)",
+                ),
+            ] {
+                let mismatches = client
+                    .update(
+                        &format!(
+                            "SELECT count(*) FROM ( \
+                                SELECT \
+                                    {moving} OVER w AS moving, \
+                                    {control} OVER w AS control \
+                                FROM moving_test \
+                                WINDOW w AS (ORDER BY i ROWS BETWEEN 20 PRECEDING AND CURRENT ROW) \
+                            ) windows \
+                            WHERE approx_percentile(0.5, moving) <> approx_percentile(0.5, control) \
+                            OR approx_percentile(0.95, moving) <> approx_percentile(0.95, control) \
+                            OR num_vals(moving) <> num_vals(control)"
+                        ),
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                    .first()
+                    .get_one::<i64>()
+                    .unwrap();
+                assert_eq!(mismatches, Some(0), "{moving}");
+            }
+
+            // frames containing only NULLs produce NULL
+            let output = client
+                .update(
+                    "SELECT toolkit_experimental.percentile_agg(value) \
+                        OVER (ORDER BY i ROWS BETWEEN CURRENT ROW AND CURRENT ROW)::TEXT \
+                    FROM moving_test WHERE i = 7",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            assert_eq!(output, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_weighted_aggregate() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TABLE weighted_test (value DOUBLE PRECISION, weight BIGINT)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO weighted_test SELECT v, (v::int * 13) % 17 FROM generate_series(1, 100) v",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let (weighted_count, expanded_count) = client
+                .update(
+                    "SELECT \
+                        num_vals((SELECT toolkit_experimental.uddsketch(100, 0.01, value, weight) FROM weighted_test)), \
+                        num_vals((SELECT uddsketch(100, 0.01, value) FROM weighted_test, generate_series(1, weight)))",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_two::<f64, f64>()
+                .unwrap();
+            assert_eq!(weighted_count, expanded_count);
+
+            for percentile in [0.01, 0.25, 0.5, 0.75, 0.99] {
+                let (weighted, expanded) = client
+                    .update(
+                        &format!(
+                            "SELECT \
+                                approx_percentile({percentile}, (SELECT toolkit_experimental.uddsketch(100, 0.01, value, weight) FROM weighted_test)), \
+                                approx_percentile({percentile}, (SELECT uddsketch(100, 0.01, value) FROM weighted_test, generate_series(1, weight)))"
+                        ),
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                    .first()
+                    .get_two::<f64, f64>()
+                    .unwrap();
+                assert_eq!(weighted, expanded);
+            }
+
+            // moving aggregate mode removes the whole weight of a row
+            let mismatches = client
+                .update(
+                    "SELECT count(*) FROM ( \
+                        SELECT \
+                            toolkit_experimental.uddsketch(100, 0.01, value, weight) OVER w AS sketch, \
+                            sum(weight) OVER w AS total \
+                        FROM weighted_test \
+                        WINDOW w AS (ORDER BY value ROWS BETWEEN 5 PRECEDING AND CURRENT ROW) \
+                    ) windows \
+                    WHERE coalesce(num_vals(sketch), 0) <> total",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<i64>()
+                .unwrap();
+            assert_eq!(mismatches, Some(0));
+        });
+    }
+
+    #[pg_test(error = "uddsketch weights must not be negative")]
+    fn test_negative_weight() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.uddsketch(100, 0.01, 1.0, -1)",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_uddsketch_distribution_accessors() {
+        Spi::connect(|mut client| {
+            check_distribution_accessors(&mut client, "uddsketch(200, 0.001, value)");
+        });
+    }
+
+    #[pg_test(error = "trimmed_mean requires 0 <= low < high <= 1")]
+    fn test_uddsketch_trimmed_mean_invalid_bounds() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.trimmed_mean(uddsketch(200, 0.001, value), 0.9, 0.1) \
+                    FROM generate_series(1, 100) value",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_uddsketch_distribution_distances() {
+        Spi::connect(|mut client| {
+            check_distribution_distances(
+                &mut client,
+                "uddsketch(200, 0.001, value)",
+                "'(version:1,alpha:0.001,max_buckets:200,num_buckets:0,compactions:0,count:0,sum:0,buckets:[])'::uddsketch",
+            );
+        });
+    }
+
+    #[pg_test(error = "cannot compare uddsketches created with different max_error")]
+    fn test_distance_mismatched_error() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "SELECT toolkit_experimental.ks_distance(\
+                        (SELECT uddsketch(100, 0.01, value) FROM generate_series(1, 10) value), \
+                        (SELECT uddsketch(100, 0.05, value) FROM generate_series(1, 10) value))",
+                    None,
+                    None,
+                )
+                .unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_buckets() {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    "CREATE TABLE bucket_test (value DOUBLE PRECISION)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "INSERT INTO bucket_test SELECT generate_series(-50, 100, 0.5)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            client
+                .update(
+                    "CREATE VIEW sketch AS SELECT uddsketch(40, 0.01, value) FROM bucket_test",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let (total, num_buckets, unordered) = client
+                .update(
+                    "SELECT \
+                        sum(count)::float, \
+                        count(*), \
+                        count(*) FILTER (WHERE lower_bound > upper_bound OR lower_bound < prev_upper) \
+                    FROM ( \
+                        SELECT b.*, lag(upper_bound) OVER () AS prev_upper \
+                        FROM sketch, toolkit_experimental.buckets(uddsketch) b \
+                    ) buckets",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_three::<f64, i64, i64>()
+                .unwrap();
+            assert_eq!(total, Some(301.0));
+            assert!(num_buckets.unwrap() <= 40);
+            assert_eq!(unordered, Some(0));
+
+            let histogram = client
+                .update(
+                    "SELECT toolkit_experimental.cumulative_histogram(\
+                        uddsketch, array[-100, 0, 50, 'Infinity']) \
+                    FROM sketch",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<Vec<i64>>()
+                .unwrap()
+                .unwrap();
+            assert_eq!(histogram[0], 0);
+            assert_eq!(histogram[1], 101);
+            assert!(histogram[2] > 101 && histogram[2] < 301);
+            assert_eq!(histogram[3], 301);
+
+            let histogram = client
+                .update(
+                    "SELECT toolkit_experimental.cumulative_histogram(\
+                        uddsketch, array[0, 'NaN', '-Infinity']) \
+                    FROM sketch",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<Vec<Option<i64>>>()
+                .unwrap()
+                .unwrap();
+            assert_eq!(histogram, vec![Some(101), None, Some(0)]);
+        });
+    }
+
     #[pg_test]
     fn test_udd_null_input_yields_null_output() {
         Spi::connect(|mut client| {
@@ -1298,4 +2017,147 @@ mod tests {
             assert_eq!(output, None)
         })
     }
+
+    // The percentile sketches share these accessors, so the same checks run
+    // against each of them; `agg` builds a sketch from a float column `value`.
+    pub(crate) fn check_distribution_accessors(client: &mut pgrx::spi::SpiClient, agg: &str) {
+        client
+            .update(
+                &format!(
+                    "CREATE VIEW dist_sketch AS \
+                    SELECT {agg} AS sketch \
+                    FROM (SELECT generate_series(1, 1000)::float UNION ALL SELECT 1e12 FROM generate_series(1, 10)) data(value)"
+                ),
+                None,
+                None,
+            )
+            .unwrap();
+
+        let (trimmed, trimmed_arrow) = client
+            .update(
+                "SELECT \
+                    toolkit_experimental.trimmed_mean(sketch, 0.0, 0.99), \
+                    sketch->toolkit_experimental.trimmed_mean(0.0, 0.99) \
+                FROM dist_sketch",
+                None,
+                None,
+            )
+            .unwrap()
+            .first()
+            .get_two::<f64, f64>()
+            .unwrap();
+        pct_eql(trimmed.unwrap(), 500.5, 0.01);
+        assert_eq!(trimmed, trimmed_arrow);
+
+        let (iqr, iqr_arrow) = client
+            .update(
+                "SELECT \
+                    toolkit_experimental.iqr(sketch), \
+                    sketch->toolkit_experimental.iqr() \
+                FROM dist_sketch",
+                None,
+                None,
+            )
+            .unwrap()
+            .first()
+            .get_two::<f64, f64>()
+            .unwrap();
+        pct_eql(iqr.unwrap(), 505.0, 0.02);
+        assert_eq!(iqr, iqr_arrow);
+
+        let (cdf, cdf_arrow) = client
+            .update(
+                "SELECT \
+                    toolkit_experimental.approx_cdf(sketch, array[0, 101, 505, 1e13]), \
+                    sketch->toolkit_experimental.approx_cdf(array[0, 101, 505, 1e13]) \
+                FROM dist_sketch",
+                None,
+                None,
+            )
+            .unwrap()
+            .first()
+            .get_two::<Vec<f64>, Vec<f64>>()
+            .unwrap();
+        let cdf = cdf.unwrap();
+        assert_eq!(cdf.len(), 4);
+        apx_eql(cdf[0], 0.0, 0.0001);
+        pct_eql(cdf[1], 0.1, 0.02);
+        pct_eql(cdf[2], 0.5, 0.02);
+        apx_eql(cdf[3], 1.0, 0.0001);
+        assert_eq!(Some(cdf), cdf_arrow);
+    }
+
+    // `empty` is a sketch of the same kind holding no values
+    pub(crate) fn check_distribution_distances(
+        client: &mut pgrx::spi::SpiClient,
+        agg: &str,
+        empty: &str,
+    ) {
+        client
+            .update(
+                &format!(
+                    "CREATE VIEW distances AS \
+                    SELECT \
+                        (SELECT {agg} FROM generate_series(1, 1000) value) AS base, \
+                        (SELECT {agg} FROM generate_series(101, 1100) value) AS shifted, \
+                        (SELECT {agg} FROM generate_series(5001, 6000) value) AS disjoint, \
+                        {empty} AS empty"
+                ),
+                None,
+                None,
+            )
+            .unwrap();
+
+        let (same, shifted, disjoint) = client
+            .update(
+                "SELECT \
+                    toolkit_experimental.ks_distance(base, base), \
+                    toolkit_experimental.ks_distance(base, shifted), \
+                    toolkit_experimental.ks_distance(base, disjoint) \
+                FROM distances",
+                None,
+                None,
+            )
+            .unwrap()
+            .first()
+            .get_three::<f64, f64, f64>()
+            .unwrap();
+        apx_eql(same.unwrap(), 0.0, 0.000001);
+        apx_eql(shifted.unwrap(), 0.1, 0.01);
+        apx_eql(disjoint.unwrap(), 1.0, 0.000001);
+
+        let (same, shifted, disjoint) = client
+            .update(
+                "SELECT \
+                    toolkit_experimental.wasserstein_distance(base, base), \
+                    toolkit_experimental.wasserstein_distance(base, shifted), \
+                    toolkit_experimental.wasserstein_distance(base, disjoint) \
+                FROM distances",
+                None,
+                None,
+            )
+            .unwrap()
+            .first()
+            .get_three::<f64, f64, f64>()
+            .unwrap();
+        apx_eql(same.unwrap(), 0.0, 0.000001);
+        pct_eql(shifted.unwrap(), 100.0, 0.02);
+        pct_eql(disjoint.unwrap(), 5000.0, 0.02);
+
+        // an empty sketch has no distribution to compare
+        let (ks, wasserstein) = client
+            .update(
+                "SELECT \
+                    toolkit_experimental.ks_distance(base, empty), \
+                    toolkit_experimental.wasserstein_distance(empty, base) \
+                FROM distances",
+                None,
+                None,
+            )
+            .unwrap()
+            .first()
+            .get_two::<f64, f64>()
+            .unwrap();
+        assert_eq!((ks, wasserstein), (None, None));
+    }
 }